@@ -0,0 +1,328 @@
+//! `#[derive(Encode, Decode)]` for embed-serial-protocol's `Encode` and
+//! `Decode` traits. Use them through the main crate's `derive` feature.
+//!
+//! Fields go on the wire one after the other with no padding, each encoded
+//! with its own `Encode`/`Decode` impl, so integers are fixed width little
+//! endian (wrap them in `Be` for big endian) and nested structs are inlined.
+//! `&[u8]` fields get a little endian u16 length in front of them and are
+//! borrowed straight out of the data when decoding. Byte slices anywhere
+//! else in a field's type, say `Option<&[u8]>`, are turned down: wrap them
+//! in `encoding::Bytes` instead. Type aliases for `&[u8]` can't be spotted,
+//! so they'd encode as a frame of their own, use `Bytes` there too.
+//!
+//! Enums start with their discriminant, a `u8` unless there's a
+//! `#[repr(..)]` saying otherwise, followed by the variant's fields.
+//! Explicit discriminants (`A = 3`) are used as given.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Error, Fields, GenericArgument, GenericParam, Generics, Lifetime, LifetimeParam, PathArguments,
+    Type, WherePredicate, parse_macro_input, parse_quote,
+};
+
+#[proc_macro_derive(Encode)]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    encode(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(Decode)]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    decode(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// `&[u8]`, with or without a lifetime
+fn is_bytes(ty: &Type) -> bool {
+    let Type::Reference(r) = ty else {
+        return false;
+    };
+    if r.mutability.is_some() {
+        return false;
+    }
+    let Type::Slice(s) = &*r.elem else {
+        return false;
+    };
+    matches!(&*s.elem, Type::Path(p) if p.qself.is_none() && p.path.is_ident("u8"))
+}
+
+/// A `&[u8]` somewhere inside `ty`, but not `ty` itself
+fn nested_bytes(ty: &Type) -> Option<&Type> {
+    let inner: Vec<&Type> = match ty {
+        Type::Array(a) => vec![&*a.elem],
+        Type::Slice(s) => vec![&*s.elem],
+        Type::Reference(r) => vec![&*r.elem],
+        Type::Paren(p) => vec![&*p.elem],
+        Type::Group(g) => vec![&*g.elem],
+        Type::Tuple(t) => t.elems.iter().collect(),
+        Type::Path(p) => p
+            .path
+            .segments
+            .iter()
+            .flat_map(|s| match &s.arguments {
+                PathArguments::AngleBracketed(args) => args
+                    .args
+                    .iter()
+                    .filter_map(|a| match a {
+                        GenericArgument::Type(t) => Some(t),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    inner.into_iter().find_map(|t| if is_bytes(t) { Some(t) } else { nested_bytes(t) })
+}
+
+/// Turn down byte slices that aren't a field of their own, the derive only
+/// knows to put a length in front of those
+fn check_bytes(data: &Data) -> syn::Result<()> {
+    match field_types(data).into_iter().find_map(nested_bytes) {
+        Some(ty) => Err(Error::new_spanned(
+            ty,
+            "`&[u8]` only gets a length prefix as a field of its own, wrap it in `embed_serial_protocol::encoding::Bytes` here",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Binding for the `i`th field in a pattern or local
+fn binding(fields: &Fields, i: usize) -> proc_macro2::Ident {
+    match fields.iter().nth(i).and_then(|f| f.ident.as_ref()) {
+        Some(ident) => format_ident!("__field_{}", ident),
+        None => format_ident!("__field_{}", i),
+    }
+}
+
+/// Pattern or constructor for `path` with the fields bound to `binding`s
+fn pattern(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    let bindings = (0..fields.len()).map(|i| binding(fields, i));
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| &f.ident);
+            quote!(#path { #(#names: #bindings),* })
+        },
+        Fields::Unnamed(_) => quote!(#path(#(#bindings),*)),
+        Fields::Unit => path,
+    }
+}
+
+/// Every field's type, for the where clause
+fn field_types(data: &Data) -> Vec<&Type> {
+    match data {
+        Data::Struct(s) => s.fields.iter().map(|f| &f.ty).collect(),
+        Data::Enum(e) => e.variants.iter().flat_map(|v| v.fields.iter().map(|f| &f.ty)).collect(),
+        Data::Union(_) => Vec::new(),
+    }
+}
+
+/// Type the enum's discriminant goes on the wire as
+fn repr(input: &DeriveInput) -> syn::Result<Type> {
+    let mut repr = parse_quote!(u8);
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            let path = &meta.path;
+            if ["u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64"].iter().any(|int| path.is_ident(int)) {
+                repr = parse_quote!(#path);
+            } else if meta.input.peek(syn::token::Paren) {
+                // e.g. align(4), nothing to do with us
+                let _args;
+                syn::parenthesized!(_args in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(repr)
+}
+
+/// Each variant's discriminant as an expression of the `repr` type
+fn discriminants(data: &syn::DataEnum, repr: &Type) -> Vec<TokenStream2> {
+    let mut last: Option<TokenStream2> = None;
+    data.variants
+        .iter()
+        .map(|v| {
+            let d = match (&v.discriminant, &last) {
+                (Some((_, e)), _) => quote!((#e)),
+                (None, Some(last)) => quote!((#last) + 1),
+                (None, None) => quote!(0),
+            };
+            last = Some(d.clone());
+            quote!((#d) as #repr)
+        })
+        .collect()
+}
+
+fn encode_fields(fields: &Fields, values: impl Iterator<Item = TokenStream2>) -> TokenStream2 {
+    let steps = fields.iter().zip(values).map(|(f, value)| {
+        if is_bytes(&f.ty) {
+            quote!(n += ::embed_serial_protocol::encoding::encode_field(
+                &::embed_serial_protocol::encoding::Bytes(*#value), buffer, n
+            )?;)
+        } else {
+            quote!(n += ::embed_serial_protocol::encoding::encode_field(#value, buffer, n)?;)
+        }
+    });
+    quote!(#(#steps)*)
+}
+
+fn decode_fields(fields: &Fields) -> TokenStream2 {
+    let steps = fields.iter().enumerate().map(|(i, f)| {
+        let b = binding(fields, i);
+        if is_bytes(&f.ty) {
+            quote!(let #b = ::embed_serial_protocol::encoding::decode_field::<
+                ::embed_serial_protocol::encoding::Bytes
+            >(data, &mut n)?.0;)
+        } else {
+            quote!(let #b = ::embed_serial_protocol::encoding::decode_field(data, &mut n)?;)
+        }
+    });
+    quote!(#(#steps)*)
+}
+
+fn encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    check_bytes(&input.data)?;
+    let mut generics = input.generics.clone();
+    let where_clause = generics.make_where_clause();
+    for ty in field_types(&input.data).into_iter().filter(|t| !is_bytes(t)) {
+        let predicate: WherePredicate = parse_quote! {
+            #ty: ::embed_serial_protocol::Encode<
+                Error: ::core::convert::Into<::embed_serial_protocol::FrameError>
+            >
+        };
+        where_clause.predicates.push(predicate);
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(s) => {
+            let values = s.fields.iter().enumerate().map(|(i, f)| match &f.ident {
+                Some(ident) => quote!(&self.#ident),
+                None => {
+                    let i = syn::Index::from(i);
+                    quote!(&self.#i)
+                },
+            });
+            encode_fields(&s.fields, values)
+        },
+        Data::Enum(e) => {
+            let repr = repr(input)?;
+            let arms = e.variants.iter().zip(discriminants(e, &repr)).map(|(v, d)| {
+                let ident = &v.ident;
+                let pattern = pattern(quote!(Self::#ident), &v.fields);
+                let values = (0..v.fields.len()).map(|i| {
+                    let b = binding(&v.fields, i);
+                    quote!(#b)
+                });
+                let fields = encode_fields(&v.fields, values);
+                quote! {
+                    #pattern => {
+                        n += ::embed_serial_protocol::encoding::encode_field(&(#d), buffer, n)?;
+                        #fields
+                    },
+                }
+            });
+            quote!(match self { #(#arms)* })
+        },
+        Data::Union(_) => return Err(Error::new_spanned(input, "Encode can't be derived for unions")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::embed_serial_protocol::Encode for #name #ty_generics #where_clause {
+            type Error = ::embed_serial_protocol::FrameError;
+
+            fn encode(&self, buffer: &mut [u8]) -> ::core::result::Result<usize, Self::Error> {
+                let mut n = 0usize;
+                #body
+                ::core::result::Result::Ok(n)
+            }
+        }
+    })
+}
+
+/// The lifetime decoded data is borrowed for: the type's own if it has one,
+/// otherwise a new one added to the impl's generics
+fn decode_lifetime(generics: &mut Generics) -> syn::Result<Lifetime> {
+    let mut lifetimes = generics.lifetimes();
+    match (lifetimes.next(), lifetimes.next()) {
+        (Some(l), None) => Ok(l.lifetime.clone()),
+        (None, _) => {
+            let l: Lifetime = parse_quote!('__de);
+            generics.params.insert(0, GenericParam::Lifetime(LifetimeParam::new(l.clone())));
+            Ok(l)
+        },
+        (Some(_), Some(extra)) => {
+            Err(Error::new_spanned(extra, "Decode can only be derived for types with at most one lifetime"))
+        },
+    }
+}
+
+fn decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    check_bytes(&input.data)?;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let mut generics = input.generics.clone();
+    let lt = decode_lifetime(&mut generics)?;
+    let where_clause = generics.make_where_clause();
+    for ty in field_types(&input.data).into_iter().filter(|t| !is_bytes(t)) {
+        let predicate: WherePredicate = parse_quote! {
+            #ty: ::embed_serial_protocol::Decode<
+                #lt,
+                Error: ::core::convert::Into<::embed_serial_protocol::FrameError>
+            >
+        };
+        where_clause.predicates.push(predicate);
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(s) => {
+            let fields = decode_fields(&s.fields);
+            let value = pattern(quote!(Self), &s.fields);
+            quote! {
+                #fields
+                ::core::result::Result::Ok((#value, n))
+            }
+        },
+        Data::Enum(e) => {
+            let repr = repr(input)?;
+            let variants = e.variants.iter().zip(discriminants(e, &repr)).map(|(v, d)| {
+                let ident = &v.ident;
+                let fields = decode_fields(&v.fields);
+                let value = pattern(quote!(Self::#ident), &v.fields);
+                quote! {
+                    if discriminant == #d {
+                        #fields
+                        return ::core::result::Result::Ok((#value, n));
+                    }
+                }
+            });
+            quote! {
+                let discriminant: #repr = ::embed_serial_protocol::encoding::decode_field(data, &mut n)?;
+                #(#variants)*
+                // Not one of ours
+                ::core::result::Result::Err(::embed_serial_protocol::FrameError::InvalidEncoding { index: 0 })
+            }
+        },
+        Data::Union(_) => return Err(Error::new_spanned(input, "Decode can't be derived for unions")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::embed_serial_protocol::Decode<#lt> for #name #ty_generics #where_clause {
+            type Error = ::embed_serial_protocol::FrameError;
+
+            fn decode(data: &#lt [u8]) -> ::core::result::Result<Self, Self::Error> {
+                Self::decode_from(data).map(|(v, _)| v)
+            }
+
+            fn decode_from(data: &#lt [u8]) -> ::core::result::Result<(Self, usize), Self::Error> {
+                let mut n = 0usize;
+                #body
+            }
+        }
+    })
+}
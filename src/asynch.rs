@@ -0,0 +1,225 @@
+use core::convert::Infallible;
+
+use embedded_io_async::{Read, Write};
+
+use crate::{
+    codec::{Delimited, FrameCodec},
+    packet::{Frame, FrameError, FrameIOError, decode_queued},
+    serial::{ByteQueue, DefaultBuffer},
+};
+
+/// Most bytes asked of the reader at once
+const READ_CHUNK: usize = 64;
+
+/// Async counterpart to `FrameRx`, reading frames off an
+/// `embedded_io_async::Read` with the same codecs.
+pub struct AsyncFrameRx<Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer> {
+    pub rx: Rx,
+    pub codec: C,
+    pub buf: B,
+}
+
+impl<Rx: Read> AsyncFrameRx<Rx> {
+    pub fn new(rx: Rx) -> AsyncFrameRx<Rx> {
+        AsyncFrameRx::with_codec(rx, Delimited::new())
+    }
+}
+
+impl<Rx: Read, C: FrameCodec> AsyncFrameRx<Rx, C> {
+    pub fn with_codec(rx: Rx, codec: C) -> AsyncFrameRx<Rx, C> {
+        AsyncFrameRx::with_buffer(rx, codec, DefaultBuffer::default())
+    }
+}
+
+impl<Rx: Read, C: FrameCodec, B: ByteQueue> AsyncFrameRx<Rx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits
+    pub fn with_buffer(rx: Rx, codec: C, buf: B) -> AsyncFrameRx<Rx, C, B> {
+        AsyncFrameRx { rx, codec, buf }
+    }
+
+    /// Wait for the next frame. Bad frames come back as errors the same
+    /// as `FrameRx::recv`, and the next call picks up after them.
+    pub async fn recv(&mut self) -> Result<Frame, FrameIOError<Infallible, Rx::Error>> {
+        loop {
+            if let Some(f) = decode_queued(&self.codec, &mut self.buf)? {
+                return Ok(f);
+            }
+            let mut chunk = [0; READ_CHUNK];
+            let want = READ_CHUNK.min(self.buf.free());
+            let n = self.rx.read(&mut chunk[..want]).await.map_err(FrameIOError::Read)?;
+            if n == 0 {
+                return Err(FrameIOError::Eof);
+            }
+            for b in &chunk[..n] {
+                let _ = self.buf.push_back(*b);
+            }
+        }
+    }
+}
+
+/// Async counterpart to `FrameTx`, writing frames to an
+/// `embedded_io_async::Write` with the same codecs.
+///
+/// Each frame is encoded into `buf` and written out from there, so `buf`
+/// needs room for the codec's biggest frame. `buf` only ever holds bytes
+/// that haven't been written yet.
+///
+/// Dropping a `send` or `flush` future part way (a `select!` timeout, say)
+/// never cuts a frame short: whatever's left of it stays in `buf` and goes
+/// out at the start of the next `send` or `flush`, ahead of anything new.
+/// There's no telling from the dropped future how much of it went.
+pub struct AsyncFrameTx<Tx: Write, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer> {
+    pub tx: Tx,
+    pub codec: C,
+    pub buf: B,
+}
+
+impl<Tx: Write> AsyncFrameTx<Tx> {
+    pub fn new(tx: Tx) -> AsyncFrameTx<Tx> {
+        AsyncFrameTx::with_codec(tx, Delimited::new())
+    }
+}
+
+impl<Tx: Write, C: FrameCodec> AsyncFrameTx<Tx, C> {
+    pub fn with_codec(tx: Tx, codec: C) -> AsyncFrameTx<Tx, C> {
+        AsyncFrameTx::with_buffer(tx, codec, DefaultBuffer::default())
+    }
+}
+
+impl<Tx: Write, C: FrameCodec, B: ByteQueue> AsyncFrameTx<Tx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits
+    pub fn with_buffer(tx: Tx, codec: C, buf: B) -> AsyncFrameTx<Tx, C, B> {
+        AsyncFrameTx { tx, codec, buf }
+    }
+
+    /// Encode `data` as a frame and wait until it's all been written and
+    /// flushed, along with anything a cancelled send left behind
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let required = self.codec.encoded_len(data)?;
+        // Finish off the last frame first so there's room for this one and
+        // it doesn't go out in the middle of it
+        self.write_queued().await.map_err(FrameIOError::Write)?;
+        if required > self.buf.free() {
+            return Err(FrameError::EncodeBufferTooSmall {
+                expected_at_least: required,
+                found: self.buf.free(),
+            }
+            .into());
+        }
+        let buf = &mut self.buf;
+        self.codec.encode_with(data, |b| {
+            let _ = buf.push_back(b);
+        })?;
+        self.flush().await.map_err(FrameIOError::Write)
+    }
+
+    /// Write out whatever's left in `buf` and flush `tx`
+    pub async fn flush(&mut self) -> Result<(), Tx::Error> {
+        self.write_queued().await?;
+        self.tx.flush().await
+    }
+
+    /// Write out `buf`, dropping each bit as it's written so a cancelled
+    /// write leaves exactly what's still to go
+    async fn write_queued(&mut self) -> Result<(), Tx::Error> {
+        while !self.buf.is_empty() {
+            let (front, _) = self.buf.as_slices();
+            match self.tx.write(front).await {
+                Ok(0) => panic!("write() returned Ok(0)"),
+                Ok(n) => self.buf.drain_front(n),
+                Err(e) => {
+                    // Whatever's left is half a frame the wire has already
+                    // broken, don't let it go out in front of the next one
+                    let len = self.buf.len();
+                    self.buf.drain_front(len);
+                    return Err(e);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::vec::Vec;
+
+    use embedded_io_async::ErrorType;
+
+    use super::*;
+
+    /// Takes a byte per write, and only every other time it's polled
+    #[derive(Default)]
+    struct Trickle {
+        out: Vec<u8>,
+        ready: bool,
+    }
+
+    impl ErrorType for Trickle {
+        type Error = Infallible;
+    }
+
+    impl Write for Trickle {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            core::future::poll_fn(|_| {
+                self.ready = !self.ready;
+                if self.ready { Poll::Ready(()) } else { Poll::Pending }
+            })
+            .await;
+            self.out.push(buf[0]);
+            Ok(1)
+        }
+    }
+
+    /// Poll `f` up to `polls` times, None if it isn't done by then
+    fn poll_for<F: Future>(f: F, polls: usize) -> Option<F::Output> {
+        let mut f = pin!(f);
+        let mut cx = Context::from_waker(Waker::noop());
+        (0..polls).find_map(|_| match f.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => Some(v),
+            Poll::Pending => None,
+        })
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut buf = [0; 32];
+        let len = Delimited::<crate::Crc8>::new().encode(data, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn send_writes_the_whole_frame() {
+        let mut tx = AsyncFrameTx::new(Trickle::default());
+        poll_for(tx.send(b"hi"), 100).unwrap().unwrap();
+        assert_eq!(tx.tx.out, frame(b"hi"));
+        assert!(tx.buf.is_empty());
+    }
+
+    #[test]
+    fn cancelled_send_finishes_before_the_next() {
+        let mut tx = AsyncFrameTx::new(Trickle::default());
+        // Dropped after a couple of bytes went out
+        assert!(poll_for(tx.send(b"first"), 4).is_none());
+        let written = tx.tx.out.len();
+        assert!(written > 0);
+        assert_eq!(tx.buf.len(), frame(b"first").len() - written);
+
+        poll_for(tx.send(b"second"), 100).unwrap().unwrap();
+        assert_eq!(tx.tx.out, [frame(b"first"), frame(b"second")].concat());
+    }
+
+    #[test]
+    fn flush_finishes_a_cancelled_send() {
+        let mut tx = AsyncFrameTx::new(Trickle::default());
+        assert!(poll_for(tx.send(b"first"), 3).is_none());
+        poll_for(tx.flush(), 100).unwrap().unwrap();
+        assert_eq!(tx.tx.out, frame(b"first"));
+    }
+}
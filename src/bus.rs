@@ -0,0 +1,305 @@
+use core::convert::Infallible;
+
+use embedded_hal_nb::serial::{Read, Write};
+
+use crate::{
+    clock::{Clock, NoClock},
+    codec::{Delimited, FrameCodec},
+    header::{FrameHeader, u4, u6},
+    packet::{
+        Frame, FrameData, FrameIOError, FrameRecv, FrameSend, FrameTxRx, frame_data, frame_data_with, widen_read_error,
+        widen_write_error,
+    },
+    serial::{ByteQueue, DefaultBuffer},
+};
+
+/// Destination address every drop on the bus listens to
+pub const BROADCAST: u8 = 0xFF;
+/// Destination then source address, after the `FrameHeader`
+pub const ADDRESS_LEN: usize = 2;
+
+/// A frame on a multi-drop bus (RS-485 and the like), saying who it's for
+/// and who it's from.
+///
+/// Addressed frames have `FrameHeader::addressed` set and the two addresses
+/// at the front of the data. Anything else on the link (mux channels, RPC,
+/// ...) isn't addressed and goes to every drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addressed<'a> {
+    pub dst: u8,
+    pub src: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Addressed<'a> {
+    /// The addresses and the data after them, None if `frame` isn't addressed
+    pub fn parse(frame: &'a Frame) -> Option<Addressed<'a>> {
+        Addressed::split(frame.header, &frame.data)
+    }
+
+    /// See `parse`, for a frame's header and data on their own
+    pub(crate) fn split(header: Option<FrameHeader>, data: &'a [u8]) -> Option<Addressed<'a>> {
+        match (header, data) {
+            (Some(h), [dst, src, data @ ..]) if h.addressed() => Some(Addressed { dst: *dst, src: *src, data }),
+            _ => None,
+        }
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.dst == BROADCAST
+    }
+
+    /// True if it's addressed to `address` or to everyone
+    pub fn is_for(&self, address: u8) -> bool {
+        self.dst == address || self.is_broadcast()
+    }
+}
+
+/// Whether a frame gets past `FrameRx`'s address filter. Only addressed
+/// frames are filtered, an addressed one too short to hold the addresses
+/// isn't for anyone.
+pub(crate) fn for_us(header: Option<FrameHeader>, data: &[u8], address: Option<u8>) -> bool {
+    match (address, header) {
+        (Some(address), Some(h)) if h.addressed() => Addressed::split(header, data).is_some_and(|f| f.is_for(address)),
+        _ => true,
+    }
+}
+
+/// `send_to` for anything that can send frames
+pub trait SendTo<Tx: Write>: FrameSend<Tx> {
+    /// Send `data` from `src` to `dst` (or `BROADCAST`) as one addressed
+    /// frame. The link decides how much fits, same as `send_with_header`.
+    fn send_to(&mut self, src: u8, dst: u8, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let mut header = FrameHeader::for_channel(u6::new(0), u4::new(0));
+        header.set_addressed(true);
+        let packet = frame_data_with(&[dst, src], data).map_err(FrameIOError::from)?;
+        self.send_with_header(header, &packet)
+    }
+}
+
+impl<Tx: Write, S: FrameSend<Tx>> SendTo<Tx> for S {}
+
+/// Something `BusMaster::poll` has to report
+#[derive(Debug)]
+// Only ever handed straight back from `poll`, never stored, so NoReply
+// being as big as a Reply costs nothing
+#[allow(clippy::large_enum_variant)]
+pub enum BusEvent {
+    /// What `slave` answered its poll with, addresses stripped off
+    Reply { slave: u8, data: FrameData },
+    /// `slave` didn't answer within the timeout
+    NoReply { slave: u8 },
+}
+
+enum State {
+    /// Waiting for the bus to go quiet so the next slave can be polled
+    Idle,
+    /// Poll queued for `slave`, waiting on it going out on the wire
+    Sending { slave: u8 },
+    /// Poll sent to `slave` at `since` (by the link's clock), waiting on its answer
+    Waiting { slave: u8, since: u32 },
+}
+
+/// The master on a half-duplex multi-drop bus, polling each slave in turn
+/// and collecting what they answer with.
+///
+/// Only one drop can talk at a time, so the master sends a poll (an empty
+/// frame addressed to the slave) and waits for the answer, or for
+/// `timeout` to pass, before moving on to the next slave. Nothing goes out
+/// until the bus has been quiet for `turnaround` ticks, giving the last
+/// drop to talk time to switch its transceiver back to receiving.
+///
+/// Both are in ticks of the link's clock, see `FrameTxRx::with_clock`.
+pub struct BusMaster<Tx: Write, Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock, const N: usize = 8> {
+    link: FrameTxRx<Tx, Rx, C, B, K>,
+    pub address: u8,
+    /// Ticks to wait on a slave's answer
+    pub timeout: u32,
+    /// Ticks the bus has to be quiet before the master talks
+    pub turnaround: u32,
+    slaves: heapless::Vec<u8, N>,
+    next: usize,
+    state: State,
+    /// Bytes received as of the last poll, to see if the bus is busy
+    heard: u32,
+    quiet_since: u32,
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize> BusMaster<Tx, Rx, C, B, K, N> {
+    /// The master at `address`. `link`'s receiver is set to skip frames
+    /// addressed to anyone else, see `FrameTxRx::with_address`.
+    pub fn new(link: FrameTxRx<Tx, Rx, C, B, K>, address: u8, timeout: u32, turnaround: u32) -> BusMaster<Tx, Rx, C, B, K, N> {
+        let link = link.with_address(address);
+        let heard = link.frx.stats().bytes_received;
+        let quiet_since = link.now();
+        BusMaster {
+            link,
+            address,
+            timeout,
+            turnaround,
+            slaves: heapless::Vec::new(),
+            next: 0,
+            state: State::Idle,
+            heard,
+            quiet_since,
+        }
+    }
+
+    pub fn into_inner(self) -> FrameTxRx<Tx, Rx, C, B, K> {
+        self.link
+    }
+
+    /// Add `slave` to the ones polled. Hands it back if there are already `N`.
+    pub fn add_slave(&mut self, slave: u8) -> Result<(), u8> {
+        self.slaves.push(slave)
+    }
+
+    pub fn slaves(&self) -> &[u8] {
+        &self.slaves
+    }
+
+    /// Do whatever's ready: poll the next slave once the bus is quiet, and
+    /// pick up its answer or give up on it.
+    ///
+    /// WouldBlock means there's nothing to report yet.
+    pub fn poll(&mut self) -> nb::Result<BusEvent, FrameIOError<Tx::Error, Rx::Error>> {
+        match self.link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(nb::Error::Other(widen_read_error(e))),
+        }
+        let now = self.link.now();
+        let heard = self.link.frx.stats().bytes_received;
+        if heard != self.heard {
+            self.heard = heard;
+            self.quiet_since = now;
+        }
+
+        loop {
+            match self.link.recv() {
+                Ok(frame) => {
+                    // Anything but an answer from the slave being polled is
+                    // out of turn, drop it
+                    if let State::Waiting { slave, .. } = self.state
+                        && let Some(f) = Addressed::parse(&frame)
+                        && f.src == slave
+                    {
+                        self.state = State::Idle;
+                        let data = frame_data(f.data).map_err(FrameIOError::from)?;
+                        return Ok(BusEvent::Reply { slave, data });
+                    }
+                },
+                Err(nb::Error::WouldBlock) => break,
+                // A garbled answer is as good as none, the slave times out
+                Err(nb::Error::Other(FrameIOError::Frame(_))) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(widen_read_error(e))),
+            }
+        }
+
+        if let State::Idle = self.state
+            && !self.slaves.is_empty()
+            && now.wrapping_sub(self.quiet_since) >= self.turnaround
+        {
+            let slave = self.slaves[self.next % self.slaves.len()];
+            match self.link.send_to(self.address, slave, &[]) {
+                Ok(()) => {
+                    self.next = (self.next + 1) % self.slaves.len();
+                    self.state = State::Sending { slave };
+                },
+                Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(widen_write_error(e))),
+            }
+        }
+
+        if let State::Sending { slave } = self.state {
+            match self.link.flush() {
+                // The timeout starts once the poll's all out
+                Ok(()) => self.state = State::Waiting { slave, since: now },
+                Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(FrameIOError::Write(e))),
+            }
+        }
+
+        if let State::Waiting { slave, since } = self.state
+            && now.wrapping_sub(since) >= self.timeout
+        {
+            self.state = State::Idle;
+            return Ok(BusEvent::NoReply { slave });
+        }
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Ticks, Wire, link};
+
+    type Master = BusMaster<Wire, Wire, Delimited, DefaultBuffer, Ticks>;
+
+    /// A master at 0 polling slaves 1 and 2, with a 5 tick timeout and a 2
+    /// tick turnaround, and the slave end of the bus
+    fn bus() -> (Master, FrameTxRx<Wire, Wire>, Ticks) {
+        let (m, s) = link();
+        let clock = Ticks::new();
+        let mut master = BusMaster::new(m.with_clock(clock.clone()), 0, 5, 2);
+        master.add_slave(1).unwrap();
+        master.add_slave(2).unwrap();
+        (master, s, clock)
+    }
+
+    #[test]
+    fn only_addressed_frames_have_addresses() {
+        let (a, b) = link();
+        let (mut a, mut b) = (a, b.with_address(7));
+        a.send_to(1, BROADCAST, b"all").unwrap();
+        a.send_with_header(FrameHeader::for_channel(u6::new(0), u4::new(0)), b"plain").unwrap();
+
+        let f = b.recv().unwrap();
+        let addressed = Addressed::parse(&f).unwrap();
+        assert_eq!((addressed.dst, addressed.src, addressed.data), (BROADCAST, 1, &b"all"[..]));
+        assert!(addressed.is_broadcast() && addressed.is_for(7));
+        // Data that happens to start with two bytes isn't an address
+        let f = b.recv().unwrap();
+        assert_eq!(Addressed::parse(&f), None);
+    }
+
+    #[test]
+    fn master_polls_slaves_in_turn() {
+        let (mut master, slave, clock) = bus();
+        let mut slave = slave.with_address(1);
+        // The bus has to be quiet for the turnaround first
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        assert!(slave.recv().is_err());
+        clock.set(2);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        let f = slave.recv().unwrap();
+        assert_eq!(Addressed::parse(&f).map(|a| (a.dst, a.src)), Some((1, 0)));
+
+        slave.send_to(1, 0, b"21C").unwrap();
+        assert!(matches!(master.poll(), Ok(BusEvent::Reply { slave: 1, data }) if data[..] == *b"21C"));
+
+        // Slave 2's turn, once the bus has been quiet since slave 1 answered
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        clock.set(4);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        // Nobody's there, and the poll wasn't for slave 1
+        assert!(matches!(slave.recv(), Err(nb::Error::WouldBlock)));
+        assert_eq!(slave.stats().filtered, 1);
+        clock.set(8);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        clock.set(9);
+        assert!(matches!(master.poll(), Ok(BusEvent::NoReply { slave: 2 })));
+    }
+
+    #[test]
+    fn answers_out_of_turn_are_dropped() {
+        let (mut master, mut slave, clock) = bus();
+        clock.set(2);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        // Slave 2 talks over slave 1's turn, and slave 1 never answers
+        slave.send_to(2, 0, b"me!").unwrap();
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        clock.set(7);
+        assert!(matches!(master.poll(), Ok(BusEvent::NoReply { slave: 1 })));
+    }
+}
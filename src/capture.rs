@@ -0,0 +1,801 @@
+use core::{cell::RefCell, convert::Infallible, fmt};
+
+use embedded_hal_nb::serial::{self, ErrorKind, ErrorType, Read, Write};
+
+use crate::{
+    clock::Clock,
+    codec::FrameCodec,
+    packet::{Frame, FrameError, FrameIOError, FrameRecv, FrameRx, FrameSend, FrameTx},
+    serial::ByteQueue,
+};
+
+/// LINKTYPE_USER0, so Wireshark shows the packets as raw data
+pub const LINKTYPE_USER0: u16 = 147;
+/// pcapng interface of the raw bytes that went over the wire
+pub const INTERFACE_WIRE: u32 = 0;
+/// pcapng interface of the decoded frames' data
+pub const INTERFACE_FRAMES: u32 = 1;
+/// pcapng interface of decode errors. The error is in the packet's comment.
+pub const INTERFACE_ERRORS: u32 = 2;
+/// Wire bytes are held until the line goes quiet or there's this many
+const WIRE_CHUNK: usize = 64;
+/// Longest error comment, anything more is cut off
+const MAX_COMMENT: usize = 128;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+/// epb_flags direction bits
+const FLAG_INBOUND: u32 = 0b01;
+const FLAG_OUTBOUND: u32 = 0b10;
+/// epb_flags link layer error bits
+const FLAG_SYMBOL_ERROR: u32 = 1 << 31;
+const FLAG_DELIMITER_ERROR: u32 = 1 << 29;
+const FLAG_TOO_SHORT: u32 = 1 << 26;
+const FLAG_TOO_LONG: u32 = 1 << 25;
+const FLAG_CRC_ERROR: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// What a capture record holds
+#[derive(Debug, Clone, Copy)]
+pub enum RecordKind<'a> {
+    /// Raw bytes as they went over the wire
+    Wire(&'a [u8]),
+    /// A frame's data
+    Frame(&'a [u8]),
+    Error(&'a FrameError),
+}
+
+/// One thing seen on the link
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub direction: Direction,
+    /// Microseconds, from whatever epoch suits. `CaptureRx`/`CaptureTx`
+    /// count their clock's ticks from when they were made.
+    pub timestamp: u64,
+    pub kind: RecordKind<'a>,
+}
+
+/// Somewhere capture records go, e.g. a `PcapWriter`.
+///
+/// Implemented for `&RefCell<S>` as well, so a `CaptureRx` and `CaptureTx`
+/// can share one capture file.
+pub trait CaptureSink {
+    type Error;
+
+    fn record(&mut self, record: Record<'_>) -> Result<(), Self::Error>;
+}
+
+impl<S: CaptureSink> CaptureSink for &RefCell<S> {
+    type Error = S::Error;
+
+    fn record(&mut self, record: Record<'_>) -> Result<(), Self::Error> {
+        self.borrow_mut().record(record)
+    }
+}
+
+/// Bytes needed to pad `len` out to a multiple of 4
+const fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Writes capture records out as pcapng, one interface each for wire
+/// bytes, frames and errors, all with `LINKTYPE_USER0`.
+///
+/// The direction goes in each packet's `epb_flags`, along with a link layer
+/// error bit for errors where one fits (CRC, too short, ...). The error
+/// itself is the packet's comment.
+pub struct PcapWriter<W: embedded_io::Write> {
+    w: W,
+}
+
+impl<W: embedded_io::Write> PcapWriter<W> {
+    /// Start a capture by writing the section and interface headers to `w`
+    pub fn new(w: W) -> Result<PcapWriter<W>, W::Error> {
+        let mut p = PcapWriter { w };
+        // Section header: no options, section length unknown
+        p.u32(BLOCK_SHB)?;
+        p.u32(28)?;
+        p.u32(BYTE_ORDER_MAGIC)?;
+        p.u16(1)?;
+        p.u16(0)?;
+        p.w.write_all(&(-1i64).to_le_bytes())?;
+        p.u32(28)?;
+        // In INTERFACE_ order
+        for name in ["wire", "frames", "errors"] {
+            p.interface(name)?;
+        }
+        Ok(p)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    pub fn flush(&mut self) -> Result<(), W::Error> {
+        self.w.flush()
+    }
+
+    fn u16(&mut self, v: u16) -> Result<(), W::Error> {
+        self.w.write_all(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Result<(), W::Error> {
+        self.w.write_all(&v.to_le_bytes())
+    }
+
+    /// An option with its value padded out to 4 bytes
+    fn option(&mut self, code: u16, value: &[u8]) -> Result<(), W::Error> {
+        self.u16(code)?;
+        self.u16(value.len() as u16)?;
+        self.w.write_all(value)?;
+        self.w.write_all(&[0; 3][..pad(value.len())])
+    }
+
+    fn interface(&mut self, name: &str) -> Result<(), W::Error> {
+        let len = 16 + 4 + name.len() + pad(name.len()) + 4 + 4;
+        self.u32(BLOCK_IDB)?;
+        self.u32(len as u32)?;
+        self.u16(LINKTYPE_USER0)?;
+        self.u16(0)?;
+        // No snap length
+        self.u32(0)?;
+        self.option(OPT_IF_NAME, name.as_bytes())?;
+        self.option(OPT_END, &[])?;
+        self.u32(len as u32)
+    }
+
+    fn packet(&mut self, interface: u32, timestamp: u64, data: &[u8], flags: u32, comment: &str) -> Result<(), W::Error> {
+        let comment_len = if comment.is_empty() { 0 } else { 4 + comment.len() + pad(comment.len()) };
+        // Header, data, flags option, comment, end of options, trailing length
+        let len = 28 + data.len() + pad(data.len()) + 8 + comment_len + 4 + 4;
+        self.u32(BLOCK_EPB)?;
+        self.u32(len as u32)?;
+        self.u32(interface)?;
+        self.u32((timestamp >> 32) as u32)?;
+        self.u32(timestamp as u32)?;
+        self.u32(data.len() as u32)?;
+        self.u32(data.len() as u32)?;
+        self.w.write_all(data)?;
+        self.w.write_all(&[0; 3][..pad(data.len())])?;
+        self.option(OPT_EPB_FLAGS, &flags.to_le_bytes())?;
+        if !comment.is_empty() {
+            self.option(OPT_COMMENT, comment.as_bytes())?;
+        }
+        self.option(OPT_END, &[])?;
+        self.u32(len as u32)
+    }
+}
+
+impl<W: embedded_io::Write> CaptureSink for PcapWriter<W> {
+    type Error = W::Error;
+
+    fn record(&mut self, record: Record<'_>) -> Result<(), Self::Error> {
+        let flags = match record.direction {
+            Direction::Rx => FLAG_INBOUND,
+            Direction::Tx => FLAG_OUTBOUND,
+        };
+        match record.kind {
+            RecordKind::Wire(bytes) => self.packet(INTERFACE_WIRE, record.timestamp, bytes, flags, ""),
+            RecordKind::Frame(data) => self.packet(INTERFACE_FRAMES, record.timestamp, data, flags, ""),
+            RecordKind::Error(e) => {
+                let mut comment = Truncated(heapless::String::<MAX_COMMENT>::new());
+                let _ = fmt::write(&mut comment, format_args!("{e:?}"));
+                self.packet(INTERFACE_ERRORS, record.timestamp, &[], flags | error_flags(e), &comment.0)
+            },
+        }
+    }
+}
+
+/// The epb_flags link layer error bit closest to `e`, if any
+fn error_flags(e: &FrameError) -> u32 {
+    match e {
+        FrameError::CrcMismatch { .. } => FLAG_CRC_ERROR,
+        FrameError::PayloadTooLarge { .. } => FLAG_TOO_LONG,
+        FrameError::EarlyEndDelim { .. }
+        | FrameError::EarlyStartDelim { .. }
+        | FrameError::DecodeBufferTooSmall { .. } => FLAG_TOO_SHORT,
+        FrameError::MissingStartDelim | FrameError::MissingEndDelim { .. } => FLAG_DELIMITER_ERROR,
+        FrameError::InvalidEncoding { .. } => FLAG_SYMBOL_ERROR,
+        _ => 0,
+    }
+}
+
+/// Formats into a fixed size String, dropping whatever doesn't fit
+struct Truncated<const N: usize>(heapless::String<N>);
+
+impl<const N: usize> fmt::Write for Truncated<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wire bytes waiting to be recorded, and the first error recording hit.
+/// Whatever's still pending goes into the sink when it's dropped.
+struct Tap<S: CaptureSink, T: Clock> {
+    /// Only None once `into_sink` has taken it
+    sink: Option<S>,
+    clock: T,
+    /// Clock reading at the last record, and the ticks counted up to it,
+    /// so timestamps keep going up past the clock wrapping
+    last: u32,
+    elapsed: u64,
+    direction: Direction,
+    pending: heapless::Vec<u8, WIRE_CHUNK>,
+    error: Option<S::Error>,
+}
+
+impl<S: CaptureSink, T: Clock> Tap<S, T> {
+    fn new(sink: S, clock: T, direction: Direction) -> Tap<S, T> {
+        let last = clock.now();
+        Tap { sink: Some(sink), clock, last, elapsed: 0, direction, pending: heapless::Vec::new(), error: None }
+    }
+
+    /// Record what's pending and hand the sink back
+    fn into_sink(mut self) -> S {
+        self.flush();
+        self.sink.take().expect("only taken here")
+    }
+
+    fn timestamp(&mut self) -> u64 {
+        let now = self.clock.now();
+        self.elapsed += now.wrapping_sub(self.last) as u64;
+        self.last = now;
+        self.elapsed
+    }
+
+    /// Once recording fails nothing more is recorded, so the capture doesn't
+    /// have holes in the middle
+    fn record(&mut self, kind: RecordKind<'_>) {
+        if self.error.is_some() {
+            return;
+        }
+        let record = Record { direction: self.direction, timestamp: self.timestamp(), kind };
+        if let Some(Err(e)) = self.sink.as_mut().map(|sink| sink.record(record)) {
+            self.error = Some(e);
+        }
+    }
+
+    fn byte(&mut self, b: u8) {
+        if self.pending.push(b).is_err() {
+            self.flush();
+            let _ = self.pending.push(b);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = core::mem::take(&mut self.pending);
+        self.record(RecordKind::Wire(&pending));
+    }
+
+    /// Record a frame or error, after the wire bytes it came from
+    fn event(&mut self, kind: RecordKind<'_>) {
+        self.flush();
+        self.record(kind);
+    }
+}
+
+impl<S: CaptureSink, T: Clock> Drop for Tap<S, T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Records every byte read from `rx` into a capture, timestamped by `clock`.
+/// pcapng takes the ticks as microseconds, and they only count up right if
+/// something's recorded at least once before the clock wraps.
+///
+/// Bytes are grouped into a packet per burst, a burst ending when `rx`
+/// would block. The last one is recorded when it's dropped or taken apart
+/// with `into_inner`.
+///
+/// Put it under a `FrameRx` and `FrameRx::recv_captured` records the
+/// frames and errors as well.
+///
+/// Recording can't fail a read, the first error is kept for `take_error`
+/// and nothing more is recorded after it.
+pub struct CaptureRx<Rx: Read, S: CaptureSink, T: Clock> {
+    pub rx: Rx,
+    tap: Tap<S, T>,
+}
+
+impl<Rx: Read, S: CaptureSink, T: Clock> CaptureRx<Rx, S, T> {
+    pub fn new(rx: Rx, sink: S, clock: T) -> CaptureRx<Rx, S, T> {
+        CaptureRx { rx, tap: Tap::new(sink, clock, Direction::Rx) }
+    }
+
+    /// Record a frame or decode error, after any wire bytes still held
+    pub fn record(&mut self, kind: RecordKind<'_>) {
+        self.tap.event(kind);
+    }
+
+    /// The error that stopped recording, if there was one
+    pub fn take_error(&mut self) -> Option<S::Error> {
+        self.tap.error.take()
+    }
+
+    pub fn into_inner(self) -> (Rx, S) {
+        (self.rx, self.tap.into_sink())
+    }
+}
+
+impl<Rx: Read, S: CaptureSink, T: Clock> ErrorType for CaptureRx<Rx, S, T> {
+    type Error = Rx::Error;
+}
+
+impl<Rx: Read, S: CaptureSink, T: Clock> Read for CaptureRx<Rx, S, T> {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        match self.rx.read() {
+            Ok(b) => {
+                self.tap.byte(b);
+                Ok(b)
+            },
+            Err(e) => {
+                self.tap.flush();
+                Err(e)
+            },
+        }
+    }
+}
+
+/// Records every byte `tx` takes into a capture, the same as `CaptureRx`.
+/// A burst ends at `flush` or when `tx` would block, and when it's dropped.
+pub struct CaptureTx<Tx: Write, S: CaptureSink, T: Clock> {
+    pub tx: Tx,
+    tap: Tap<S, T>,
+}
+
+impl<Tx: Write, S: CaptureSink, T: Clock> CaptureTx<Tx, S, T> {
+    pub fn new(tx: Tx, sink: S, clock: T) -> CaptureTx<Tx, S, T> {
+        CaptureTx { tx, tap: Tap::new(sink, clock, Direction::Tx) }
+    }
+
+    /// Record a frame or encode error, after any wire bytes still held
+    pub fn record(&mut self, kind: RecordKind<'_>) {
+        self.tap.event(kind);
+    }
+
+    /// The error that stopped recording, if there was one
+    pub fn take_error(&mut self) -> Option<S::Error> {
+        self.tap.error.take()
+    }
+
+    pub fn into_inner(self) -> (Tx, S) {
+        (self.tx, self.tap.into_sink())
+    }
+}
+
+impl<Tx: Write, S: CaptureSink, T: Clock> ErrorType for CaptureTx<Tx, S, T> {
+    type Error = Tx::Error;
+}
+
+impl<Tx: Write, S: CaptureSink, T: Clock> Write for CaptureTx<Tx, S, T> {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        match self.tx.write(word) {
+            Ok(()) => {
+                self.tap.byte(word);
+                Ok(())
+            },
+            Err(e) => {
+                self.tap.flush();
+                Err(e)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.tap.flush();
+        self.tx.flush()
+    }
+}
+
+impl<Rx: Read, S: CaptureSink, T: Clock, C: FrameCodec, B: ByteQueue, K: Clock> FrameRx<CaptureRx<Rx, S, T>, C, B, K> {
+    /// `recv`, recording the frame or decode error in the capture
+    pub fn recv_captured(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, Rx::Error>> {
+        let result = self.recv();
+        match &result {
+            Ok(f) => self.rx.rx.record(RecordKind::Frame(&f.data)),
+            Err(nb::Error::Other(FrameIOError::Frame(e))) => self.rx.rx.record(RecordKind::Error(e)),
+            Err(_) => (),
+        }
+        result
+    }
+}
+
+impl<Tx: Write, S: CaptureSink, T: Clock, C: FrameCodec, B: ByteQueue> FrameTx<CaptureTx<Tx, S, T>, C, B> {
+    /// `send`, recording the frame's data or the encode error in the capture.
+    /// The wire bytes are recorded as they reach tx.
+    pub fn send_captured(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let result = self.send(data);
+        let tap = &mut self.tx.tx;
+        match &result {
+            Ok(()) => tap.record(RecordKind::Frame(data)),
+            Err(nb::Error::Other(FrameIOError::Frame(e))) => tap.record(RecordKind::Error(e)),
+            Err(_) => (),
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The capture isn't pcapng as `PcapWriter` writes it. `offset` is
+    /// where the bad block starts.
+    Malformed { offset: usize },
+}
+
+impl serial::Error for ReplayError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// A packet read back out of a capture
+#[derive(Debug, Clone, Copy)]
+pub struct Packet<'a> {
+    /// One of the `INTERFACE_` constants
+    pub interface: u32,
+    /// None if the packet has no direction flags
+    pub direction: Option<Direction>,
+    /// Microseconds
+    pub timestamp: u64,
+    pub data: &'a [u8],
+    /// The error, for packets on `INTERFACE_ERRORS`
+    pub comment: Option<&'a str>,
+}
+
+/// Iterates over the packets in a little endian pcapng capture, skipping
+/// any other blocks. Stops after the first malformed block.
+pub struct Packets<'a> {
+    capture: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Packets<'a> {
+    pub fn new(capture: &'a [u8]) -> Packets<'a> {
+        Packets { capture, offset: 0 }
+    }
+
+    /// Type, body (without the type, length and trailing length) and total
+    /// length of the block at `offset`
+    fn block(&self, offset: usize) -> Option<(u32, &'a [u8], usize)> {
+        let header = self.capture.get(offset..offset + 8)?;
+        let block_type = u32::from_le_bytes(header[..4].try_into().ok()?);
+        let len = u32::from_le_bytes(header[4..].try_into().ok()?) as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return None;
+        }
+        let body = self.capture.get(offset + 8..offset + len - 4)?;
+        Some((block_type, body, len))
+    }
+
+    fn packet(body: &'a [u8]) -> Option<Packet<'a>> {
+        let u32_at = |at: usize| body.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let interface = u32_at(0)?;
+        let timestamp = ((u32_at(4)? as u64) << 32) | u32_at(8)? as u64;
+        let captured = u32_at(12)? as usize;
+        let data = body.get(20..20 + captured)?;
+
+        let mut packet = Packet { interface, direction: None, timestamp, data, comment: None };
+        let mut at = 20 + captured + pad(captured);
+        while let Some(header) = body.get(at..at + 4) {
+            let code = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let value = body.get(at + 4..at + 4 + len)?;
+            match code {
+                OPT_END => break,
+                OPT_EPB_FLAGS if len == 4 => {
+                    let flags = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                    packet.direction = match flags & 0b11 {
+                        FLAG_INBOUND => Some(Direction::Rx),
+                        FLAG_OUTBOUND => Some(Direction::Tx),
+                        _ => None,
+                    };
+                },
+                OPT_COMMENT => packet.comment = core::str::from_utf8(value).ok(),
+                _ => (),
+            }
+            at += 4 + len + pad(len);
+        }
+        Some(packet)
+    }
+}
+
+impl<'a> Iterator for Packets<'a> {
+    type Item = Result<Packet<'a>, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.offset < self.capture.len() {
+            let offset = self.offset;
+            let parsed = self.block(offset).and_then(|(block_type, body, len)| {
+                let packet = match block_type {
+                    BLOCK_EPB => Some(Self::packet(body)?),
+                    // Only little endian captures
+                    BLOCK_SHB if body.get(..4)? != BYTE_ORDER_MAGIC.to_le_bytes() => return None,
+                    _ => None,
+                };
+                Some((packet, len))
+            });
+            match parsed {
+                Some((packet, len)) => {
+                    self.offset += len;
+                    if let Some(p) = packet {
+                        return Some(Ok(p));
+                    }
+                },
+                None => {
+                    self.offset = self.capture.len();
+                    return Some(Err(ReplayError::Malformed { offset }));
+                },
+            }
+        }
+        None
+    }
+}
+
+/// Plays the wire bytes of a capture back as a serial port, e.g. to feed
+/// a `FrameRx` the exact bytes that tripped it up on a board.
+///
+/// Reads WouldBlock once the capture runs out, same as a quiet line.
+pub struct Replay<'a> {
+    packets: Packets<'a>,
+    direction: Direction,
+    current: &'a [u8],
+}
+
+impl<'a> Replay<'a> {
+    /// Replay the bytes that were received
+    pub fn new(capture: &'a [u8]) -> Replay<'a> {
+        Replay::with_direction(capture, Direction::Rx)
+    }
+
+    /// Replay the bytes that went in `direction`, e.g. `Tx` to check what a
+    /// board sent
+    pub fn with_direction(capture: &'a [u8], direction: Direction) -> Replay<'a> {
+        Replay { packets: Packets::new(capture), direction, current: &[] }
+    }
+}
+
+impl ErrorType for Replay<'_> {
+    type Error = ReplayError;
+}
+
+impl Read for Replay<'_> {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        while self.current.is_empty() {
+            match self.packets.next() {
+                Some(Ok(p)) if p.interface == INTERFACE_WIRE && p.direction == Some(self.direction) => {
+                    self.current = p.data;
+                },
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(nb::Error::Other(e)),
+                None => return Err(nb::Error::WouldBlock),
+            }
+        }
+        let b = self.current[0];
+        self.current = &self.current[1..];
+        Ok(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        Encode,
+        mock::{Ticks, Wire},
+    };
+
+    /// Microseconds that go up by one every time they're read
+    fn ticks() -> impl Clock {
+        let t = Cell::new(0u32);
+        move || {
+            t.set(t.get() + 1);
+            t.get()
+        }
+    }
+
+    fn packets(capture: &[u8]) -> Vec<Packet<'_>> {
+        Packets::new(capture).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn records_read_back() {
+        let file = Wire::new();
+        let mut w = PcapWriter::new(file.clone()).unwrap();
+        let e = FrameError::CrcMismatch {
+            calculated: 1,
+            found: 2,
+            width: 1,
+            #[cfg(feature = "alloc")]
+            buf: Vec::new(),
+        };
+        w.record(Record { direction: Direction::Rx, timestamp: 1 << 40 | 7, kind: RecordKind::Wire(&[1, 2, 3]) }).unwrap();
+        w.record(Record { direction: Direction::Tx, timestamp: 8, kind: RecordKind::Frame(b"data") }).unwrap();
+        w.record(Record { direction: Direction::Rx, timestamp: 9, kind: RecordKind::Error(&e) }).unwrap();
+        let capture = file.take();
+        // Every block is padded out to 4 bytes
+        assert_eq!(capture.len() % 4, 0);
+
+        let p = packets(&capture);
+        assert_eq!(p.len(), 3);
+        assert_eq!((p[0].interface, p[0].direction, p[0].timestamp, p[0].data), (INTERFACE_WIRE, Some(Direction::Rx), 1 << 40 | 7, &[1, 2, 3][..]));
+        assert_eq!((p[1].interface, p[1].direction, p[1].data), (INTERFACE_FRAMES, Some(Direction::Tx), &b"data"[..]));
+        assert_eq!(p[1].comment, None);
+        assert_eq!((p[2].interface, p[2].data), (INTERFACE_ERRORS, &[][..]));
+        assert!(p[2].comment.unwrap().starts_with("CrcMismatch"));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn long_error_comments_are_cut_off() {
+        let file = Wire::new();
+        let mut w = PcapWriter::new(file.clone()).unwrap();
+        let e = FrameError::CrcMismatch { calculated: 1, found: 2, width: 1, buf: [0xAB; 100].to_vec() };
+        w.record(Record { direction: Direction::Rx, timestamp: 0, kind: RecordKind::Error(&e) }).unwrap();
+        let capture = file.take();
+        assert_eq!(packets(&capture)[0].comment.unwrap().len(), MAX_COMMENT);
+    }
+
+    #[test]
+    fn captured_link_replays_the_same() {
+        let (wire, file) = (Wire::new(), Wire::new());
+        let sink = RefCell::new(PcapWriter::new(file.clone()).unwrap());
+        let mut tx = FrameTx::new(CaptureTx::new(wire.clone(), &sink, ticks()));
+        let mut rx = FrameRx::new(CaptureRx::new(wire.clone(), &sink, ticks()));
+
+        tx.send_captured(b"good").unwrap();
+        assert!(matches!(rx.recv_captured(), Ok(f) if f.data[..] == *b"good"));
+        // A bad frame, straight onto the wire
+        let mut bad = [0; 16];
+        let len = b"bad".as_slice().encode(&mut bad).unwrap();
+        bad[len - 2] ^= 0x01;
+        wire.push(&bad[..len]);
+        assert!(matches!(rx.recv_captured(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        drop((tx, rx));
+        let capture = file.take();
+
+        let kinds: Vec<_> = packets(&capture).iter().map(|p| (p.interface, p.direction)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (INTERFACE_WIRE, Some(Direction::Tx)),
+                (INTERFACE_FRAMES, Some(Direction::Tx)),
+                (INTERFACE_WIRE, Some(Direction::Rx)),
+                (INTERFACE_FRAMES, Some(Direction::Rx)),
+                (INTERFACE_WIRE, Some(Direction::Rx)),
+                (INTERFACE_ERRORS, Some(Direction::Rx)),
+            ]
+        );
+
+        // Played back, the receive side trips over the same frames
+        let mut replay = FrameRx::new(Replay::new(&capture));
+        assert_eq!(replay.recv().unwrap().data[..], *b"good");
+        assert!(matches!(replay.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        assert!(matches!(replay.recv(), Err(nb::Error::WouldBlock)));
+        // And the sending side has what went out
+        let mut sent = FrameRx::new(Replay::with_direction(&capture, Direction::Tx));
+        assert_eq!(sent.recv().unwrap().data[..], *b"good");
+    }
+
+    /// Fails every record after the first `ok`
+    struct Flaky {
+        ok: usize,
+        recorded: Cell<usize>,
+    }
+
+    impl CaptureSink for Flaky {
+        type Error = ();
+
+        fn record(&mut self, _record: Record<'_>) -> Result<(), ()> {
+            if self.recorded.get() == self.ok {
+                return Err(());
+            }
+            self.recorded.set(self.recorded.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn recording_stops_at_the_first_error() {
+        let wire = Wire::new();
+        let mut rx = CaptureRx::new(wire.clone(), Flaky { ok: 1, recorded: Cell::new(0) }, ticks());
+        wire.push(&[1, 2]);
+        // Reads carry on regardless
+        assert_eq!(rx.read(), Ok(1));
+        assert_eq!(rx.read(), Ok(2));
+        assert_eq!(rx.read(), Err(nb::Error::WouldBlock));
+        rx.record(RecordKind::Frame(&[1]));
+        wire.push(&[3]);
+        assert_eq!(rx.read(), Ok(3));
+        assert_eq!(rx.read(), Err(nb::Error::WouldBlock));
+
+        assert_eq!(rx.take_error(), Some(()));
+        assert_eq!(rx.take_error(), None);
+        let (_, sink) = rx.into_inner();
+        assert_eq!(sink.recorded.get(), 1);
+    }
+
+    #[test]
+    fn timestamps_carry_on_past_the_clock_wrapping() {
+        let (wire, file) = (Wire::new(), Wire::new());
+        let clock = Ticks::new();
+        clock.set(u32::MAX - 1);
+        let mut rx = CaptureRx::new(wire.clone(), PcapWriter::new(file.clone()).unwrap(), clock.clone());
+        clock.set(u32::MAX);
+        rx.record(RecordKind::Frame(b"a"));
+        clock.set(3);
+        rx.record(RecordKind::Frame(b"b"));
+        drop(rx);
+        let capture = file.take();
+        let times: Vec<_> = packets(&capture).iter().map(|p| p.timestamp).collect();
+        assert_eq!(times, [1, 5]);
+    }
+
+    #[test]
+    fn bursts_are_cut_at_wire_chunk() {
+        let (wire, file) = (Wire::new(), Wire::new());
+        let mut rx = CaptureRx::new(wire.clone(), PcapWriter::new(file.clone()).unwrap(), ticks());
+        wire.push(&[0; WIRE_CHUNK + 1]);
+        while rx.read().is_ok() {}
+        drop(rx);
+        let capture = file.take();
+        let sizes: Vec<_> = packets(&capture).iter().map(|p| p.data.len()).collect();
+        assert_eq!(sizes, [WIRE_CHUNK, 1]);
+    }
+
+    #[test]
+    fn the_last_burst_is_recorded_on_the_way_out() {
+        let (wire, tx_file, rx_file) = (Wire::new(), Wire::new(), Wire::new());
+        let mut tx = CaptureTx::new(wire.clone(), PcapWriter::new(tx_file.clone()).unwrap(), ticks());
+        tx.write(1).unwrap();
+        tx.write(2).unwrap();
+        drop(tx);
+        let capture = tx_file.take();
+        assert!(packets(&capture).iter().map(|p| p.data).eq([&[1, 2][..]]));
+
+        // Stopped short of the read that would block
+        let mut rx = CaptureRx::new(wire.clone(), PcapWriter::new(rx_file.clone()).unwrap(), ticks());
+        assert_eq!(rx.read(), Ok(1));
+        assert_eq!(rx.read(), Ok(2));
+        drop(rx.into_inner());
+        let capture = rx_file.take();
+        assert!(packets(&capture).iter().map(|p| p.data).eq([&[1, 2][..]]));
+    }
+
+    #[test]
+    fn malformed_captures_are_errors() {
+        let file = Wire::new();
+        let mut w = PcapWriter::new(file.clone()).unwrap();
+        let last = file.len();
+        w.record(Record { direction: Direction::Rx, timestamp: 0, kind: RecordKind::Wire(&[1]) }).unwrap();
+        let mut capture = file.take();
+        // Block length that isn't a multiple of 4
+        capture[last + 4] = 0x21;
+        let mut replay = Replay::new(&capture);
+        assert_eq!(replay.read(), Err(nb::Error::Other(ReplayError::Malformed { offset: last })));
+    }
+}
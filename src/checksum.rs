@@ -0,0 +1,188 @@
+use crc::{Crc, Digest};
+
+/// Widest checksum any of the codecs can carry, in bytes
+pub const MAX_CHECKSUM_WIDTH: usize = 4;
+
+static CRC_8: Crc<u8> = Crc::<u8>::new(&crc::CRC_8_MAXIM_DOW);
+static CRC_16: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+static CRC_32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// Checksum trailer for a frame, chosen at the type level on the codec,
+/// e.g. `Delimited<Crc16Ccitt>`.
+///
+/// The checksum covers the frame's header (the size byte) and the data, and
+/// goes on the wire as `WIDTH` little endian bytes.
+pub trait Checksum {
+    /// Bytes the checksum takes up on the wire
+    const WIDTH: usize;
+
+    /// Running state for building a checksum up a few bytes at a time
+    type Digest;
+
+    fn digest() -> Self::Digest;
+
+    fn update(digest: &mut Self::Digest, bytes: &[u8]);
+
+    fn finalize(digest: Self::Digest) -> u32;
+
+    fn checksum(header: &[u8], data: &[u8]) -> u32 {
+        let mut d = Self::digest();
+        Self::update(&mut d, header);
+        Self::update(&mut d, data);
+        Self::finalize(d)
+    }
+
+    /// Write the low `WIDTH` bytes of `value` to the front of `buffer`
+    fn write(value: u32, buffer: &mut [u8]) {
+        buffer[..Self::WIDTH].copy_from_slice(&value.to_le_bytes()[..Self::WIDTH]);
+    }
+
+    /// Read a checksum off the front of `buffer`
+    fn read(buffer: &[u8]) -> u32 {
+        let mut b = [0; MAX_CHECKSUM_WIDTH];
+        b[..Self::WIDTH].copy_from_slice(&buffer[..Self::WIDTH]);
+        u32::from_le_bytes(b)
+    }
+}
+
+/// No checksum at all. Nothing goes on the wire and every frame passes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoCrc;
+
+impl Checksum for NoCrc {
+    const WIDTH: usize = 0;
+    type Digest = ();
+
+    fn digest() {}
+
+    fn update(_digest: &mut (), _bytes: &[u8]) {}
+
+    fn finalize(_digest: ()) -> u32 {
+        0
+    }
+}
+
+/// CRC-8/MAXIM-DOW, the original single byte CRC
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Crc8;
+
+impl Checksum for Crc8 {
+    const WIDTH: usize = 1;
+    type Digest = Digest<'static, u8>;
+
+    fn digest() -> Self::Digest {
+        CRC_8.digest()
+    }
+
+    fn update(digest: &mut Self::Digest, bytes: &[u8]) {
+        digest.update(bytes);
+    }
+
+    fn finalize(digest: Self::Digest) -> u32 {
+        digest.finalize() as u32
+    }
+}
+
+/// CRC-16/CCITT-FALSE (aka CRC-16/IBM-3740)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Crc16Ccitt;
+
+impl Checksum for Crc16Ccitt {
+    const WIDTH: usize = 2;
+    type Digest = Digest<'static, u16>;
+
+    fn digest() -> Self::Digest {
+        CRC_16.digest()
+    }
+
+    fn update(digest: &mut Self::Digest, bytes: &[u8]) {
+        digest.update(bytes);
+    }
+
+    fn finalize(digest: Self::Digest) -> u32 {
+        digest.finalize() as u32
+    }
+}
+
+/// CRC-32C (Castagnoli, aka CRC-32/ISCSI)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Crc32C;
+
+impl Checksum for Crc32C {
+    const WIDTH: usize = 4;
+    type Digest = Digest<'static, u32>;
+
+    fn digest() -> Self::Digest {
+        CRC_32.digest()
+    }
+
+    fn update(digest: &mut Self::Digest, bytes: &[u8]) {
+        digest.update(bytes);
+    }
+
+    fn finalize(digest: Self::Digest) -> u32 {
+        digest.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Decode,
+        codec::{Delimited, FrameCodec},
+        packet::{Frame, FrameError, MAX_FRAME_SIZE},
+    };
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn check_values() {
+        assert_eq!(NoCrc::checksum(&[], CHECK), 0);
+        assert_eq!(Crc8::checksum(&[], CHECK), 0xA1);
+        assert_eq!(Crc16Ccitt::checksum(&[], CHECK), 0x29B1);
+        assert_eq!(Crc32C::checksum(&[], CHECK), 0xE306_9283);
+        // Header and data are one run of bytes
+        assert_eq!(Crc32C::checksum(&CHECK[..1], &CHECK[1..]), 0xE306_9283);
+    }
+
+    #[test]
+    fn trailer_is_width_bytes_little_endian() {
+        let mut buf = [0xEE; MAX_CHECKSUM_WIDTH];
+        Crc16Ccitt::write(0x1234_5678, &mut buf);
+        assert_eq!(buf, [0x78, 0x56, 0xEE, 0xEE]);
+        assert_eq!(Crc16Ccitt::read(&buf), 0x5678);
+        assert_eq!(Crc32C::read(&[0x78, 0x56, 0x34, 0x12]), 0x1234_5678);
+    }
+
+    fn round_trip<C: Checksum>() -> (Frame, usize) {
+        let codec = Delimited::<C>::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let len = codec.encode(CHECK, &mut buf).unwrap();
+        let (frame, used) = codec.decode(&buf[..len]).unwrap();
+        assert_eq!(frame.data[..], *CHECK);
+        assert_eq!(frame.crc, C::checksum(&[CHECK.len() as u8], CHECK));
+        assert_eq!(frame.encoded_len(&codec).unwrap(), used);
+        (frame, used)
+    }
+
+    #[test]
+    fn frames_carry_the_configured_width() {
+        // None of these checksums need escaping
+        let (_, none) = round_trip::<NoCrc>();
+        assert_eq!(round_trip::<Crc8>().1, none + 1);
+        assert_eq!(round_trip::<Crc16Ccitt>().1, none + 2);
+        assert_eq!(round_trip::<Crc32C>().1, none + 4);
+    }
+
+    #[test]
+    fn mismatch_reports_the_width() {
+        let codec = Delimited::<Crc32C>::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let len = codec.encode(CHECK, &mut buf).unwrap();
+        buf[len - 2] ^= 0x01;
+        assert!(matches!(codec.decode(&buf[..len]), Err(FrameError::CrcMismatch { width: 4, .. })));
+        // The default Frame decode is still the single CRC-8 byte
+        assert!(Frame::decode(&buf[..len]).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+/// Tells the time for timeouts, e.g. off an embedded-hal timer or
+/// `std::time::Instant`.
+///
+/// Ticks are whatever unit suits (ms, timer counts, ...) as long as the
+/// timeouts are given in the same ones. They're expected to wrap, only the
+/// difference between two readings matters.
+///
+/// Implemented for closures, so `move || start.elapsed().as_millis() as u32`
+/// does the job on a host.
+pub trait Clock {
+    fn now(&self) -> u32;
+}
+
+impl<F: Fn() -> u32> Clock for F {
+    fn now(&self) -> u32 {
+        self()
+    }
+}
+
+/// No clock at all, for when nothing should ever time out
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now(&self) -> u32 {
+        0
+    }
+}
@@ -0,0 +1,288 @@
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::marker::PhantomData;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::{
+    checksum::{Checksum, Crc8, MAX_CHECKSUM_WIDTH},
+    codec::FrameCodec,
+    packet::{FrameError, FrameRef, MAX_DATA_SIZE, check_payload},
+};
+
+/// Byte that ends every COBS frame. It never shows up anywhere else.
+pub const COBS_DELIMITER: u8 = 0x00;
+/// A COBS block holds at most 254 data bytes after its code byte
+const COBS_BLOCK: usize = 254;
+/// Data: MAX_DATA_SIZE, CRC: up to MAX_CHECKSUM_WIDTH,
+/// one code byte per 254 bytes plus one, End: 1
+pub const COBS_MAX_FRAME_SIZE: usize = cobs_max_len(MAX_DATA_SIZE + MAX_CHECKSUM_WIDTH) + 1;
+
+/// Worst case encoded length of `len` bytes, not counting the trailing zero
+const fn cobs_max_len(len: usize) -> usize {
+    len + len / COBS_BLOCK + 1
+}
+
+/// Run over the COBS blocks in `encoded` (everything before the trailing
+/// zero) handing each decoded byte to `f`. Returns how many bytes it decoded to.
+fn cobs_blocks(encoded: &[u8], mut f: impl FnMut(u8)) -> Result<usize, FrameError> {
+    let mut n = 0;
+    let mut i = 0;
+    while i < encoded.len() {
+        let code = encoded[i] as usize;
+        // A code byte pointing past the end of the frame means something got mangled
+        if code == 0 || i + code > encoded.len() {
+            return Err(FrameError::InvalidEncoding { index: i });
+        }
+        for b in &encoded[i + 1..i + code] {
+            f(*b);
+        }
+        n += code - 1;
+        i += code;
+        // Every block other than a full one or the last stood in for a zero
+        if code != COBS_BLOCK + 1 && i < encoded.len() {
+            f(COBS_DELIMITER);
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Consistent Overhead Byte Stuffing. Each frame is the COBS encoded data
+/// followed by the CRC, ending in a single 0x00.
+///
+/// There is no size byte on the wire, the zero marks the end of the frame.
+/// The CRC still covers the size so `Frame::crc` means the same thing
+/// whichever codec made it. After corruption we just skip to the next zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cobs<C: Checksum = Crc8>(PhantomData<C>);
+
+impl<C: Checksum> Cobs<C> {
+    pub const fn new() -> Cobs<C> {
+        Cobs(PhantomData)
+    }
+}
+
+impl<C: Checksum> FrameCodec for Cobs<C> {
+    const MAX_FRAME_SIZE: usize = cobs_max_len(MAX_DATA_SIZE + C::WIDTH) + 1;
+    const MAX_DATA_SIZE: usize = MAX_DATA_SIZE;
+
+    fn encode_with(&self, data: &[u8], mut out: impl FnMut(u8)) -> Result<usize, FrameError> {
+        check_payload(data.len(), MAX_DATA_SIZE)?;
+        let size = data.len();
+        let mut crc = [0; MAX_CHECKSUM_WIDTH];
+        C::write(C::checksum(&[size as u8], data), &mut crc);
+
+        // Data then crc, as one run of bytes
+        let raw = |k: usize| if k < size { data[k] } else { crc[k - size] };
+        let n = size + C::WIDTH;
+
+        // Each block starts with a code byte saying how far it is to the next
+        // zero, so look ahead for the zero (or a full block) before sending any of it
+        let mut written = 0;
+        let mut start = 0;
+        loop {
+            let len = (start..n)
+                .take(COBS_BLOCK)
+                .take_while(|k| raw(*k) != COBS_DELIMITER)
+                .count();
+            out(len as u8 + 1);
+            (start..start + len).for_each(|k| out(raw(k)));
+            written += len + 1;
+            start += len;
+            // A full block doesn't stand in for a zero, so there's always another after it
+            if len == COBS_BLOCK {
+                continue;
+            }
+            if start == n {
+                break;
+            }
+            // Skip the zero this block stood in for
+            start += 1;
+        }
+        out(COBS_DELIMITER);
+
+        Ok(written + 1)
+    }
+
+    fn decode_in_place<'a>(&self, data: &'a mut [u8]) -> Result<(FrameRef<'a>, usize), FrameError> {
+        let end = match data.iter().position(|b| *b == COBS_DELIMITER) {
+            Some(end) => end,
+            None if data.len() >= Self::MAX_FRAME_SIZE => {
+                // We've got more than a frame's worth and still no zero
+                return Err(FrameError::MissingEndDelim {
+                    index: Self::MAX_FRAME_SIZE - 1,
+                    found: data[Self::MAX_FRAME_SIZE - 1],
+                });
+            }
+            None => {
+                return Err(FrameError::DecodeBufferTooSmall {
+                    expected_at_least: data.len() + 1,
+                    found: data.len(),
+                });
+            }
+        };
+
+        // First time through just check the blocks make sense and count
+        // how many bytes they decode to
+        let n = cobs_blocks(&data[..end], |_| {})?;
+        // Last bytes are the crc
+        if n < C::WIDTH {
+            return Err(FrameError::InvalidEncoding { index: 0 });
+        }
+        let size = n - C::WIDTH;
+        if size > MAX_DATA_SIZE {
+            return Err(FrameError::InvalidEncoding { index: end });
+        }
+
+        // Second time through check the crc
+        let mut d = C::digest();
+        C::update(&mut d, &[size as u8]);
+        let mut crc = [0; MAX_CHECKSUM_WIDTH];
+        let mut k = 0;
+        cobs_blocks(&data[..end], |b| {
+            if k < size {
+                C::update(&mut d, &[b]);
+            } else {
+                crc[k - size] = b;
+            }
+            k += 1;
+        })?;
+        let crc = C::read(&crc);
+        let calc_crc = C::finalize(d);
+        if crc != calc_crc {
+            return Err(FrameError::CrcMismatch {
+                calculated: calc_crc,
+                found: crc,
+                width: C::WIDTH,
+                #[cfg(feature = "alloc")]
+                buf: Vec::from(&data[..=end]),
+            });
+        }
+
+        // Now it's good we can decode for real. Decoded bytes are never further
+        // along than their encoded version, so we can write from the front as we read.
+        let mut i = 0;
+        let mut w = 0;
+        while i < end {
+            let code = data[i] as usize;
+            data.copy_within(i + 1..i + code, w);
+            w += code - 1;
+            i += code;
+            if code != COBS_BLOCK + 1 && i < end {
+                data[w] = COBS_DELIMITER;
+                w += 1;
+            }
+        }
+
+        Ok((FrameRef { size: size as u16, header: None, data: &data[..size], crc }, end + 1))
+    }
+
+    fn skip_to_start(&self, data: &[u8]) -> usize {
+        // Zeros between frames are just empty frames, toss them
+        data.iter().take_while(|b| **b == COBS_DELIMITER).count()
+    }
+
+    fn only_separators(&self, skipped: &[u8]) -> bool {
+        skipped.iter().all(|b| *b == COBS_DELIMITER)
+    }
+
+    fn skip_after_error(&self, data: &[u8], _error: &FrameError) -> usize {
+        // Whatever went wrong, the next frame starts after the next zero
+        data.iter()
+            .position(|b| *b == COBS_DELIMITER)
+            .map(|i| i + 1)
+            .unwrap_or(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::{Crc16Ccitt, NoCrc},
+        mock::Wire,
+        packet::{FrameIOError, FrameRecv, FrameRx, FrameSend, FrameTx},
+    };
+
+    fn encoded<C: Checksum>(data: &[u8]) -> ([u8; COBS_MAX_FRAME_SIZE], usize) {
+        let mut buf = [0; COBS_MAX_FRAME_SIZE];
+        let len = Cobs::<C>::new().encode(data, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    #[test]
+    fn encodes_like_the_paper() {
+        let (buf, len) = encoded::<NoCrc>(&[0x11, 0x22, 0x00, 0x33]);
+        assert_eq!(buf[..len], [0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+        let (buf, len) = encoded::<NoCrc>(&[0x00]);
+        assert_eq!(buf[..len], [0x01, 0x01, 0x00]);
+        let (buf, len) = encoded::<NoCrc>(&[]);
+        assert_eq!(buf[..len], [0x01, 0x00]);
+    }
+
+    #[test]
+    fn only_zero_ends_the_frame() {
+        let mut data = [0; MAX_DATA_SIZE];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = if i % 3 == 0 { 0 } else { i as u8 };
+        }
+        let (buf, len) = encoded::<Crc16Ccitt>(&data);
+        assert!(len <= Cobs::<Crc16Ccitt>::MAX_FRAME_SIZE);
+        assert_eq!(buf[..len].iter().position(|b| *b == COBS_DELIMITER), Some(len - 1));
+    }
+
+    #[test]
+    fn round_trips_around_block_boundaries() {
+        let codec = Cobs::<Crc8>::new();
+        for size in [0, 1, 253, 254, 255] {
+            for fill in [0x00, 0x01] {
+                let data = [fill; MAX_DATA_SIZE];
+                let (mut buf, len) = encoded::<Crc8>(&data[..size]);
+                assert_eq!(codec.encoded_len(&data[..size]).unwrap(), len);
+                let (frame, used) = codec.decode_in_place(&mut buf[..len]).unwrap();
+                assert_eq!(frame.data, &data[..size]);
+                assert_eq!(used, len);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_code_byte_is_invalid() {
+        // Points past the zero that ends the frame
+        let mut data = [0x05, 0x11, 0x00];
+        assert!(matches!(
+            Cobs::<NoCrc>::new().decode_in_place(&mut data),
+            Err(FrameError::InvalidEncoding { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn resyncs_on_the_next_zero() {
+        let wire = Wire::new();
+        let mut tx = FrameTx::with_codec(wire.clone(), Cobs::<Crc8>::new());
+        let mut rx = FrameRx::with_codec(wire.clone(), Cobs::<Crc8>::new());
+        tx.send(b"first").unwrap();
+        let (mut buf, len) = encoded::<Crc8>(b"mangled");
+        buf[3] ^= 0x40;
+        wire.push(&buf[..len]);
+        tx.send(b"last").unwrap();
+
+        assert_eq!(rx.recv().unwrap().data[..], *b"first");
+        assert!(matches!(rx.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        assert_eq!(rx.recv().unwrap().data[..], *b"last");
+    }
+
+    #[test]
+    fn stray_zeros_are_skipped() {
+        let wire = Wire::new();
+        let mut rx = FrameRx::with_codec(wire.clone(), Cobs::<Crc8>::new());
+        let (buf, len) = encoded::<Crc8>(&[0, 0, 0]);
+        wire.push(&[0, 0]);
+        wire.push(&buf[..len]);
+        assert_eq!(rx.recv().unwrap().data[..], [0, 0, 0]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+    }
+}
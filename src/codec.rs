@@ -0,0 +1,267 @@
+use core::marker::PhantomData;
+
+use crate::{
+    checksum::{Checksum, Crc8, Crc32C, MAX_CHECKSUM_WIDTH},
+    packet::{
+        DELIMITER, Frame, FrameError, FrameRef, MAX_DATA_SIZE, MAX_FRAME_SIZE, decode_frame,
+        decode_frame_in_place, encode_frame_with, encoded_frame_len,
+    },
+};
+
+/// Size field of an Extended frame is a u16, but we cap it at 4 KiB so
+/// buffers on both ends can be sized for it
+pub const EXTENDED_MAX_DATA_SIZE: usize = 4096;
+/// Start: 1, Size, Data and CRC: twice their size if all escaped, End: 1
+pub const EXTENDED_MAX_FRAME_SIZE: usize = 2 + 2 * (2 + EXTENDED_MAX_DATA_SIZE + MAX_CHECKSUM_WIDTH);
+
+/// A wire format for getting Frames on and off a byte stream.
+///
+/// `FrameTx`/`FrameRx` (and so `FrameTxRx`) are generic over the codec so
+/// the same `FrameSend`/`FrameRecv` API works whatever the bytes on the
+/// wire look like.
+pub trait FrameCodec {
+    /// Most bytes a single encoded frame can take up on the wire.
+    /// Codecs that go over the crate wide `MAX_FRAME_SIZE` have to override `decode`.
+    const MAX_FRAME_SIZE: usize;
+
+    /// Most data a single frame can carry. Any more is `FrameError::PayloadTooLarge`.
+    const MAX_DATA_SIZE: usize;
+
+    /// Encode `data` as a single frame, handing the encoded bytes to `out`
+    /// one at a time. Returns how many bytes there were.
+    ///
+    /// Nothing is handed to `out` if this fails.
+    fn encode_with(&self, data: &[u8], out: impl FnMut(u8)) -> Result<usize, FrameError>;
+
+    /// How many bytes `data` takes up once encoded.
+    ///
+    /// By default this runs the encoder and counts.
+    fn encoded_len(&self, data: &[u8]) -> Result<usize, FrameError> {
+        self.encode_with(data, |_| {})
+    }
+
+    /// Encode `data` as a single frame into `buffer`, returning how many
+    /// bytes were written
+    fn encode(&self, data: &[u8], buffer: &mut [u8]) -> Result<usize, FrameError> {
+        let required = self.encoded_len(data)?;
+        if buffer.len() < required {
+            return Err(FrameError::EncodeBufferTooSmall {
+                expected_at_least: required,
+                found: buffer.len(),
+            });
+        }
+        let mut buffer = buffer.iter_mut();
+        self.encode_with(data, |b| {
+            if let Some(out) = buffer.next() {
+                *out = b;
+            }
+        })
+    }
+
+    /// Decode the frame at the front of `data`. Alongside the Frame we hand
+    /// back how many bytes of `data` it took up.
+    ///
+    /// `FrameError::DecodeBufferTooSmall` means the frame isn't all here yet.
+    ///
+    /// By default this decodes a copy of the front of `data` in place.
+    fn decode(&self, data: &[u8]) -> Result<(Frame, usize), FrameError> {
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let len = data.len().min(MAX_FRAME_SIZE);
+        buf[..len].copy_from_slice(&data[..len]);
+        let (f, used) = self.decode_in_place(&mut buf[..len])?;
+        Ok((f.to_frame()?, used))
+    }
+
+    /// Same as `decode` but the frame's data is decoded in place in `data`
+    /// and borrowed by the FrameRef rather than copied out.
+    ///
+    /// `data` must be left untouched if this returns an error, so the caller
+    /// can still use it to resync.
+    fn decode_in_place<'a>(&self, data: &'a mut [u8]) -> Result<(FrameRef<'a>, usize), FrameError>;
+
+    /// How many bytes at the front of `data` can be thrown away because
+    /// they can't be the start of a frame.
+    fn skip_to_start(&self, data: &[u8]) -> usize;
+
+    /// True if `skipped`, thrown away by `skip_to_start`, is only what the
+    /// codec itself puts between frames rather than line noise.
+    ///
+    /// By default any skipped byte is noise.
+    fn only_separators(&self, skipped: &[u8]) -> bool {
+        skipped.is_empty()
+    }
+
+    /// How many bytes at the front of `data` to throw away after `decode`
+    /// failed with `error`, so the next attempt starts on a fresh frame.
+    fn skip_after_error(&self, data: &[u8], error: &FrameError) -> usize;
+}
+
+/// The original format: Start Delimiter, Size byte, data, CRC and End
+/// Delimiter, with everything between the delimiters byte stuffed.
+///
+/// The checksum defaults to the original single CRC-8 byte.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Delimited<C: Checksum = Crc8>(PhantomData<C>);
+
+impl<C: Checksum> Delimited<C> {
+    pub const fn new() -> Delimited<C> {
+        Delimited(PhantomData)
+    }
+}
+
+impl<C: Checksum> FrameCodec for Delimited<C> {
+    /// Start: 1, Size, Data and CRC: twice their size if all escaped, End: 1
+    const MAX_FRAME_SIZE: usize = 2 + 2 * (1 + MAX_DATA_SIZE + C::WIDTH);
+    const MAX_DATA_SIZE: usize = MAX_DATA_SIZE;
+
+    fn encode_with(&self, data: &[u8], out: impl FnMut(u8)) -> Result<usize, FrameError> {
+        encode_frame_with::<C, 1>(data, out)
+    }
+
+    fn encoded_len(&self, data: &[u8]) -> Result<usize, FrameError> {
+        encoded_frame_len::<C, 1>(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<(Frame, usize), FrameError> {
+        decode_frame::<C, 1>(data)
+    }
+
+    fn decode_in_place<'a>(&self, data: &'a mut [u8]) -> Result<(FrameRef<'a>, usize), FrameError> {
+        decode_frame_in_place::<C, 1>(data)
+    }
+
+    fn skip_to_start(&self, data: &[u8]) -> usize {
+        delimited_skip_to_start(data)
+    }
+
+    fn skip_after_error(&self, _data: &[u8], error: &FrameError) -> usize {
+        delimited_skip_after_error(error)
+    }
+}
+
+/// The Delimited format with a two byte little endian size, so one frame
+/// can carry up to `EXTENDED_MAX_DATA_SIZE` bytes. Both ends have to agree
+/// on it, there's nothing on the wire telling the two formats apart.
+///
+/// The checksum defaults to CRC-32C since a single CRC-8 byte doesn't say
+/// much about 4 KiB of data.
+///
+/// Receive buffers need room for `EXTENDED_MAX_FRAME_SIZE`. Without `alloc`
+/// owned Frames still only hold `MAX_DATA_SIZE` bytes, so use `recv_ref`
+/// for anything bigger.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Extended<C: Checksum = Crc32C>(PhantomData<C>);
+
+impl<C: Checksum> Extended<C> {
+    pub const fn new() -> Extended<C> {
+        Extended(PhantomData)
+    }
+}
+
+impl<C: Checksum> FrameCodec for Extended<C> {
+    const MAX_FRAME_SIZE: usize = 2 + 2 * (2 + EXTENDED_MAX_DATA_SIZE + C::WIDTH);
+    const MAX_DATA_SIZE: usize = EXTENDED_MAX_DATA_SIZE;
+
+    fn encode_with(&self, data: &[u8], out: impl FnMut(u8)) -> Result<usize, FrameError> {
+        encode_frame_with::<C, 2>(data, out)
+    }
+
+    fn encoded_len(&self, data: &[u8]) -> Result<usize, FrameError> {
+        encoded_frame_len::<C, 2>(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<(Frame, usize), FrameError> {
+        decode_frame::<C, 2>(data)
+    }
+
+    fn decode_in_place<'a>(&self, data: &'a mut [u8]) -> Result<(FrameRef<'a>, usize), FrameError> {
+        decode_frame_in_place::<C, 2>(data)
+    }
+
+    fn skip_to_start(&self, data: &[u8]) -> usize {
+        delimited_skip_to_start(data)
+    }
+
+    fn skip_after_error(&self, _data: &[u8], error: &FrameError) -> usize {
+        delimited_skip_after_error(error)
+    }
+}
+
+fn delimited_skip_to_start(data: &[u8]) -> usize {
+    data.iter().position(|b| *b == DELIMITER).unwrap_or(data.len())
+}
+
+fn delimited_skip_after_error(error: &FrameError) -> usize {
+    match error {
+        FrameError::EarlyEndDelim { .. }
+        | FrameError::EarlyStartDelim { .. }
+        | FrameError::CrcMismatch { .. }
+        | FrameError::MissingEndDelim { .. }
+        | FrameError::PayloadTooLarge { .. } => {
+            // If we think we're on a frame, then the current next read will be the
+            // Frame delimiter. We should pop this so the next time we `recv` we'll
+            // toss the bytes until the next Delimiter
+            1
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::Wire,
+        packet::{FrameIOError, FrameSend, FrameTx},
+    };
+
+    #[test]
+    fn extended_carries_more_than_a_byte_of_size() {
+        let codec = Extended::<Crc32C>::new();
+        let data = [0x55; 300];
+        let mut buf = [0; EXTENDED_MAX_FRAME_SIZE];
+        let len = codec.encode(&data, &mut buf).unwrap();
+        assert!(len <= Extended::<Crc32C>::MAX_FRAME_SIZE);
+        let (frame, used) = codec.decode_in_place(&mut buf[..len]).unwrap();
+        assert_eq!(frame.size, 300);
+        assert_eq!(frame.data, data);
+        assert_eq!(used, len);
+    }
+
+    #[test]
+    fn oversized_payloads_are_rejected() {
+        let data = [0; EXTENDED_MAX_DATA_SIZE + 1];
+        assert!(matches!(
+            Delimited::<Crc8>::new().encoded_len(&data[..MAX_DATA_SIZE + 1]),
+            Err(FrameError::PayloadTooLarge { size: 256, max: MAX_DATA_SIZE })
+        ));
+        assert!(matches!(
+            Extended::<Crc32C>::new().encoded_len(&data),
+            Err(FrameError::PayloadTooLarge { max: EXTENDED_MAX_DATA_SIZE, .. })
+        ));
+        assert!(Extended::<Crc32C>::new().encoded_len(&data[..EXTENDED_MAX_DATA_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn mangled_size_is_caught() {
+        let codec = Extended::<Crc32C>::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let len = codec.encode(b"hi", &mut buf).unwrap();
+        // A size of 0xFF02 is way past the cap
+        buf[2] = 0xFF;
+        assert!(matches!(codec.decode(&buf[..len]), Err(FrameError::PayloadTooLarge { .. })));
+    }
+
+    #[test]
+    fn send_never_truncates_a_frame() {
+        let wire = Wire::new();
+        let mut tx = FrameTx::with_buffer(wire.clone(), Extended::<Crc32C>::new(), heapless::Deque::<u8, 64>::new());
+        assert!(matches!(
+            tx.send(&[0; 100]),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::EncodeBufferTooSmall { .. })))
+        ));
+        assert!(wire.is_empty());
+        tx.send(&[0; 10]).unwrap();
+        assert_eq!(wire.len(), Extended::<Crc32C>::new().encoded_len(&[0; 10]).unwrap());
+    }
+}
@@ -0,0 +1,347 @@
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::convert::Infallible;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use embedded_hal_nb::serial::Read;
+
+use crate::{
+    checksum::{Checksum, Crc8, MAX_CHECKSUM_WIDTH},
+    packet::{DELIMITER, END_DELIM, ESCAPE, ESCAPE_XOR, FrameError, FrameIOError, FrameRef, MAX_DATA_SIZE},
+};
+
+/// Where the decoder is in the current frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting on a start delimiter
+    Idle,
+    Size,
+    Data,
+    Crc,
+    /// Everything's in, only the end delimiter to go
+    End,
+}
+
+/// Decodes Delimited frames (the default format) one byte at a time, e.g.
+/// straight from a UART RX interrupt.
+///
+/// Each byte is a constant amount of work: the checksum is built up as the
+/// bytes arrive and only the unstuffed data is kept, in a fixed `N` byte
+/// buffer. No contiguous receive buffer is needed. Frames with more than `N`
+/// bytes of data are dropped with `FrameError::PayloadTooLarge`.
+///
+/// After an error the decoder goes back to waiting on a start delimiter, or
+/// starts on the new frame straight away if the error was a start delimiter.
+pub struct FrameDecoder<C: Checksum = Crc8, const N: usize = MAX_DATA_SIZE> {
+    state: State,
+    /// Last byte was an ESCAPE
+    escaped: bool,
+    /// Bytes seen since the start delimiter, for error reporting
+    index: usize,
+    size: usize,
+    data: [u8; N],
+    len: usize,
+    crc: [u8; MAX_CHECKSUM_WIDTH],
+    crc_len: usize,
+    digest: C::Digest,
+}
+
+impl<C: Checksum, const N: usize> Default for FrameDecoder<C, N> {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl<C: Checksum, const N: usize> FrameDecoder<C, N> {
+    pub fn new() -> FrameDecoder<C, N> {
+        FrameDecoder {
+            state: State::Idle,
+            escaped: false,
+            index: 0,
+            size: 0,
+            data: [0; N],
+            len: 0,
+            crc: [0; MAX_CHECKSUM_WIDTH],
+            crc_len: 0,
+            digest: C::digest(),
+        }
+    }
+
+    /// Drop any partial frame and wait for the next start delimiter
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+    }
+
+    /// Feed in the next byte off the wire. Returns the frame once its end
+    /// delimiter arrives, or an error if the byte means the current frame is bad.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<FrameRef<'_>, FrameError>> {
+        match self.step(byte)? {
+            Ok(()) => Some(Ok(self.frame())),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Read bytes out of `rx` until we've got a frame, a bad frame, or
+    /// `rx` would block.
+    pub fn read<Rx: Read>(&mut self, rx: &mut Rx) -> nb::Result<FrameRef<'_>, FrameIOError<Infallible, Rx::Error>> {
+        loop {
+            let b = rx.read().map_err(|e| e.map(FrameIOError::Read))?;
+            match self.step(b) {
+                None => continue,
+                Some(Ok(())) => return Ok(self.frame()),
+                Some(Err(e)) => return Err(nb::Error::Other(FrameIOError::Frame(e))),
+            }
+        }
+    }
+
+    /// The frame that's just been completed
+    fn frame(&self) -> FrameRef<'_> {
+        FrameRef {
+            size: self.size as u16,
+            header: None,
+            data: &self.data[..self.len],
+            crc: C::read(&self.crc),
+        }
+    }
+
+    /// Start on a new frame, the start delimiter has just gone by
+    fn start(&mut self) {
+        self.state = State::Size;
+        self.escaped = false;
+        self.index = 0;
+        self.size = 0;
+        self.len = 0;
+        self.crc = [0; MAX_CHECKSUM_WIDTH];
+        self.crc_len = 0;
+        self.digest = C::digest();
+    }
+
+    /// Size is done or the last data byte is in, so on to the crc (if there is one)
+    fn after_data(&mut self) {
+        self.state = if C::WIDTH == 0 { State::End } else { State::Crc };
+    }
+
+    /// Unstuffed bytes still needed before the end delimiter
+    fn remaining(&self) -> usize {
+        let size = if self.state == State::Size { 1 } else { self.size - self.len };
+        size + C::WIDTH - self.crc_len
+    }
+
+    fn step(&mut self, byte: u8) -> Option<Result<(), FrameError>> {
+        if self.state == State::Idle {
+            if byte == DELIMITER {
+                self.start();
+            }
+            return None;
+        }
+        self.index += 1;
+
+        if self.state == State::End {
+            self.state = State::Idle;
+            if byte != END_DELIM {
+                let err = FrameError::MissingEndDelim { index: self.index, found: byte };
+                if byte == DELIMITER {
+                    self.start();
+                }
+                return Some(Err(err));
+            }
+            let calc_crc = C::finalize(core::mem::replace(&mut self.digest, C::digest()));
+            let crc = C::read(&self.crc);
+            if crc != calc_crc {
+                return Some(Err(FrameError::CrcMismatch {
+                    calculated: calc_crc,
+                    found: crc,
+                    width: C::WIDTH,
+                    #[cfg(feature = "alloc")]
+                    buf: Vec::from(&self.data[..self.len]),
+                }));
+            }
+            return Some(Ok(()));
+        }
+
+        // Running into either delimiter means the frame got cut short
+        match byte {
+            DELIMITER => {
+                let err = FrameError::EarlyStartDelim { found_at: self.index };
+                self.start();
+                return Some(Err(err));
+            },
+            END_DELIM => {
+                let err = FrameError::EarlyEndDelim {
+                    found_at: self.index,
+                    expected: self.index + self.remaining(),
+                };
+                self.state = State::Idle;
+                return Some(Err(err));
+            },
+            ESCAPE if !self.escaped => {
+                self.escaped = true;
+                return None;
+            },
+            _ => (),
+        }
+        let b = if self.escaped { byte ^ ESCAPE_XOR } else { byte };
+        self.escaped = false;
+
+        match self.state {
+            State::Size => {
+                self.size = b as usize;
+                if self.size > N {
+                    self.state = State::Idle;
+                    return Some(Err(FrameError::PayloadTooLarge { size: self.size, max: N }));
+                }
+                C::update(&mut self.digest, &[b]);
+                if self.size == 0 {
+                    self.after_data();
+                } else {
+                    self.state = State::Data;
+                }
+            },
+            State::Data => {
+                self.data[self.len] = b;
+                self.len += 1;
+                C::update(&mut self.digest, &[b]);
+                if self.len == self.size {
+                    self.after_data();
+                }
+            },
+            State::Crc => {
+                self.crc[self.crc_len] = b;
+                self.crc_len += 1;
+                if self.crc_len == C::WIDTH {
+                    self.state = State::End;
+                }
+            },
+            State::Idle | State::End => (),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::{Crc16Ccitt, NoCrc},
+        codec::{Delimited, FrameCodec},
+        mock::Wire,
+        packet::MAX_FRAME_SIZE,
+    };
+
+    fn encoded<C: Checksum>(data: &[u8]) -> ([u8; MAX_FRAME_SIZE], usize) {
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let len = Delimited::<C>::new().encode(data, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    /// Feed all of `bytes` in, checking nothing comes out before the last one
+    fn feed_all<'a, C: Checksum, const N: usize>(
+        decoder: &'a mut FrameDecoder<C, N>,
+        bytes: &[u8],
+    ) -> Result<FrameRef<'a>, FrameError> {
+        let (last, rest) = bytes.split_last().unwrap();
+        for b in rest {
+            assert!(decoder.feed(*b).is_none());
+        }
+        decoder.feed(*last).unwrap()
+    }
+
+    fn round_trip<C: Checksum>() {
+        let data = [DELIMITER, 0x01, END_DELIM, ESCAPE, 0x02];
+        let (buf, len) = encoded::<C>(&data);
+        let mut decoder = FrameDecoder::<C>::new();
+        let frame = feed_all(&mut decoder, &buf[..len]).unwrap();
+        assert_eq!(frame.data, data);
+        assert_eq!(frame.crc, C::checksum(&[data.len() as u8], &data));
+    }
+
+    #[test]
+    fn a_frame_comes_out_on_its_end_delimiter() {
+        round_trip::<NoCrc>();
+        round_trip::<Crc8>();
+        round_trip::<Crc16Ccitt>();
+    }
+
+    #[test]
+    fn junk_and_empty_frames() {
+        let (buf, len) = encoded::<Crc8>(&[]);
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        for b in [0x00, END_DELIM, ESCAPE, 0x42] {
+            assert!(decoder.feed(b).is_none());
+        }
+        assert!(feed_all(&mut decoder, &buf[..len]).unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn bad_crc_then_carries_on() {
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        let (mut buf, len) = encoded::<Crc8>(b"abc");
+        buf[3] ^= 0x01;
+        assert!(matches!(feed_all(&mut decoder, &buf[..len]), Err(FrameError::CrcMismatch { width: 1, .. })));
+        let (buf, len) = encoded::<Crc8>(b"def");
+        assert_eq!(feed_all(&mut decoder, &buf[..len]).unwrap().data, b"def");
+    }
+
+    #[test]
+    fn start_delimiter_starts_over() {
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        let (buf, len) = encoded::<Crc8>(b"abc");
+        for b in &buf[..len - 2] {
+            assert!(decoder.feed(*b).is_none());
+        }
+        // The start of the next frame cuts the last one short and begins a new one
+        assert!(matches!(decoder.feed(DELIMITER), Some(Err(FrameError::EarlyStartDelim { .. }))));
+        assert_eq!(feed_all(&mut decoder, &buf[1..len]).unwrap().data, b"abc");
+    }
+
+    #[test]
+    fn end_delimiter_too_soon() {
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        let (buf, _) = encoded::<Crc8>(b"abc");
+        for b in &buf[..3] {
+            assert!(decoder.feed(*b).is_none());
+        }
+        // Two data bytes and the crc still to come
+        assert!(matches!(
+            decoder.feed(END_DELIM),
+            Some(Err(FrameError::EarlyEndDelim { found_at: 3, expected: 6 }))
+        ));
+        // Back to waiting on a start
+        assert!(decoder.feed(0x01).is_none());
+    }
+
+    #[test]
+    fn missing_end_delimiter() {
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        let (mut buf, len) = encoded::<Crc8>(b"abc");
+        buf[len - 1] = 0x01;
+        assert!(matches!(
+            feed_all(&mut decoder, &buf[..len]),
+            Err(FrameError::MissingEndDelim { found: 0x01, .. })
+        ));
+    }
+
+    #[test]
+    fn too_big_for_the_buffer() {
+        let mut decoder = FrameDecoder::<Crc8, 4>::new();
+        let (buf, _) = encoded::<Crc8>(b"abcde");
+        assert!(decoder.feed(buf[0]).is_none());
+        assert!(matches!(decoder.feed(buf[1]), Some(Err(FrameError::PayloadTooLarge { size: 5, max: 4 }))));
+        let (buf, len) = encoded::<Crc8>(b"abcd");
+        assert_eq!(feed_all(&mut decoder, &buf[..len]).unwrap().data, b"abcd");
+    }
+
+    #[test]
+    fn reads_until_a_frame() {
+        let wire = Wire::new();
+        let (buf, len) = encoded::<Crc8>(b"abc");
+        wire.push(&buf[..len - 1]);
+        let mut rx = wire.clone();
+        let mut decoder = FrameDecoder::<Crc8>::new();
+        assert!(matches!(decoder.read(&mut rx), Err(nb::Error::WouldBlock)));
+        wire.push(&buf[len - 1..len]);
+        assert_eq!(decoder.read(&mut rx).unwrap().data, b"abc");
+    }
+}
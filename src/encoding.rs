@@ -0,0 +1,587 @@
+use crate::{Decode, Encode, packet::FrameError};
+
+/// Length prefix in front of a byte slice field, see `encode_bytes`
+pub const BYTES_LEN_PREFIX: usize = 2;
+
+/// Encode `value` into `buffer` at `at`, returning how many bytes it took.
+///
+/// Errors are widened to FrameError, with buffer sizes counted from the
+/// start of `buffer` rather than from `at`. Nothing after this field is
+/// counted, so a too small buffer only says how much was needed to get
+/// this far. This is what the `Encode` derive builds each field out of.
+pub fn encode_field<T>(value: &T, buffer: &mut [u8], at: usize) -> Result<usize, FrameError>
+where
+    T: Encode + ?Sized,
+    T::Error: Into<FrameError>,
+{
+    let found = buffer.len();
+    let rest = buffer.get_mut(at..).ok_or(FrameError::EncodeBufferTooSmall { expected_at_least: at, found })?;
+    value.encode(rest).map_err(|e| match e.into() {
+        FrameError::EncodeBufferTooSmall { expected_at_least, found } => FrameError::EncodeBufferTooSmall {
+            expected_at_least: at + expected_at_least,
+            found: at + found,
+        },
+        e => e,
+    })
+}
+
+/// Decode a `T` out of `data` at `*at`, moving `*at` past it.
+///
+/// Errors are widened to FrameError, with sizes counted from the start of
+/// `data`. This is what the `Decode` derive builds each field out of.
+pub fn decode_field<'a, T>(data: &'a [u8], at: &mut usize) -> Result<T, FrameError>
+where
+    T: Decode<'a>,
+    T::Error: Into<FrameError>,
+{
+    let found = data.len();
+    let rest = data.get(*at..).ok_or(FrameError::DecodeBufferTooSmall { expected_at_least: *at, found })?;
+    let offset = *at;
+    let (value, len) = T::decode_from(rest).map_err(|e| match e.into() {
+        FrameError::DecodeBufferTooSmall { expected_at_least, found } => FrameError::DecodeBufferTooSmall {
+            expected_at_least: offset + expected_at_least,
+            found: offset + found,
+        },
+        FrameError::InvalidEncoding { index } => FrameError::InvalidEncoding { index: offset + index },
+        e => e,
+    })?;
+    *at += len;
+    Ok(value)
+}
+
+/// Encode `bytes` at `at` with a little endian u16 length in front of them.
+///
+/// `&[u8]` already encodes as a whole frame, so byte slice fields go
+/// through here instead, see `Bytes`.
+pub fn encode_bytes(bytes: &[u8], buffer: &mut [u8], at: usize) -> Result<usize, FrameError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| FrameError::PayloadTooLarge {
+        size: bytes.len(),
+        max: u16::MAX as usize,
+    })?;
+    let end = at + BYTES_LEN_PREFIX + bytes.len();
+    if buffer.len() < end {
+        return Err(FrameError::EncodeBufferTooSmall { expected_at_least: end, found: buffer.len() });
+    }
+    buffer[at..at + BYTES_LEN_PREFIX].copy_from_slice(&len.to_le_bytes());
+    buffer[at + BYTES_LEN_PREFIX..end].copy_from_slice(bytes);
+    Ok(BYTES_LEN_PREFIX + bytes.len())
+}
+
+/// Borrow a byte slice written by `encode_bytes` out of `data` at `*at`,
+/// moving `*at` past it
+pub fn decode_bytes<'a>(data: &'a [u8], at: &mut usize) -> Result<&'a [u8], FrameError> {
+    let mut prefix = [0; BYTES_LEN_PREFIX];
+    prefix.copy_from_slice(take(data, *at, BYTES_LEN_PREFIX)?);
+    let len = u16::from_le_bytes(prefix) as usize;
+    let bytes = take(data, *at + BYTES_LEN_PREFIX, len)?;
+    *at += BYTES_LEN_PREFIX + len;
+    Ok(bytes)
+}
+
+/// A byte slice that goes on the wire with a little endian u16 length in
+/// front of it, and is borrowed straight out of the data when decoding.
+///
+/// The derive does this for `&[u8]` fields by itself. Anywhere else a byte
+/// slice has to be wrapped, e.g. `Option<Bytes<'a>>` or `[Bytes<'a>; 2]`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl Encode for Bytes<'_> {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        encode_bytes(self.0, buffer, 0)
+    }
+}
+
+impl<'a> Decode<'a> for Bytes<'a> {
+    type Error = FrameError;
+
+    fn decode(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+        let mut n = 0;
+        let bytes = decode_bytes(data, &mut n)?;
+        Ok((Bytes(bytes), n))
+    }
+}
+
+/// `len` bytes of `data` from `at`
+fn take(data: &[u8], at: usize, len: usize) -> Result<&[u8], FrameError> {
+    data.get(at..at + len).ok_or(FrameError::DecodeBufferTooSmall {
+        expected_at_least: at + len,
+        found: data.len(),
+    })
+}
+
+/// Decodes one value after another out of the same data, keeping track
+/// of where it's got to. Errors give offsets from the start of the data.
+#[derive(Debug, Clone)]
+pub struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    /// How far into the data we are
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// What's left to decode
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    /// Decode the next `T`
+    pub fn read<T>(&mut self) -> Result<T, FrameError>
+    where
+        T: Decode<'a>,
+        T::Error: Into<FrameError>,
+    {
+        decode_field(self.data, &mut self.pos)
+    }
+
+    /// The next `len` bytes as they are
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], FrameError> {
+        let bytes = take(self.data, self.pos, len)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// The next length prefixed byte slice, see `encode_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], FrameError> {
+        decode_bytes(self.data, &mut self.pos)
+    }
+}
+
+/// Fixed width numbers, which go on the wire little endian unless wrapped
+/// in `Be`
+pub trait Number: Copy {
+    const LEN: usize;
+
+    /// `out` is exactly `LEN` bytes
+    fn put_le(self, out: &mut [u8]);
+    fn put_be(self, out: &mut [u8]);
+    /// `bytes` is exactly `LEN` bytes
+    fn get_le(bytes: &[u8]) -> Self;
+    fn get_be(bytes: &[u8]) -> Self;
+}
+
+/// A number that goes on the wire big endian
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Be<T>(pub T);
+
+/// A number that goes on the wire little endian. Same as the bare number,
+/// for spelling it out next to `Be` fields.
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Le<T>(pub T);
+
+/// Write `len` bytes to the front of `buffer` with `put`
+fn put(buffer: &mut [u8], len: usize, put: impl FnOnce(&mut [u8])) -> Result<usize, FrameError> {
+    let found = buffer.len();
+    let out = buffer.get_mut(..len).ok_or(FrameError::EncodeBufferTooSmall { expected_at_least: len, found })?;
+    put(out);
+    Ok(len)
+}
+
+impl<T: Number> Encode for Le<T> {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        put(buffer, T::LEN, |out| self.0.put_le(out))
+    }
+}
+
+impl<T: Number> Decode<'_> for Le<T> {
+    type Error = FrameError;
+
+    fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+        Ok((Le(T::get_le(take(data, 0, T::LEN)?)), T::LEN))
+    }
+}
+
+impl<T: Number> Encode for Be<T> {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        put(buffer, T::LEN, |out| self.0.put_be(out))
+    }
+}
+
+impl<T: Number> Decode<'_> for Be<T> {
+    type Error = FrameError;
+
+    fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+        Ok((Be(T::get_be(take(data, 0, T::LEN)?)), T::LEN))
+    }
+}
+
+macro_rules! number_impls {
+    ($($t:ty),*) => {$(
+        impl Number for $t {
+            const LEN: usize = size_of::<$t>();
+
+            fn put_le(self, out: &mut [u8]) {
+                out.copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn put_be(self, out: &mut [u8]) {
+                out.copy_from_slice(&self.to_be_bytes());
+            }
+
+            fn get_le(bytes: &[u8]) -> Self {
+                let mut b = [0; size_of::<$t>()];
+                b.copy_from_slice(bytes);
+                <$t>::from_le_bytes(b)
+            }
+
+            fn get_be(bytes: &[u8]) -> Self {
+                let mut b = [0; size_of::<$t>()];
+                b.copy_from_slice(bytes);
+                <$t>::from_be_bytes(b)
+            }
+        }
+
+        impl Encode for $t {
+            type Error = FrameError;
+
+            fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+                Le(*self).encode(buffer)
+            }
+        }
+
+        impl Decode<'_> for $t {
+            type Error = FrameError;
+
+            fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+                Self::decode_from(data).map(|(v, _)| v)
+            }
+
+            fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+                Le::decode_from(data).map(|(Le(v), n)| (v, n))
+            }
+        }
+    )*};
+}
+
+number_impls!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// One byte, 0 or 1
+impl Encode for bool {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        (*self as u8).encode(buffer)
+    }
+}
+
+impl Decode<'_> for bool {
+    type Error = FrameError;
+
+    fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+        match u8::decode_from(data)? {
+            (0, n) => Ok((false, n)),
+            (1, n) => Ok((true, n)),
+            _ => Err(FrameError::InvalidEncoding { index: 0 }),
+        }
+    }
+}
+
+impl<T, const N: usize> Encode for [T; N]
+where
+    T: Encode,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut n = 0;
+        for v in self {
+            n += encode_field(v, buffer, n)?;
+        }
+        Ok(n)
+    }
+}
+
+impl<'a, T, const N: usize> Decode<'a> for [T; N]
+where
+    T: Decode<'a>,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn decode(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+        let mut cursor = Cursor::new(data);
+        let mut values = heapless::Vec::<T, N>::new();
+        while !values.is_full() {
+            // There's room, it was just checked
+            let _ = values.push(cursor.read()?);
+        }
+        // Full, so always an array of N
+        let values = values.into_array().map_err(|_| FrameError::DecodeBufferTooSmall {
+            expected_at_least: cursor.position(),
+            found: data.len(),
+        })?;
+        Ok((values, cursor.position()))
+    }
+}
+
+/// A 0 byte for None, or a 1 followed by the value
+impl<T> Encode for Option<T>
+where
+    T: Encode,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            None => false.encode(buffer),
+            Some(v) => {
+                let n = true.encode(buffer)?;
+                Ok(n + encode_field(v, buffer, n)?)
+            },
+        }
+    }
+}
+
+impl<'a, T> Decode<'a> for Option<T>
+where
+    T: Decode<'a>,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn decode(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+        let mut cursor = Cursor::new(data);
+        let value = match cursor.read()? {
+            false => None,
+            true => Some(cursor.read()?),
+        };
+        Ok((value, cursor.position()))
+    }
+}
+
+/// Each element in turn
+macro_rules! tuple_impls {
+    ($(($($t:ident $i:tt),+))*) => {$(
+        impl<$($t),+> Encode for ($($t,)+)
+        where
+            $($t: Encode, $t::Error: Into<FrameError>,)+
+        {
+            type Error = FrameError;
+
+            fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+                let mut n = 0;
+                $(n += encode_field(&self.$i, buffer, n)?;)+
+                Ok(n)
+            }
+        }
+
+        impl<'a, $($t),+> Decode<'a> for ($($t,)+)
+        where
+            $($t: Decode<'a>, $t::Error: Into<FrameError>,)+
+        {
+            type Error = FrameError;
+
+            fn decode(data: &'a [u8]) -> Result<Self, Self::Error> {
+                Self::decode_from(data).map(|(v, _)| v)
+            }
+
+            fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+                let mut cursor = Cursor::new(data);
+                let value = ($(cursor.read::<$t>()?,)+);
+                Ok((value, cursor.position()))
+            }
+        }
+    )*};
+}
+
+tuple_impls! {
+    (A 0)
+    (A 0, B 1)
+    (A 0, B 1, C 2)
+    (A 0, B 1, C 2, D 3)
+    (A 0, B 1, C 2, D 3, E 4)
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+}
+
+/// Length prefix for a collection, same as `encode_bytes` uses
+fn encode_len(len: usize, buffer: &mut [u8]) -> Result<usize, FrameError> {
+    let len = u16::try_from(len).map_err(|_| FrameError::PayloadTooLarge {
+        size: len,
+        max: u16::MAX as usize,
+    })?;
+    len.encode(buffer)
+}
+
+/// A little endian u16 length followed by the elements
+impl<T, const N: usize> Encode for heapless::Vec<T, N>
+where
+    T: Encode,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut n = encode_len(self.len(), buffer)?;
+        for v in self {
+            n += encode_field(v, buffer, n)?;
+        }
+        Ok(n)
+    }
+}
+
+/// More elements than there's room for is `PayloadTooLarge`
+impl<'a, T, const N: usize> Decode<'a> for heapless::Vec<T, N>
+where
+    T: Decode<'a>,
+    T::Error: Into<FrameError>,
+{
+    type Error = FrameError;
+
+    fn decode(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+        let mut cursor = Cursor::new(data);
+        let len = cursor.read::<u16>()? as usize;
+        if len > N {
+            return Err(FrameError::PayloadTooLarge { size: len, max: N });
+        }
+        let mut values = heapless::Vec::new();
+        for _ in 0..len {
+            // Can't fail, there's room for len
+            let _ = values.push(cursor.read()?);
+        }
+        Ok((values, cursor.position()))
+    }
+}
+
+/// A little endian u16 length followed by the UTF-8
+impl<const N: usize> Encode for heapless::String<N> {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        encode_bytes(self.as_bytes(), buffer, 0)
+    }
+}
+
+/// Anything that isn't UTF-8 is `InvalidEncoding`, too long is
+/// `PayloadTooLarge`
+impl<const N: usize> Decode<'_> for heapless::String<N> {
+    type Error = FrameError;
+
+    fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::decode_from(data).map(|(v, _)| v)
+    }
+
+    fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+        let mut cursor = Cursor::new(data);
+        let bytes = cursor.read_bytes()?;
+        let s = core::str::from_utf8(bytes).map_err(|e| FrameError::InvalidEncoding {
+            index: BYTES_LEN_PREFIX + e.valid_up_to(),
+        })?;
+        let mut value = heapless::String::new();
+        value
+            .push_str(s)
+            .map_err(|_| FrameError::PayloadTooLarge { size: bytes.len(), max: N })?;
+        Ok((value, cursor.position()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_and_their_byte_order() {
+        let mut buf = [0; 8];
+        let n = (0x0102u16, Be(0x0102u16), -1i8).encode(&mut buf).unwrap();
+        assert_eq!(buf[..n], [0x02, 0x01, 0x01, 0x02, 0xFF]);
+        assert_eq!(<(u16, Be<u16>, i8)>::decode(&buf[..n]).unwrap(), (0x0102, Be(0x0102), -1));
+    }
+
+    #[test]
+    fn too_small_counts_up_to_the_field_that_did_not_fit() {
+        // The second u32 doesn't fit, nothing after it is counted
+        assert!(matches!(
+            (1u8, 2u32, 3u64).encode(&mut [0; 3]),
+            Err(FrameError::EncodeBufferTooSmall { expected_at_least: 5, found: 3 })
+        ));
+        assert!(matches!(
+            [[1u16; 2]; 2].encode(&mut [0; 6]),
+            Err(FrameError::EncodeBufferTooSmall { expected_at_least: 8, found: 6 })
+        ));
+    }
+
+    #[test]
+    fn arrays_decode_every_element() {
+        let data = [1, 0, 2, 0, 3, 0, 4, 0];
+        assert_eq!(<[[u16; 2]; 2]>::decode(&data).unwrap(), [[1, 2], [3, 4]]);
+        assert_eq!(<[u8; 0]>::decode_from(&data).unwrap(), ([], 0));
+        // Running out part way is an error, not a panic
+        assert!(matches!(
+            <[u16; 3]>::decode(&data[..5]),
+            Err(FrameError::DecodeBufferTooSmall { expected_at_least: 6, found: 5 })
+        ));
+        assert!(matches!(<[bool; 2]>::decode(&[1, 2]), Err(FrameError::InvalidEncoding { index: 1 })));
+    }
+
+    #[test]
+    fn byte_fields_are_length_prefixed() {
+        let mut buf = [0; 8];
+        let n = encode_bytes(b"abc", &mut buf, 1).unwrap();
+        assert_eq!(buf[..1 + n], [0, 3, 0, b'a', b'b', b'c']);
+        let mut at = 1;
+        assert_eq!(decode_bytes(&buf, &mut at).unwrap(), b"abc");
+        assert_eq!(at, 6);
+        assert!(matches!(
+            decode_bytes(&buf[..5], &mut 1),
+            Err(FrameError::DecodeBufferTooSmall { expected_at_least: 6, found: 5 })
+        ));
+    }
+
+    #[test]
+    fn wrapped_byte_slices_nest() {
+        let value = (Some(Bytes(b"ab")), [Bytes(b"c"), Bytes(b"")]);
+        let mut buf = [0; 16];
+        let n = value.encode(&mut buf).unwrap();
+        assert_eq!(buf[..n], [1, 2, 0, b'a', b'b', 1, 0, b'c', 0, 0]);
+        assert_eq!(<(Option<Bytes>, [Bytes; 2])>::decode(&buf[..n]).unwrap(), value);
+    }
+}
@@ -0,0 +1,205 @@
+use bilge::prelude::*;
+/// The odd sized ints the header's fields are made of
+pub use bilge::arbitrary_int::{u2, u4, u6};
+
+use crate::{
+    Decode, Encode,
+    packet::{Frame, FrameData, FrameError, FrameRef, frame_data, frame_data_with},
+};
+
+/// Version of the header layout below. 2 took a bit off the version for
+/// `addressed`.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Bytes the header takes up at the front of a frame's data
+pub const FRAME_HEADER_LEN: usize = 2;
+
+/// Optional typed header at the front of a frame's data, so the first
+/// payload bytes don't have to be packed by hand.
+///
+/// To the codecs it's just data. On links set up for it (see
+/// `FrameTxRx::with_header`) every frame carries one, `send_with_header`
+/// writes it and `recv` hands it back in `Frame::header` with the data
+/// after it. Nothing on the wire says whether a frame has one, both ends
+/// have to agree on it the same as on the codec.
+///
+/// On the wire it's two little endian bytes, `msg_type` in the low bits.
+/// An `addressed` frame has its destination and source addresses right
+/// after the header, see `bus::Addressed`.
+///
+/// Decoding doesn't check the version, compare `version()` with
+/// `PROTOCOL_VERSION` if it matters.
+#[bitsize(16)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Application defined
+    pub msg_type: u4,
+    pub ack_requested: bool,
+    /// Part of a larger transaction
+    pub fragment: bool,
+    pub priority: bool,
+    pub channel: u6,
+    /// Destination and source addresses follow the header
+    pub addressed: bool,
+    pub version: u2,
+}
+
+impl FrameHeader {
+    /// Header for `msg_type` on `channel` with no flags set, at the current version
+    pub fn for_channel(channel: u6, msg_type: u4) -> FrameHeader {
+        FrameHeader::new(msg_type, false, false, false, channel, false, u2::new(PROTOCOL_VERSION))
+    }
+}
+
+impl Encode for FrameHeader {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        if buffer.len() < FRAME_HEADER_LEN {
+            return Err(FrameError::EncodeBufferTooSmall {
+                expected_at_least: FRAME_HEADER_LEN,
+                found: buffer.len(),
+            });
+        }
+        buffer[..FRAME_HEADER_LEN].copy_from_slice(&self.value.to_le_bytes());
+        Ok(FRAME_HEADER_LEN)
+    }
+}
+
+impl Decode<'_> for FrameHeader {
+    type Error = FrameError;
+
+    fn decode(data: &[u8]) -> Result<Self, Self::Error> {
+        match data {
+            [lo, hi, ..] => Ok(FrameHeader::from(u16::from_le_bytes([*lo, *hi]))),
+            _ => Err(FrameError::DecodeBufferTooSmall {
+                expected_at_least: FRAME_HEADER_LEN,
+                found: data.len(),
+            }),
+        }
+    }
+
+    fn decode_from(data: &[u8]) -> Result<(Self, usize), Self::Error> {
+        Ok((FrameHeader::decode(data)?, FRAME_HEADER_LEN))
+    }
+}
+
+/// `header` then `data` as one FrameData, ready to go out as a frame
+pub(crate) fn with_header(header: FrameHeader, data: &[u8]) -> Result<FrameData, FrameError> {
+    frame_data_with(&header.value.to_le_bytes(), data)
+}
+
+impl Frame {
+    /// Move the `FrameHeader` at the front of the data into `header`, for
+    /// frames off a link where every frame has one
+    pub fn split_header(self) -> Result<Frame, FrameError> {
+        let header = FrameHeader::decode(&self.data)?;
+        Ok(Frame {
+            header: Some(header),
+            data: frame_data(&self.data[FRAME_HEADER_LEN..])?,
+            ..self
+        })
+    }
+}
+
+impl FrameRef<'_> {
+    /// See `Frame::split_header`
+    pub fn split_header(self) -> Result<Self, FrameError> {
+        let header = FrameHeader::decode(self.data)?;
+        Ok(FrameRef {
+            header: Some(header),
+            data: &self.data[FRAME_HEADER_LEN..],
+            ..self
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::link,
+        packet::{FrameRecv, FrameSend},
+    };
+
+    fn header() -> FrameHeader {
+        FrameHeader::new(u4::new(0x3), true, false, true, u6::new(0x15), false, u2::new(PROTOCOL_VERSION))
+    }
+
+    #[test]
+    fn fields_pack_low_bits_first() {
+        let mut buf = [0; FRAME_HEADER_LEN];
+        assert_eq!(header().encode(&mut buf).unwrap(), FRAME_HEADER_LEN);
+        // msg_type 3, ack 1 << 4, priority 1 << 6, channel 0x15 << 7, version 2 << 14
+        assert_eq!(u16::from_le_bytes(buf), 0x3 | 1 << 4 | 1 << 6 | 0x15 << 7 | 2 << 14);
+        assert_eq!(FrameHeader::decode(&buf).unwrap(), header());
+    }
+
+    #[test]
+    fn for_channel_sets_no_flags() {
+        let h = FrameHeader::for_channel(u6::new(5), u4::new(2));
+        assert_eq!((h.channel(), h.msg_type(), h.version()), (u6::new(5), u4::new(2), u2::new(PROTOCOL_VERSION)));
+        assert!(!h.ack_requested() && !h.fragment() && !h.priority() && !h.addressed());
+    }
+
+    #[test]
+    fn short_buffers_are_errors() {
+        assert!(matches!(header().encode(&mut [0; 1]), Err(FrameError::EncodeBufferTooSmall { expected_at_least: 2, found: 1 })));
+        assert!(matches!(FrameHeader::decode(&[0]), Err(FrameError::DecodeBufferTooSmall { expected_at_least: 2, found: 1 })));
+    }
+
+    #[test]
+    fn frame_encode_writes_the_header() {
+        let frame = Frame { size: 0, header: Some(header()), data: frame_data(b"hi").unwrap(), crc: 0 };
+        let mut buf = [0; 16];
+        let len = frame.encode(&mut buf).unwrap();
+        assert_eq!(len, frame.encoded_len(&crate::Delimited::<crate::Crc8>::new()).unwrap());
+
+        // Decoding can't tell there's a header until it's asked to split it off
+        let plain = Frame::decode(&buf[..len]).unwrap();
+        assert_eq!(plain.header, None);
+        assert_eq!(plain.size, 4);
+        let split = plain.split_header().unwrap();
+        assert_eq!(split.header, Some(header()));
+        assert_eq!(split.data[..], *b"hi");
+    }
+
+    #[test]
+    fn header_links_carry_one_on_every_frame() {
+        let (a, b) = link();
+        let default = FrameHeader::for_channel(u6::new(1), u4::new(0));
+        let (mut a, mut b) = (a.with_header(default), b.with_header(default));
+
+        a.send(b"plain").unwrap();
+        a.send_with_header(header(), b"typed").unwrap();
+        let f = b.recv().unwrap();
+        assert_eq!((f.header, &f.data[..]), (Some(default), &b"plain"[..]));
+        let f = b.recv().unwrap();
+        assert_eq!((f.header, &f.data[..]), (Some(header()), &b"typed"[..]));
+
+        a.send_with_header(header(), b"borrowed").unwrap();
+        let f = b.recv_ref().unwrap();
+        assert_eq!(f.frame().header, Some(header()));
+        assert_eq!(f.data(), b"borrowed");
+    }
+
+    #[test]
+    fn plain_links_leave_it_in_the_data() {
+        let (mut a, mut b) = link();
+        a.send_with_header(header(), b"typed").unwrap();
+        let f = b.recv().unwrap();
+        assert_eq!(f.header, None);
+        assert_eq!(f.data[FRAME_HEADER_LEN..], *b"typed");
+        assert_eq!(f.split_header().unwrap().header, Some(header()));
+    }
+
+    #[test]
+    fn frames_too_short_for_a_header_are_bad() {
+        let (mut a, b) = link();
+        let mut b = b.with_header(header());
+        a.send(&[1]).unwrap();
+        a.send_with_header(header(), b"ok").unwrap();
+        assert!(matches!(b.recv(), Err(nb::Error::Other(crate::FrameIOError::Frame(FrameError::DecodeBufferTooSmall { .. })))));
+        assert_eq!(b.recv().unwrap().data[..], *b"ok");
+        assert_eq!(b.stats().other_errors, 1);
+    }
+}
@@ -1,8 +1,31 @@
 #![no_std]
 
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod bus;
+pub mod capture;
+pub mod checksum;
+pub mod clock;
+pub mod cobs;
+pub mod codec;
+pub mod decoder;
+pub mod encoding;
+pub mod header;
+#[cfg(feature = "serde")]
+pub mod message;
+#[cfg(test)]
+mod mock;
+pub mod mux;
 pub mod packet;
+pub mod reliable;
+pub mod rpc;
 pub mod serial;
+pub mod slip;
+pub mod stats;
+pub mod stream;
+pub mod transaction;
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 pub use embedded_io as io;
@@ -20,10 +43,101 @@ where
     type Error;
 
     fn decode(data: &'a [u8]) -> Result<Self, Self::Error>;
+
+    /// Decode from the front of `data`, also handing back how many bytes
+    /// were used. Types that don't know where they end take all of it.
+    fn decode_from(data: &'a [u8]) -> Result<(Self, usize), Self::Error> {
+        Self::decode(data).map(|v| (v, data.len()))
+    }
 }
 
 pub use packet::{
-    DELIMITER, Frame, FrameDataSlice, FrameError, FrameIOError, FrameTxRx, MAX_DATA_SIZE,
-    MAX_FRAME_SIZE,
+    BorrowedFrame, DELIMITER, Frame, FrameData, FrameDataSlice, FrameError, FrameIOError, FrameRef,
+    FrameTxRx, MAX_DATA_SIZE, MAX_FRAME_SIZE,
 };
-pub use serial::{BufferedRx, BufferedTx, ErrorShim};
+#[cfg(feature = "async")]
+pub use asynch::{AsyncFrameRx, AsyncFrameTx};
+pub use bus::{Addressed, BusMaster, SendTo};
+pub use capture::{CaptureRx, CaptureSink, CaptureTx, Direction, PcapWriter, Replay};
+pub use checksum::{Checksum, Crc8, Crc16Ccitt, Crc32C, NoCrc};
+pub use clock::{Clock, NoClock};
+pub use cobs::Cobs;
+pub use codec::{Delimited, EXTENDED_MAX_DATA_SIZE, EXTENDED_MAX_FRAME_SIZE, Extended, FrameCodec};
+pub use decoder::FrameDecoder;
+pub use encoding::{Be, Bytes, Cursor, Le};
+#[cfg(feature = "derive")]
+pub use embed_serial_protocol_derive::{Decode, Encode};
+pub use header::{FrameHeader, PROTOCOL_VERSION};
+#[cfg(feature = "serde")]
+pub use message::{RecvMsg, SendMsg};
+pub use mux::{Channel, Mux, Schedule};
+pub use serial::{BufferedRx, BufferedTx, ByteQueue, DefaultBuffer, ErrorShim};
+pub use reliable::ReliableLink;
+pub use rpc::{RpcClient, RpcServer};
+pub use slip::Slip;
+pub use stats::LinkStats;
+pub use stream::{StreamFrameRx, StreamFrameTx};
+pub use transaction::{Fragmenter, Reassembler};
+
+/// What `#[derive(Encode, Decode)]` turns down, checked by the doctests.
+///
+/// Unions, since there's no telling which field to put on the wire:
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Encode)]
+/// union Either { a: u8, b: u16 }
+/// ```
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Decode)]
+/// union Either { a: u8, b: u16 }
+/// ```
+///
+/// More than one lifetime to borrow the data for:
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Decode)]
+/// struct Two<'a, 'b> { a: &'a [u8], b: &'b [u8] }
+/// ```
+///
+/// Fields without an `Encode`/`Decode` of their own. The impl's bounded on
+/// each field having one, so for `Decode` it's using it that fails:
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Encode)]
+/// struct Timed { after: core::time::Duration }
+/// ```
+///
+/// ```compile_fail
+/// use embed_serial_protocol::Decode;
+///
+/// #[derive(Decode)]
+/// struct Timed { after: core::time::Duration }
+///
+/// let _ = Timed::decode(&[]);
+/// ```
+///
+/// Byte slices inside another type, which would go on the wire as a whole
+/// frame with nothing to say where they end. `encoding::Bytes` nests:
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Encode)]
+/// struct Named<'a> { name: Option<&'a [u8]> }
+/// ```
+///
+/// ```compile_fail
+/// #[derive(embed_serial_protocol::Decode)]
+/// struct Tagged<'a> { tagged: (u8, &'a [u8]) }
+/// ```
+///
+/// While the same types with fields it can handle are fine:
+///
+/// ```
+/// #[derive(embed_serial_protocol::Encode, embed_serial_protocol::Decode)]
+/// struct Two<'a> { a: &'a [u8], b: &'a [u8], after: u32 }
+///
+/// #[derive(embed_serial_protocol::Encode, embed_serial_protocol::Decode)]
+/// struct Named<'a> { name: Option<embed_serial_protocol::Bytes<'a>> }
+/// ```
+#[cfg(all(doctest, feature = "derive"))]
+pub struct DeriveCompileFail;
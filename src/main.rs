@@ -1,52 +1,341 @@
-use std::{collections::VecDeque, convert::Infallible};
+use std::{
+    collections::VecDeque,
+    env, fmt, fs,
+    io::{self, BufWriter, Read as _, Write as _},
+    path::Path,
+    process,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use embed_serial_protocol::{Decode, Encode, Frame, FrameTxRx, MAX_FRAME_SIZE, packet::FrameRx};
-use embedded_hal_nb::serial::{ErrorType, Read, Write};
+use embed_serial_protocol::{
+    Clock, Cobs, Crc8, Crc32C, DefaultBuffer, Delimited, Extended, Frame, FrameCodec, FrameError, FrameIOError, FrameTxRx, NoCrc, Slip,
+    packet::{FrameRecv, FrameSend},
+};
+use embedded_hal_nb::serial::{self, ErrorKind, ErrorType, Read, Write};
+
+const USAGE: &str = "\
+usage: embed-serial-protocol [--codec delimited|extended|cobs|slip] <command> <path> [data]
+
+commands:
+  send <path> <hex|file>  send one frame, from hex (e.g. 01ab55) or a file's contents
+  recv <path>             print the first good frame and exit
+  monitor <path>          print every frame, CRC error and resync until the input ends
+
+<path> is a serial device, PTY or plain file. Set the baud rate etc. with stty first.";
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
 
+fn run(args: &[String]) -> Result<(), String> {
+    let (codec, args) = match args {
+        [flag, codec, rest @ ..] if flag == "--codec" => (codec.as_str(), rest),
+        rest => ("delimited", rest),
+    };
+    let (command, path, data) = match args {
+        [command, path] => (command.as_str(), path.as_str(), None),
+        [command, path, data] => (command.as_str(), path.as_str(), Some(data.as_str())),
+        _ => return Err(usage("expected a command and a path")),
+    };
+    match codec {
+        "delimited" => command_with(Delimited::<Crc8>::new(), command, path, data),
+        "extended" => command_with(Extended::<Crc32C>::new(), command, path, data),
+        "cobs" => command_with(Cobs::<Crc8>::new(), command, path, data),
+        "slip" => command_with(Slip::<NoCrc>::new(), command, path, data),
+        other => Err(usage(&format!("unknown codec {other}"))),
+    }
 }
 
-#[derive(Debug)]
-struct TxBuffer(Vec<u8>);
+fn command_with<C: FrameCodec + Clone>(codec: C, command: &str, path: &str, data: Option<&str>) -> Result<(), String> {
+    match (command, data) {
+        ("send", Some(data)) => send(open(path, true)?, codec, &parse_data(data)?),
+        ("recv", None) => receive(open(path, false)?, codec, false, &mut io::stdout()),
+        ("monitor", None) => receive(open(path, false)?, codec, true, &mut io::stdout()),
+        ("send", None) => Err(usage("send needs hex or a file to send")),
+        _ => Err(usage(&format!("unknown command {command}"))),
+    }
+}
+
+fn usage(problem: &str) -> String {
+    format!("{problem}\n{USAGE}")
+}
+
+/// A file that exists is sent as is, anything else has to be hex
+fn parse_data(data: &str) -> Result<Vec<u8>, String> {
+    if Path::new(data).is_file() {
+        return fs::read(data).map_err(|e| format!("reading {data}: {e}"));
+    }
+    let hex: String = data.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("{data} is neither a file nor an even number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{data} is neither a file nor hex")))
+        .collect()
+}
+
+type Link<C> = FrameTxRx<PortTx, PortRx, C, DefaultBuffer, Uptime>;
 
-impl TxBuffer {
-    fn new() -> TxBuffer {
-        TxBuffer(Vec::new())
+/// Milliseconds without a byte before a partial frame is given up on
+const FRAME_TIMEOUT: u32 = 250;
+
+/// Milliseconds since we started
+struct Uptime(Instant);
+
+impl Clock for Uptime {
+    fn now(&self) -> u32 {
+        self.0.elapsed().as_millis() as u32
     }
 }
 
-impl ErrorType for TxBuffer {
-    type Error = Infallible;
+/// Open `path` for both directions. Only a send creates a missing file, and
+/// sends to a plain file go on the end so it builds up into a capture.
+fn open(path: &str, create: bool) -> Result<fs::File, String> {
+    fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(create)
+        .open(path)
+        .map_err(|e| format!("opening {path}: {e}"))
+}
+
+fn link<C: FrameCodec + Clone>(file: fs::File, codec: C) -> Result<Link<C>, String> {
+    let reader = file.try_clone().map_err(|e| e.to_string())?;
+    Ok(FrameTxRx::with_codec(PortTx(BufWriter::new(file)), PortRx::spawn(reader), codec)
+        .with_timeout(Uptime(Instant::now()), FRAME_TIMEOUT))
 }
 
-impl Write for TxBuffer {
-    fn write(&mut self, c: u8) -> nb::Result<(), Self::Error> {
-        self.0.push(c);
-        Ok(())
+fn send<C: FrameCodec + Clone>(file: fs::File, codec: C, data: &[u8]) -> Result<(), String> {
+    let mut link = link(file, codec)?;
+    nb::block!(link.send(data)).map_err(|e| format!("{e:?}"))?;
+    nb::block!(link.flush()).map_err(|e| e.to_string())?;
+    println!("sent {} bytes: {}", data.len(), hex(data));
+    Ok(())
+}
+
+/// Report each frame and error to `out` as it comes in
+fn receive<C: FrameCodec + Clone>(file: fs::File, codec: C, monitor: bool, out: &mut impl io::Write) -> Result<(), String> {
+    let mut link = link(file, codec)?;
+    // Once the input's closed there's still whatever was buffered to get through
+    let mut closed = false;
+    loop {
+        match link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(FrameIOError::Read(PortError::Closed))) => closed = true,
+            Err(nb::Error::Other(FrameIOError::Read(e))) => return Err(e.to_string()),
+            Err(nb::Error::Other(e)) => return Err(format!("{e:?}")),
+        }
+
+        // FrameRx would quietly toss these, but seeing them is half the point.
+        // What the codec puts between frames isn't worth mentioning.
+        let junk = link.frx.codec.skip_to_start(link.frx.rx.slice());
+        let skipped = &link.frx.rx.slice()[..junk];
+        if !link.frx.codec.only_separators(skipped) {
+            report(out, format_args!("resync: skipped {junk} bytes: {}", hex(skipped)))?;
+        }
+        link.frx.rx.skip(junk);
+
+        match link.recv() {
+            Ok(frame) => {
+                print_frame(out, &frame)?;
+                if !monitor {
+                    return Ok(());
+                }
+            },
+            Err(nb::Error::WouldBlock) if closed => return end_of_input(&link, monitor, out),
+            Err(nb::Error::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { calculated, found, width, .. }))) => {
+                report(
+                    out,
+                    format_args!("crc error: calculated {calculated:0w$x}, found {found:0w$x}, resyncing", w = 2 * width),
+                )?;
+            },
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::Timeout))) => {
+                report(out, format_args!("timeout: rest of the frame never came, resyncing"))?;
+            },
+            Err(nb::Error::Other(FrameIOError::Frame(e))) => report(out, format_args!("bad frame: {e:?}, resyncing"))?,
+            Err(nb::Error::Other(FrameIOError::Read(PortError::Closed))) => return end_of_input(&link, monitor, out),
+            Err(nb::Error::Other(FrameIOError::Read(e))) => return Err(e.to_string()),
+            Err(nb::Error::Other(e)) => return Err(format!("{e:?}")),
+        }
     }
+}
 
-    fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        Ok(())
+fn end_of_input<C: FrameCodec>(link: &Link<C>, monitor: bool, out: &mut impl io::Write) -> Result<(), String> {
+    let left = link.frx.rx.slice();
+    if !left.is_empty() {
+        report(out, format_args!("end of input with {} bytes of partial frame: {}", left.len(), hex(left)))?;
     }
+    if monitor {
+        let s = link.stats();
+        report(
+            out,
+            format_args!(
+                "{} frames, {} bytes received, {} crc errors, {} other bad frames, {} timeouts",
+                s.frames_received,
+                s.bytes_received,
+                s.crc_errors,
+                s.early_end_delim + s.missing_end_delim + s.other_errors,
+                s.timeouts
+            ),
+        )
+    } else {
+        Err("input ended before a frame came in".into())
+    }
+}
+
+fn print_frame(out: &mut impl io::Write, frame: &Frame) -> Result<(), String> {
+    report(out, format_args!("frame: {} bytes, crc {:x}: {}", frame.data.len(), frame.crc, hex(&frame.data)))
+}
+
+/// One line of output
+fn report(out: &mut impl io::Write, line: fmt::Arguments) -> Result<(), String> {
+    writeln!(out, "{line}").map_err(|e| e.to_string())
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(" ")
 }
 
 #[derive(Debug)]
-struct ReadBuffer(pub VecDeque<u8>);
+enum PortError {
+    Io(io::Error),
+    /// Nothing more is coming, e.g. end of a file or the device went away
+    Closed,
+}
+
+impl fmt::Display for PortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortError::Io(e) => write!(f, "{e}"),
+            PortError::Closed => write!(f, "input closed"),
+        }
+    }
+}
+
+impl serial::Error for PortError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
 
-impl ReadBuffer {
-    fn from_iter(data: impl Iterator<Item = u8>) -> ReadBuffer {
-        let q = VecDeque::from_iter(data);
-        ReadBuffer(q)
+/// Reads from the device on a background thread, so `read` never blocks
+struct PortRx {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    pending: VecDeque<u8>,
+}
+
+impl PortRx {
+    fn spawn(mut file: fs::File) -> PortRx {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                let chunk = match file.read(&mut buf) {
+                    // End of a file. Dropping tx lets PortRx know.
+                    Ok(0) => return,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                if tx.send(chunk).is_err() {
+                    return;
+                }
+            }
+        });
+        PortRx { chunks, pending: Default::default() }
     }
 }
 
-impl ErrorType for ReadBuffer {
-    type Error = Infallible;
+impl ErrorType for PortRx {
+    type Error = PortError;
 }
 
-impl Read for ReadBuffer {
+impl Read for PortRx {
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
-        self.0.pop_front().ok_or(nb::Error::WouldBlock)
+        if self.pending.is_empty() {
+            match self.chunks.try_recv() {
+                Ok(Ok(chunk)) => self.pending.extend(chunk),
+                Ok(Err(e)) => return Err(nb::Error::Other(PortError::Io(e))),
+                Err(mpsc::TryRecvError::Empty) => return Err(nb::Error::WouldBlock),
+                Err(mpsc::TryRecvError::Disconnected) => return Err(nb::Error::Other(PortError::Closed)),
+            }
+        }
+        self.pending.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+struct PortTx(BufWriter<fs::File>);
+
+impl ErrorType for PortTx {
+    type Error = PortError;
+}
+
+impl Write for PortTx {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.write_all(&[word]).map_err(|e| nb::Error::Other(PortError::Io(e)))
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.0.flush().map_err(|e| nb::Error::Other(PortError::Io(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monitor a file holding `bytes`, handing back what got printed
+    fn monitor<C: FrameCodec + Clone>(codec: C, bytes: &[u8], name: &str) -> String {
+        let path = env::temp_dir().join(format!("embed-serial-protocol-{}-{name}", process::id()));
+        fs::write(&path, bytes).unwrap();
+        let mut out = Vec::new();
+        let result = receive(open(path.to_str().unwrap(), false).unwrap(), codec, true, &mut out);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn encoded<C: FrameCodec>(codec: &C, frames: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for data in frames {
+            codec.encode_with(data, |b| bytes.push(b)).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn clean_slip_stream_has_no_resyncs() {
+        let codec = Slip::<NoCrc>::new();
+        let out = monitor(codec, &encoded(&codec, &[b"one", b"two", b"three"]), "slip");
+        assert!(!out.contains("resync"), "{out}");
+        assert_eq!(out.lines().filter(|l| l.starts_with("frame:")).count(), 3);
+    }
+
+    #[test]
+    fn clean_cobs_stream_has_no_resyncs() {
+        let codec = Cobs::<Crc8>::new();
+        let out = monitor(codec, &encoded(&codec, &[b"one", &[0, 0], b"three"]), "cobs");
+        assert!(!out.contains("resync"), "{out}");
+        assert_eq!(out.lines().filter(|l| l.starts_with("frame:")).count(), 3);
+    }
+
+    #[test]
+    fn junk_between_frames_is_reported() {
+        let codec = Delimited::<Crc8>::new();
+        let mut bytes = encoded(&codec, &[b"one"]);
+        bytes.extend([0x01, 0x02]);
+        bytes.extend(encoded(&codec, &[b"two"]));
+        let out = monitor(codec, &bytes, "junk");
+        assert!(out.contains("resync: skipped 2 bytes: 01 02"), "{out}");
+        assert_eq!(out.lines().filter(|l| l.starts_with("frame:")).count(), 2);
     }
 }
@@ -0,0 +1,116 @@
+use core::convert::Infallible;
+
+use embedded_hal_nb::serial::{Read, Write};
+use serde::{Serialize, de::DeserializeOwned};
+
+#[cfg(not(feature = "alloc"))]
+use crate::packet::MAX_DATA_SIZE;
+use crate::packet::{FrameIOError, FrameRecv, FrameSend};
+
+/// `send_msg` for anything that can send frames. Each message is
+/// serialized with postcard into a single frame.
+pub trait SendMsg<Tx: Write>: FrameSend<Tx> {
+    /// Serialize `msg` and send it as a frame. A message too big for the
+    /// link's codec is `FrameError::PayloadTooLarge`, same as `send`.
+    ///
+    /// Without `alloc` it's serialized on the stack into `MAX_DATA_SIZE`
+    /// bytes, bigger ones are `FrameIOError::Message(SerializeBufferFull)`.
+    /// Use `send_msg_in` to send bigger ones over an `Extended` link.
+    ///
+    /// WouldBlock means there isn't room for the frame yet, same as `send`.
+    fn send_msg<T: Serialize + ?Sized>(&mut self, msg: &T) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        #[cfg(feature = "alloc")]
+        let data = postcard::to_allocvec(msg).map_err(|e| nb::Error::Other(FrameIOError::Message(e)))?;
+        #[cfg(not(feature = "alloc"))]
+        let mut buf = [0; MAX_DATA_SIZE];
+        #[cfg(not(feature = "alloc"))]
+        let data = postcard::to_slice(msg, &mut buf).map_err(|e| nb::Error::Other(FrameIOError::Message(e)))?;
+        self.send(&data[..])
+    }
+
+    /// `send_msg`, serializing into `buf` instead. Messages bigger than
+    /// `buf` are `FrameIOError::Message(SerializeBufferFull)`.
+    fn send_msg_in<T: Serialize + ?Sized>(
+        &mut self,
+        msg: &T,
+        buf: &mut [u8],
+    ) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let data = postcard::to_slice(msg, buf).map_err(|e| nb::Error::Other(FrameIOError::Message(e)))?;
+        self.send(data)
+    }
+}
+
+impl<Tx: Write, S: FrameSend<Tx>> SendMsg<Tx> for S {}
+
+/// `recv_msg` for anything that can receive frames
+pub trait RecvMsg<Rx: Read>: FrameRecv<Rx> {
+    /// Receive the next frame and deserialize it as a `T`. A frame that
+    /// isn't a `T` is `FrameIOError::Message`, and it's gone either way.
+    fn recv_msg<T: DeserializeOwned>(&mut self) -> nb::Result<T, FrameIOError<Infallible, Rx::Error>> {
+        let frame = self.recv()?;
+        postcard::from_bytes(&frame.data).map_err(|e| nb::Error::Other(FrameIOError::Message(e)))
+    }
+}
+
+impl<Rx: Read, R: FrameRecv<Rx>> RecvMsg<Rx> for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Crc8, Extended,
+        mock::{Wire, link},
+        packet::{FrameRx, FrameTx},
+    };
+
+    #[test]
+    fn messages_round_trip() {
+        let (mut a, mut b) = link();
+        a.send_msg(&(7u8, -300i32, true)).unwrap();
+        a.send_msg("hello").unwrap();
+        assert_eq!(b.recv_msg::<(u8, i32, bool)>().unwrap(), (7, -300, true));
+        #[cfg(feature = "alloc")]
+        assert_eq!(b.recv_msg::<alloc::string::String>().unwrap(), "hello");
+    }
+
+    #[test]
+    fn wrong_type_is_a_message_error() {
+        let (mut a, mut b) = link();
+        a.send_msg(&1u8).unwrap();
+        assert!(matches!(b.recv_msg::<(u32, u32)>(), Err(nb::Error::Other(FrameIOError::Message(_)))));
+        // The frame's gone either way
+        assert!(matches!(b.recv_msg::<u8>(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn big_messages_fit_an_extended_link() {
+        let wire = Wire::new();
+        let mut tx = FrameTx::with_codec(wire.clone(), Extended::<Crc8>::new());
+        tx.tx.capacity = usize::MAX;
+        let msg: [u8; 1000] = core::array::from_fn(|i| i as u8);
+        #[cfg(feature = "alloc")]
+        tx.send_msg(&msg[..]).unwrap();
+        #[cfg(not(feature = "alloc"))]
+        tx.send_msg_in(&msg[..], &mut [0; 1100]).unwrap();
+
+        let mut rx = FrameRx::with_codec(wire, Extended::<Crc8>::new());
+        (rx.rx.capacity, rx.rx.budget) = (usize::MAX, usize::MAX);
+        let frame = rx.recv_ref().unwrap();
+        assert_eq!(postcard::from_bytes::<&[u8]>(frame.data()).unwrap(), msg);
+    }
+
+    #[test]
+    fn too_big_for_the_codec() {
+        let (mut a, _) = link();
+        let msg = [0u8; 300];
+        #[cfg(feature = "alloc")]
+        assert!(matches!(
+            a.send_msg(&msg[..]),
+            Err(nb::Error::Other(FrameIOError::Frame(crate::FrameError::PayloadTooLarge { .. })))
+        ));
+        assert!(matches!(
+            a.send_msg_in(&msg[..], &mut [0; 100]),
+            Err(nb::Error::Other(FrameIOError::Message(postcard::Error::SerializeBufferFull)))
+        ));
+    }
+}
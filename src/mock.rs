@@ -0,0 +1,160 @@
+//! In-memory serial lines for the tests
+
+extern crate std;
+
+use core::convert::Infallible;
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    vec::Vec,
+};
+
+use embedded_hal_nb::serial::{ErrorType, Read, Write};
+
+use crate::{clock::Clock, packet::FrameTxRx};
+
+/// One direction of a serial line. Clones share the same bytes, so one
+/// can be handed to a sender and another to a receiver.
+#[derive(Debug, Clone, Default)]
+pub struct Wire(Rc<RefCell<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    bytes: VecDeque<u8>,
+    /// The other end hung up, reads past the last byte see the end of the stream
+    closed: bool,
+    /// Most bytes the wire holds before writes WouldBlock, like a slow UART
+    room: Option<usize>,
+}
+
+impl Wire {
+    pub fn new() -> Wire {
+        Wire::default()
+    }
+
+    /// Put bytes on the wire as if the other end had sent them
+    pub fn push(&self, data: &[u8]) {
+        self.0.borrow_mut().bytes.extend(data);
+    }
+
+    /// Everything on the wire, emptying it
+    pub fn take(&self) -> Vec<u8> {
+        self.0.borrow_mut().bytes.drain(..).collect()
+    }
+
+    /// Only take `room` bytes until some are read, see `State::room`
+    pub fn set_room(&self, room: usize) {
+        self.0.borrow_mut().room = Some(room);
+    }
+
+    /// How many more bytes the wire will take
+    fn free(&self) -> usize {
+        let state = self.0.borrow();
+        state.room.map_or(usize::MAX, |room| room.saturating_sub(state.bytes.len()))
+    }
+
+    /// Hang up, see `State::closed`
+    pub fn close(&self) {
+        self.0.borrow_mut().closed = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Two ends of a full duplex link, each sending on the wire the other
+/// reads from
+pub fn link() -> (FrameTxRx<Wire, Wire>, FrameTxRx<Wire, Wire>) {
+    let (a, b) = (Wire::new(), Wire::new());
+    (FrameTxRx::new(a.clone(), b.clone()), FrameTxRx::new(b, a))
+}
+
+/// A clock the test moves along by hand. Clones all tell the same time.
+#[derive(Debug, Clone, Default)]
+pub struct Ticks(Rc<Cell<u32>>);
+
+impl Ticks {
+    pub fn new() -> Ticks {
+        Ticks::default()
+    }
+
+    pub fn set(&self, now: u32) {
+        self.0.set(now);
+    }
+}
+
+impl Clock for Ticks {
+    fn now(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+impl ErrorType for Wire {
+    type Error = Infallible;
+}
+
+impl Read for Wire {
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.0.borrow_mut().bytes.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write for Wire {
+    fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        if self.free() == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.push(&[word]);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl embedded_io::ErrorType for Wire {
+    type Error = Infallible;
+}
+
+impl embedded_io::Read for Wire {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let mut state = self.0.borrow_mut();
+        let n = buf.len().min(state.bytes.len());
+        for (b, w) in buf.iter_mut().zip(state.bytes.drain(..n)) {
+            *b = w;
+        }
+        Ok(n)
+    }
+}
+
+impl embedded_io::ReadReady for Wire {
+    fn read_ready(&mut self) -> Result<bool, Infallible> {
+        let state = self.0.borrow();
+        Ok(!state.bytes.is_empty() || state.closed)
+    }
+}
+
+impl embedded_io::Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        let n = buf.len().min(self.free());
+        self.push(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl embedded_io::WriteReady for Wire {
+    fn write_ready(&mut self) -> Result<bool, Infallible> {
+        Ok(self.free() > 0)
+    }
+}
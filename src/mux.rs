@@ -0,0 +1,301 @@
+use core::{cell::RefCell, convert::Infallible};
+
+use embedded_hal_nb::serial::{Read, Write};
+use heapless::Deque;
+
+use crate::{
+    clock::{Clock, NoClock},
+    codec::{Delimited, FrameCodec},
+    header::{FRAME_HEADER_LEN, FrameHeader, u4, u6},
+    packet::{Frame, FrameData, FrameIOError, FrameRecv, FrameSend, FrameTxRx, check_payload, frame_data, frame_data_limit},
+    serial::{ByteQueue, DefaultBuffer},
+    stats::LinkStats,
+};
+
+/// Most channels a `Mux` can have, as many as `FrameHeader::channel` holds
+pub const MAX_CHANNELS: usize = 64;
+
+/// Which channel's frame goes out next when more than one is waiting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Take turns, one frame from each channel with something to send
+    #[default]
+    RoundRobin,
+    /// Lowest numbered channel first, higher ones only get a turn when
+    /// the lower ones have nothing queued
+    Priority,
+}
+
+struct Queues<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> {
+    link: FrameTxRx<Tx, Rx, C, B, K>,
+    rx: [Deque<Frame, Q>; N],
+    tx: [Deque<(FrameHeader, FrameData), Q>; N],
+    /// Round robin's next turn
+    next: usize,
+    dropped: u32,
+}
+
+/// Runs `N` independent channels over one `FrameTxRx`, e.g. a console,
+/// telemetry and firmware control all on the same UART.
+///
+/// Every frame carries a `FrameHeader` whose `channel` says which channel
+/// it's for, so there can be up to `MAX_CHANNELS`. `channel` hands out an
+/// endpoint per channel that's a `FrameSend` and `FrameRecv` in its own
+/// right, and they can all be used at once. Each channel queues up to `Q`
+/// frames each way: frames come off the link into the queue of whichever
+/// channel they're for, and go out from the channel queues in the order
+/// `schedule` says as the link has room.
+pub struct Mux<Tx: Write, Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock, const N: usize = 4, const Q: usize = 4> {
+    queues: RefCell<Queues<Tx, Rx, C, B, K, N, Q>>,
+    pub schedule: Schedule,
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> Mux<Tx, Rx, C, B, K, N, Q> {
+    /// Sets `link` up to carry a `FrameHeader` on every frame, see
+    /// `FrameTxRx::with_header`
+    pub fn new(link: FrameTxRx<Tx, Rx, C, B, K>) -> Mux<Tx, Rx, C, B, K, N, Q> {
+        Mux {
+            queues: RefCell::new(Queues {
+                link: link.with_header(FrameHeader::for_channel(u6::new(0), u4::new(0))),
+                rx: core::array::from_fn(|_| Deque::new()),
+                tx: core::array::from_fn(|_| Deque::new()),
+                next: 0,
+                dropped: 0,
+            }),
+            schedule: Schedule::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> FrameTxRx<Tx, Rx, C, B, K> {
+        self.queues.into_inner().link
+    }
+
+    /// The endpoint for channel `id`, None if there are only `N` channels
+    pub fn channel(&self, id: u8) -> Option<Channel<'_, Tx, Rx, C, B, K, N, Q>> {
+        ((id as usize) < N.min(MAX_CHANNELS)).then_some(Channel { mux: self, id })
+    }
+
+    /// Frames thrown away because they were for a channel that doesn't
+    /// exist, or one whose receive queue was full
+    pub fn dropped(&self) -> u32 {
+        self.queues.borrow().dropped
+    }
+
+    /// See `FrameTxRx::stats`
+    pub fn stats(&self) -> LinkStats {
+        self.queues.borrow().link.stats()
+    }
+
+    /// Read in frames and sort them into the channels' receive queues
+    fn buffer(&self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        let mut q = self.queues.borrow_mut();
+        match q.link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(e) => return Err(e),
+        }
+        loop {
+            match q.link.recv() {
+                Ok(frame) => {
+                    // The link has headers on, so every frame has one
+                    let id = frame.header.map_or(usize::MAX, |h| h.channel().value() as usize);
+                    let full = match q.rx.get_mut(id) {
+                        Some(rx) => rx.push_back(frame).is_err(),
+                        None => true,
+                    };
+                    if full {
+                        q.dropped = q.dropped.wrapping_add(1);
+                    }
+                },
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                // There's no telling which channel a bad frame was for, the
+                // link's stats have it
+                Err(nb::Error::Other(FrameIOError::Frame(_))) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Move queued frames onto the link as it has room for them
+    fn send_queued(&self) -> Result<(), Tx::Error> {
+        let mut q = self.queues.borrow_mut();
+        let q = &mut *q;
+        loop {
+            let next = match self.schedule {
+                Schedule::RoundRobin => (0..N).map(|i| (q.next + i) % N).find(|&id| !q.tx[id].is_empty()),
+                Schedule::Priority => (0..N).find(|&id| !q.tx[id].is_empty()),
+            };
+            let Some(id) = next else {
+                return Ok(());
+            };
+            let Some((header, data)) = q.tx[id].front() else {
+                return Ok(());
+            };
+            match q.link.send_with_header(*header, data) {
+                Ok(()) => q.next = (id + 1) % N,
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(FrameIOError::Write(e))) => return Err(e),
+                // It was never going to fit, drop it rather than hold up the queue
+                Err(nb::Error::Other(_)) => q.dropped = q.dropped.wrapping_add(1),
+            }
+            q.tx[id].pop_front();
+        }
+    }
+}
+
+/// One of a `Mux`'s channels, see `Mux::channel`
+pub struct Channel<'a, Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> {
+    mux: &'a Mux<Tx, Rx, C, B, K, N, Q>,
+    id: u8,
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> Channel<'_, Tx, Rx, C, B, K, N, Q> {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> FrameSend<Tx> for Channel<'_, Tx, Rx, C, B, K, N, Q> {
+    /// Done once this channel's frames are all out on the wire. Frames
+    /// from the other channels go out along the way, in turn.
+    fn flush(&mut self) -> nb::Result<(), Tx::Error> {
+        self.mux.send_queued()?;
+        // Make room for the rest
+        match self.mux.queues.borrow_mut().link.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(e) => return Err(e),
+        }
+        self.mux.send_queued()?;
+        let mut q = self.mux.queues.borrow_mut();
+        if !q.tx[self.id as usize].is_empty() {
+            return Err(nb::Error::WouldBlock);
+        }
+        q.link.flush()
+    }
+
+    /// WouldBlock means this channel's transmit queue is full
+    fn send(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        self.send_with_header(FrameHeader::for_channel(u6::new(0), u4::new(0)), data)
+    }
+
+    fn max_payload(&self) -> usize {
+        frame_data_limit(C::MAX_DATA_SIZE) - FRAME_HEADER_LEN
+    }
+
+    /// `header`'s channel is set to this one's
+    fn send_with_header(&mut self, mut header: FrameHeader, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        check_payload(data.len(), self.max_payload()).map_err(FrameIOError::from)?;
+        header.set_channel(u6::new(self.id));
+        let data = frame_data(data).map_err(FrameIOError::from)?;
+        {
+            let mut q = self.mux.queues.borrow_mut();
+            q.tx[self.id as usize].push_back((header, data)).map_err(|_| nb::Error::WouldBlock)?;
+        }
+        self.mux.send_queued().map_err(|e| nb::Error::Other(FrameIOError::Write(e)))
+    }
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize, const Q: usize> FrameRecv<Rx> for Channel<'_, Tx, Rx, C, B, K, N, Q> {
+    /// Reads in frames for every channel, not just this one
+    fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        self.mux.buffer()
+    }
+
+    fn recv(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, Rx::Error>> {
+        match self.mux.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(e) => return Err(e),
+        }
+        let mut q = self.mux.queues.borrow_mut();
+        q.rx[self.id as usize].pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::{
+        checksum::Crc8,
+        mock::{Wire, link},
+        packet::FrameRx,
+    };
+
+    type Queued = Mux<Wire, Wire, Delimited<Crc8>, Deque<u8, 12>>;
+
+    /// A Mux whose link only has room to queue one frame, on a wire that
+    /// takes nothing until it's opened up
+    fn backed_up(schedule: Schedule) -> (Queued, Wire) {
+        let wire = Wire::new();
+        wire.set_room(0);
+        let link = FrameTxRx::with_buffers(wire.clone(), Wire::new(), Delimited::new(), Deque::new(), Deque::new());
+        let mut mux = Mux::new(link);
+        mux.schedule = schedule;
+        (mux, wire)
+    }
+
+    /// Queue two frames on each of channels 0 and 1 and one on 2, then
+    /// let them out and see which order they went in
+    fn sent_order(schedule: Schedule) -> Vec<FrameData, 5> {
+        let (mux, wire) = backed_up(schedule);
+        for (id, data) in [(0, b"a1"), (0, b"a2"), (1, b"b1"), (1, b"b2"), (2, b"c1")] {
+            mux.channel(id).unwrap().send(data).unwrap();
+        }
+        wire.set_room(usize::MAX);
+        for id in 0..3 {
+            while mux.channel(id).unwrap().flush().is_err() {}
+        }
+        let mut rx = FrameRx::new(wire).with_headers();
+        let mut sent = Vec::new();
+        while let Ok(frame) = rx.recv() {
+            sent.push(frame.data).unwrap();
+        }
+        sent
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        // a1 went straight onto the link, then it's channel 1's turn
+        let order = sent_order(Schedule::RoundRobin);
+        assert!(order.iter().map(|d| &d[..]).eq([b"a1", b"b1", b"c1", b"a2", b"b2"]));
+    }
+
+    #[test]
+    fn priority_empties_the_lowest_channel_first() {
+        let order = sent_order(Schedule::Priority);
+        assert!(order.iter().map(|d| &d[..]).eq([b"a1", b"a2", b"b1", b"b2", b"c1"]));
+    }
+
+    #[test]
+    fn channels_are_carried_in_the_header() {
+        let (a, b) = link();
+        let (a, b): (Mux<_, _>, Mux<_, _>) = (Mux::new(a), Mux::new(b));
+        a.channel(2).unwrap().send(b"console").unwrap();
+        // Whatever channel the header says, it goes out on the sender's
+        let header = FrameHeader::for_channel(u6::new(1), u4::new(7));
+        a.channel(3).unwrap().send_with_header(header, b"telemetry").unwrap();
+
+        assert!(matches!(b.channel(0).unwrap().recv(), Err(nb::Error::WouldBlock)));
+        let frame = b.channel(3).unwrap().recv().unwrap();
+        assert_eq!(frame.data[..], *b"telemetry");
+        assert_eq!(frame.header.map(|h| (h.channel(), h.msg_type())), Some((u6::new(3), u4::new(7))));
+        let frame = b.channel(2).unwrap().recv().unwrap();
+        assert_eq!(frame.data[..], *b"console");
+        assert_eq!(b.dropped(), 0);
+    }
+
+    #[test]
+    fn frames_with_nowhere_to_go_are_dropped() {
+        let (a, b) = link();
+        let a: Mux<_, _, _, _, _, 8> = Mux::new(a);
+        let b: Mux<_, _, _, _, _, 4, 1> = Mux::new(b);
+        assert!(b.channel(4).is_none());
+        // No channel 5 on the other end, and room for one frame on channel 0
+        a.channel(5).unwrap().send(b"nobody").unwrap();
+        a.channel(0).unwrap().send(b"first").unwrap();
+        a.channel(0).unwrap().send(b"second").unwrap();
+        assert_eq!(b.channel(0).unwrap().recv().unwrap().data[..], *b"first");
+        assert!(matches!(b.channel(0).unwrap().recv(), Err(nb::Error::WouldBlock)));
+        assert_eq!(b.dropped(), 2);
+    }
+}
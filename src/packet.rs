@@ -1,39 +1,141 @@
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 use core::convert::Infallible;
 
+#[cfg(feature = "alloc")]
 use alloc::string::String;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
-use crc::Crc;
 use embedded_hal_nb::serial::{ErrorType, Read, Write};
-use embedded_io::Write as IoWrite;
 
 use crate::{
     Decode, Encode,
-    serial::{BufferedRx, BufferedRxTx, BufferedTx, ErrorShim, ReadAmt},
+    bus::for_us,
+    checksum::{Checksum, Crc8, MAX_CHECKSUM_WIDTH},
+    clock::{Clock, NoClock},
+    stats::{LinkStats, count},
+    codec::{Delimited, EXTENDED_MAX_DATA_SIZE, FrameCodec},
+    header::{FRAME_HEADER_LEN, FrameHeader, with_header},
+    serial::{BufferedRx, BufferedTx, ByteQueue, DefaultBuffer},
 };
 
 /// size field is a u8, so max amount of data is u8::MAX (255)
 pub const MAX_DATA_SIZE: usize = u8::MAX as usize;
-/// Start: 1, Size: 1, Data: MAX_DATA_SIZE, CRC: 1, End: 1
-pub const MAX_FRAME_SIZE: usize = MAX_DATA_SIZE + 4;
+/// Start: 1, Size: 1-2, Data: MAX_DATA_SIZE-(2 * MAX_DATA_SIZE),
+/// CRC: up to 2 * MAX_CHECKSUM_WIDTH, End: 1
+///
+/// Worst case every byte between the delimiters has to be escaped. This is
+/// the largest frame any codec with a single byte size can produce, see
+/// `EXTENDED_MAX_FRAME_SIZE` for the `Extended` format.
+pub const MAX_FRAME_SIZE: usize = 2 + 2 * (MAX_DATA_SIZE + 1 + MAX_CHECKSUM_WIDTH);
 /// Start and End byte of a Frame
 pub const DELIMITER: u8 = 0x55;
 pub const END_DELIM: u8 = 0xAA;
+/// Any DELIMITER, END_DELIM or ESCAPE byte between the delimiters of a frame
+/// goes on the wire as ESCAPE followed by the byte XOR'd with ESCAPE_XOR.
+pub const ESCAPE: u8 = 0x7D;
+pub const ESCAPE_XOR: u8 = 0x20;
 
 /// Frames consist of a Start Delimiter, Size byte,
-/// the packaged data, CRC byte, and End Delimiter.
+/// the packaged data, CRC, and End Delimiter.
+/// Size, data and CRC are byte stuffed.
+///
+/// `crc` is wide enough for any `Checksum`, only the low `Checksum::WIDTH`
+/// bytes are ever used. `size` is as it was on the wire, `header` included.
 #[derive(Debug)]
 pub struct Frame {
-    pub size: u8,
-    pub data: Vec<u8>,
-    pub crc: u8,
+    pub size: u16,
+    /// Only set on links where every frame has one, see `FrameHeader`
+    pub header: Option<FrameHeader>,
+    pub data: FrameData,
+    pub crc: u32,
 }
 
 impl Frame {
-    /// Length in a slice this frame occupies including start and end Delimiters
-    pub fn len(&self) -> usize {
-        self.data.len() + 4
+    /// Length in a slice this frame occupies once `codec` encodes it,
+    /// delimiters, escape bytes and checksum included. Decoding reports
+    /// how many bytes a frame took up as well.
+    pub fn encoded_len<C: FrameCodec>(&self, codec: &C) -> Result<usize, FrameError> {
+        match self.header {
+            Some(header) => codec.encoded_len(&with_header(header, &self.data)?),
+            None => codec.encoded_len(&self.data),
+        }
+    }
+
+    /// True if the frame carries no data
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Owned Frame data. A Vec with the `alloc` feature, otherwise the data
+/// is stored inline in a heapless Vec big enough for any frame with a single
+/// byte size. Bigger `Extended` frames can still be borrowed with `recv_ref`.
+#[cfg(feature = "alloc")]
+pub type FrameData = Vec<u8>;
+#[cfg(not(feature = "alloc"))]
+pub type FrameData = heapless::Vec<u8, MAX_DATA_SIZE>;
+
+/// `max`, or less if that's more than FrameData holds
+pub(crate) fn frame_data_limit(max: usize) -> usize {
+    #[cfg(not(feature = "alloc"))]
+    let max = max.min(MAX_DATA_SIZE);
+    max
+}
+
+/// Copy `data` into FrameData. Without `alloc` there's only room for
+/// MAX_DATA_SIZE bytes.
+pub(crate) fn frame_data(data: &[u8]) -> Result<FrameData, FrameError> {
+    #[cfg(feature = "alloc")]
+    let data = Ok(data.to_vec());
+    #[cfg(not(feature = "alloc"))]
+    let data = FrameData::from_slice(data).map_err(|_| FrameError::PayloadTooLarge {
+        size: data.len(),
+        max: MAX_DATA_SIZE,
+    });
+    data
+}
+
+/// `prefix` then `data` as one FrameData, for the layers that put a few
+/// bytes of their own in front of the data. Without `alloc` there's only
+/// room for MAX_DATA_SIZE bytes in all.
+pub(crate) fn frame_data_with(prefix: &[u8], data: &[u8]) -> Result<FrameData, FrameError> {
+    let mut packet = frame_data(prefix)?;
+    #[cfg(feature = "alloc")]
+    packet.extend_from_slice(data);
+    #[cfg(not(feature = "alloc"))]
+    packet.extend_from_slice(data).map_err(|_| FrameError::PayloadTooLarge {
+        size: data.len(),
+        max: MAX_DATA_SIZE - prefix.len(),
+    })?;
+    Ok(packet)
+}
+
+/// A Frame whose data is borrowed rather than owned, e.g. straight out
+/// of the `BufferedRx` buffer by `FrameRx::recv_ref`.
+///
+/// Because of byte stuffing the data can only be borrowed once it's been
+/// unstuffed in place, see `FrameCodec::decode_in_place`.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameRef<'a> {
+    pub size: u16,
+    /// See `Frame::header`
+    pub header: Option<FrameHeader>,
+    pub data: &'a [u8],
+    pub crc: u32,
+}
+
+impl FrameRef<'_> {
+    /// Copy the data out into an owned Frame. Only fails without `alloc`
+    /// if there's more data than FrameData has room for.
+    pub fn to_frame(&self) -> Result<Frame, FrameError> {
+        Ok(Frame {
+            size: self.size,
+            header: self.header,
+            data: frame_data(self.data)?,
+            crc: self.crc,
+        })
     }
 }
 
@@ -52,153 +154,367 @@ pub enum FrameError {
         found_at: usize,
         expected: usize,
     },
+    /// A start delimiter showed up before the frame we were decoding was finished.
+    /// The frame got cut short and a new one starts at `found_at`.
+    EarlyStartDelim {
+        found_at: usize,
+    },
+    /// Codecs know the whole encoded size up front. Types encoded a field
+    /// at a time only know they needed `expected_at_least` to get as far
+    /// as the field that didn't fit.
     EncodeBufferTooSmall {
-        expected: usize,
+        expected_at_least: usize,
         found: usize,
     },
     DecodeBufferTooSmall {
         expected_at_least: usize,
         found: usize,
     },
+    /// The bytes at `index` can't have come from the codec's encoder
+    InvalidEncoding {
+        index: usize,
+    },
+    /// There's more data than fits in a frame. `max` is as much as will fit.
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The rest of a frame never came. It's been thrown away.
+    Timeout,
+    /// `width` is the configured checksum's width in bytes
     CrcMismatch {
-        calculated: u8,
-        found: u8,
+        calculated: u32,
+        found: u32,
+        width: usize,
+        #[cfg(feature = "alloc")]
         buf: Vec<u8>
     },
+    #[cfg(feature = "alloc")]
     Debug(String),
 }
 
-impl<'a> Encode for FrameDataSlice<'a> {
-    type Error = FrameError;
+/// True if the byte can't go on the wire as is inside a frame
+fn needs_escape(b: u8) -> bool {
+    b == DELIMITER || b == END_DELIM || b == ESCAPE
+}
 
-    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-        // Frame start
-        buffer[0] = DELIMITER;
-        // size byte. If Self is too large then we'll just
-        // grab the first MAX_DATA_SIZE bytes *shrug*
-        let size = MAX_DATA_SIZE.min(self.len());
-        buffer[1] = size as u8;
-
-        // Check buffer length
-        // Required length is 1 from start Delim, 1 from size byte,
-        // size from data, 1 from crc, 1 from end delim
-        if buffer.len() < size + 4 {
-            return Err(FrameError::EncodeBufferTooSmall {
-                expected: size + 4,
-                found: buffer.len(),
-            });
+/// Number of bytes `data` takes up on the wire once stuffed
+pub fn stuffed_len(data: &[u8]) -> usize {
+    data.len() + data.iter().filter(|b| needs_escape(**b)).count()
+}
+
+/// Read the next unstuffed byte starting at `index`, moving `index` past it.
+/// `remaining` is how many unstuffed bytes (including this one) the frame still
+/// needs before its end delimiter, and is only used for error reporting.
+fn unstuff(data: &[u8], index: &mut usize, remaining: usize) -> Result<u8, FrameError> {
+    let i = *index;
+    let check = |at: usize| -> Result<u8, FrameError> {
+        match data.get(at) {
+            // +1 for the end delimiter
+            None => Err(FrameError::DecodeBufferTooSmall {
+                expected_at_least: i + remaining + 1,
+                found: data.len(),
+            }),
+            Some(&END_DELIM) => Err(FrameError::EarlyEndDelim {
+                found_at: at,
+                expected: i + remaining,
+            }),
+            Some(&DELIMITER) => Err(FrameError::EarlyStartDelim { found_at: at }),
+            Some(b) => Ok(*b),
         }
+    };
+    let b = check(i)?;
+    if b == ESCAPE {
+        let escaped = check(i + 1)?;
+        *index = i + 2;
+        Ok(escaped ^ ESCAPE_XOR)
+    } else {
+        *index = i + 1;
+        Ok(b)
+    }
+}
+
+/// Fail with PayloadTooLarge if `size` bytes of data won't fit in a frame
+/// that carries at most `max`
+pub(crate) fn check_payload(size: usize, max: usize) -> Result<(), FrameError> {
+    if size > max {
+        return Err(FrameError::PayloadTooLarge { size, max });
+    }
+    Ok(())
+}
 
-        // Copy data from Self to buffer
-        let data = &self[0..size];
-        let data_buf = &mut buffer[2..size + 2];
-        data_buf.copy_from_slice(data);
+/// Most data a Delimited frame with an `L` byte size field carries
+const fn max_data_size(l: usize) -> usize {
+    if l == 1 { MAX_DATA_SIZE } else { EXTENDED_MAX_DATA_SIZE }
+}
 
-        // CRC
-        let c = Crc::<u8>::new(&crc::CRC_8_MAXIM_DOW);
-        let mut d = c.digest();
-        d.update(&[size as u8]);
-        d.update(data);
-        let crc = d.finalize();
-        buffer[size + 2] = crc;
+/// The little endian size field for `size` bytes of data
+fn size_header<const L: usize>(size: usize) -> [u8; L] {
+    let mut header = [0; L];
+    header.copy_from_slice(&(size as u32).to_le_bytes()[..L]);
+    header
+}
 
-        // End delim
-        buffer[size + 3] = END_DELIM;
+/// Number of bytes `data` takes up once encoded as a Delimited frame with an
+/// `L` byte size field and a `C` checksum
+pub(crate) fn encoded_frame_len<C: Checksum, const L: usize>(data: &[u8]) -> Result<usize, FrameError> {
+    check_payload(data.len(), max_data_size(L))?;
+    let header = size_header::<L>(data.len());
+    let mut crc = [0; MAX_CHECKSUM_WIDTH];
+    C::write(C::checksum(&header, data), &mut crc);
 
-        Ok(size + 4)
-    }
+    // 1 from start Delim, the stuffed size, data and crc, and 1 from end delim
+    Ok(2 + stuffed_len(&header) + stuffed_len(data) + stuffed_len(&crc[..C::WIDTH]))
 }
 
-impl<'a> Decode<'_> for Frame {
-    type Error = FrameError;
+/// Encode `data` as a Delimited frame with an `L` byte size field and a `C`
+/// checksum, handing the bytes to `out`. Returns how many there were.
+pub(crate) fn encode_frame_with<C: Checksum, const L: usize>(
+    data: &[u8],
+    mut out: impl FnMut(u8),
+) -> Result<usize, FrameError> {
+    // Too much data is an error rather than quietly sending only some of it
+    check_payload(data.len(), max_data_size(L))?;
+    let header = size_header::<L>(data.len());
 
-    fn decode(data: &'_ [u8]) -> Result<Self, Self::Error> {
+    // CRC
+    let mut crc = [0; MAX_CHECKSUM_WIDTH];
+    C::write(C::checksum(&header, data), &mut crc);
+    let crc = &crc[..C::WIDTH];
 
-        // Check data has at least a zero length data frame
-        if data.len() < 4 {
-            return Err(FrameError::DecodeBufferTooSmall {
-                expected_at_least: 4,
-                found: data.len(),
-            });
-        }
-        // Check start delimiter
-        if data[0] != DELIMITER {
-            return Err(FrameError::MissingStartDelim);
-        }
-        // Grab size byte
-        let size = data[1] as usize;
-
-        // Check data size
-        // Delimiter: 1, Size: 1, crc: 1, End Delim: 1
-        if data.len() < size + 4 {
-            return Err(FrameError::DecodeBufferTooSmall {
-                expected_at_least: size + 4,
-                found: data.len(),
-            });
+    // Frame start
+    out(DELIMITER);
+    let mut n = 2;
+    // Size, data and crc, escaped as needed
+    for b in header.iter().chain(data).chain(crc) {
+        if needs_escape(*b) {
+            out(ESCAPE);
+            out(*b ^ ESCAPE_XOR);
+            n += 2;
+        } else {
+            out(*b);
+            n += 1;
         }
+    }
+    // End delim
+    out(END_DELIM);
 
-        // Grab data vec
-        let p = data[2..size + 2].to_vec();
+    Ok(n)
+}
 
-        // Check size of the frame by going to the end delimiter position and
-        // walking backwards until we find it
-        if data[size + 3] != END_DELIM {
-            for i in (0..=size + 3).rev() {
-                if data[i] == END_DELIM {
-                    return Err(FrameError::EarlyEndDelim { found_at: i, expected: size+3 })
-                }
-            }
-            // If we get here then the end delimiter is totally missing.
-            // We should still check CRC
-        }
+/// What a pass over a Delimited frame found, without copying anything out
+struct FrameScan {
+    size: usize,
+    crc: u32,
+    /// Index of the first stuffed data byte
+    data_start: usize,
+    /// Bytes the whole frame took up, delimiters and all
+    len: usize,
+}
 
-        // CRC
-        let c = Crc::<u8>::new(&crc::CRC_8_MAXIM_DOW);
-        let mut d = c.digest();
-        d.update(&[size as u8]);
-        d.update(p.as_ref());
-        let calc_crc = d.finalize();
-        let crc = data[size + 2];
-        if crc != calc_crc {
-            // Now a CRC check only fails if a decoded frame is known to be the same size
-            // based on the position of the end delimiter.
-            return Err(FrameError::CrcMismatch {
-                calculated: calc_crc,
-                found: crc,
-                buf: Vec::from(data)
-            });
-        }
+/// Walk a Delimited frame with an `L` byte size field and a `C` checksum at
+/// the front of `data`, checking everything about it (CRC included) without
+/// copying it out.
+fn scan_frame<C: Checksum, const L: usize>(data: &[u8]) -> Result<FrameScan, FrameError> {
+
+    // Check data has at least a zero length data frame
+    let min = 2 + L + C::WIDTH;
+    if data.len() < min {
+        return Err(FrameError::DecodeBufferTooSmall {
+            expected_at_least: min,
+            found: data.len(),
+        });
+    }
+    // Check start delimiter
+    if data[0] != DELIMITER {
+        return Err(FrameError::MissingStartDelim);
+    }
+    // Grab the size. Size, data and crc still to come
+    let mut i = 1;
+    let mut header = [0; L];
+    for (n, h) in header.iter_mut().enumerate() {
+        *h = unstuff(data, &mut i, L - n + C::WIDTH)?;
+    }
+    let mut size = [0; 4];
+    size[..L].copy_from_slice(&header);
+    let size = u32::from_le_bytes(size) as usize;
+    // Only an Extended size can be out of range, and only if it got mangled
+    check_payload(size, max_data_size(L))?;
+    let data_start = i;
+
+    // Run over the data. Running into either delimiter on the way means
+    // the frame is shorter than it says it is.
+    let mut d = C::digest();
+    C::update(&mut d, &header);
+    for n in 0..size {
+        let b = unstuff(data, &mut i, size - n + C::WIDTH)?;
+        C::update(&mut d, &[b]);
+    }
+    let mut crc = [0; MAX_CHECKSUM_WIDTH];
+    for (n, c) in crc[..C::WIDTH].iter_mut().enumerate() {
+        *c = unstuff(data, &mut i, C::WIDTH - n)?;
+    }
+    let crc = C::read(&crc);
+
+    // Need the end delimiter position to be in the buffer
+    if data.len() <= i {
+        return Err(FrameError::DecodeBufferTooSmall {
+            expected_at_least: i + 1,
+            found: data.len(),
+        });
+    }
+
+    // CRC
+    let calc_crc = C::finalize(d);
+    if crc != calc_crc {
+        // Now a CRC check only fails if a decoded frame is known to be the same size
+        // based on the position of the end delimiter.
+        return Err(FrameError::CrcMismatch {
+            calculated: calc_crc,
+            found: crc,
+            width: C::WIDTH,
+            #[cfg(feature = "alloc")]
+            buf: Vec::from(data)
+        });
+    }
+
+    // If data is good, double check End Delim
+    if data[i] != END_DELIM {
+        return Err(FrameError::MissingEndDelim { index: i, found: data[i] })
+    }
+
+    Ok(FrameScan { size, crc, data_start, len: i + 1 })
+}
+
+/// Decode a Delimited frame with an `L` byte size field and a `C` checksum
+/// from the front of `data`. Also returns how many bytes of `data` the frame took up.
+pub(crate) fn decode_frame<C: Checksum, const L: usize>(data: &[u8]) -> Result<(Frame, usize), FrameError> {
+    let scan = scan_frame::<C, L>(data)?;
+    // Without alloc an owned Frame only has room for MAX_DATA_SIZE
+    #[cfg(not(feature = "alloc"))]
+    check_payload(scan.size, MAX_DATA_SIZE)?;
+
+    // We know it's all good now so just copy the data out
+    let mut i = scan.data_start;
+    let p = (0..scan.size)
+        .map(|n| unstuff(data, &mut i, scan.size - n))
+        .collect::<Result<FrameData, _>>()?;
+
+    Ok((Frame {
+        size: scan.size as u16,
+        header: None,
+        data: p,
+        crc: scan.crc,
+    }, scan.len))
+}
+
+/// Decode a Delimited frame with an `L` byte size field and a `C` checksum
+/// from the front of `data`, unstuffing the data in place so the FrameRef can
+/// borrow it. `data` is only touched if the frame is good.
+pub(crate) fn decode_frame_in_place<C: Checksum, const L: usize>(
+    data: &mut [u8],
+) -> Result<(FrameRef<'_>, usize), FrameError> {
+    let scan = scan_frame::<C, L>(data)?;
 
-        // If data is good, double check End Delim
-        if data[size + 3] != END_DELIM {
-            return Err(FrameError::MissingEndDelim { index: size+3, found: data[size+3] })
+    // Unstuffed bytes are never further along than their stuffed
+    // version, so we can write them from the front as we read.
+    let mut i = scan.data_start;
+    for n in 0..scan.size {
+        data[n] = unstuff(data, &mut i, scan.size - n)?;
+    }
+
+    Ok((FrameRef {
+        size: scan.size as u16,
+        header: None,
+        data: &data[..scan.size],
+        crc: scan.crc,
+    }, scan.len))
+}
+
+impl Encode for FrameDataSlice<'_> {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        Delimited::<Crc8>::new().encode(self, buffer)
+    }
+}
+
+/// The header, if there is one, goes out in front of the data
+impl Encode for Frame {
+    type Error = FrameError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        match self.header {
+            Some(header) => Delimited::<Crc8>::new().encode(&with_header(header, &self.data)?, buffer),
+            None => Delimited::<Crc8>::new().encode(&self.data, buffer),
         }
+    }
+}
 
-        Ok(Frame {
-            size: size as u8,
-            data: p,
-            crc,
-        })
+/// There's no telling from the wire if a frame has a header, so it's left
+/// in the data. `split_header` moves it out.
+impl Decode<'_> for Frame {
+    type Error = FrameError;
+
+    fn decode(data: &'_ [u8]) -> Result<Self, Self::Error> {
+        decode_frame::<Crc8, 1>(data).map(|(f, _)| f)
+    }
+
+    fn decode_from(data: &'_ [u8]) -> Result<(Self, usize), Self::Error> {
+        decode_frame::<Crc8, 1>(data)
+    }
+}
+
+/// Decode the frame at the front of `buf`, first throwing away anything that
+/// can't be the start of one and afterwards resyncing if it was bad.
+/// `Ok(None)` means the frame isn't all there yet.
+///
+/// If the buffer is full and still doesn't hold the whole frame it never
+/// will, so we drop a byte to resync and hand back `DecodeBufferTooSmall`.
+pub(crate) fn decode_queued<C: FrameCodec, B: ByteQueue>(codec: &C, buf: &mut B) -> Result<Option<Frame>, FrameError> {
+    let junk = codec.skip_to_start(buf.make_contiguous());
+    buf.drain_front(junk);
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let data = buf.make_contiguous();
+    match codec.decode(data) {
+        Ok((f, len)) => {
+            buf.drain_front(len);
+            Ok(Some(f))
+        },
+        Err(e @ FrameError::DecodeBufferTooSmall { .. }) => {
+            if buf.is_full() {
+                buf.drain_front(1);
+                return Err(e);
+            }
+            Ok(None)
+        },
+        Err(e) => {
+            let skip = codec.skip_after_error(data, &e);
+            buf.drain_front(skip);
+            Err(e)
+        },
     }
 }
 
-pub fn recv_frame<Rx: Read>(rx: &mut BufferedRx<Rx>) -> nb::Result<Frame, FrameError> {
+pub fn recv_frame<Rx: Read, B: ByteQueue>(rx: &mut BufferedRx<Rx, B>) -> nb::Result<Frame, FrameError> {
     // Cycle through bytes until we get to the delimiter
     let sl = rx.slice();
     let delim = sl.iter().enumerate().find(|(_, x)| **x == DELIMITER);
     match delim {
         Some((i, _)) => {
-            let _ = rx.read_amt(i);
+            rx.skip(i);
         },
         None => {
             // If we don't find the delimiter drain everything
-            let _ = rx.read_amt(sl.len());
+            rx.skip(sl.len());
         }
     }
-    match Frame::decode(rx.slice()) {
-        Ok(f) => {
+    match Frame::decode_from(rx.slice()) {
+        Ok((f, len)) => {
             // drain the bytes we took
-            let _ = rx.read_amt(f.len());
+            rx.skip(len);
             Ok(f)
         },
         Err(e) => {
@@ -210,20 +526,59 @@ pub fn recv_frame<Rx: Read>(rx: &mut BufferedRx<Rx>) -> nb::Result<Frame, FrameE
     }
 }
 
-pub fn send_frame<Tx: Write>(tx: &mut BufferedTx<Tx>, data: &[u8]) -> Result<(), FrameError> {
+/// Queue `data` on `tx` as a `Delimited` frame, all of it or none of it.
+/// WouldBlock means there isn't room for it yet, same as `FrameTx::send`.
+pub fn send_frame<Tx: Write, B: ByteQueue>(
+    tx: &mut BufferedTx<Tx, B>,
+    data: &[u8],
+) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
     let mut buf = [0; MAX_FRAME_SIZE];
-    let size = data.encode(&mut buf)?;
-    for b in &buf[0..size] {
-        let _ = embedded_hal_nb::serial::Write::write(tx, *b);
-    }
-    Ok(())
+    let size = data.encode(&mut buf).map_err(|e| nb::Error::Other(e.into()))?;
+    tx.write_all(&buf[..size]).map_err(|e| e.map(FrameIOError::Write))
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum FrameIOError<WriteError, ReadError> {
     Frame(FrameError),
     Write(WriteError),
     Read(ReadError),
+    /// The stream ended, e.g. end of file or the other end hung up.
+    /// Only byte stream readers that can tell (`embedded_io`) report it.
+    Eof,
+    /// Bytes came in with the receive buffer full and were thrown away,
+    /// see `Overflow::Error`
+    Overrun,
+    /// A message didn't serialize into a frame, or a frame didn't
+    /// deserialize into the message asked for
+    #[cfg(feature = "serde")]
+    Message(postcard::Error),
+}
+
+/// Widen an error from the sending half of a link to cover both halves
+pub(crate) fn widen_write_error<Ew, Er>(e: FrameIOError<Ew, Infallible>) -> FrameIOError<Ew, Er> {
+    match e {
+        FrameIOError::Frame(e) => FrameIOError::Frame(e),
+        FrameIOError::Write(e) => FrameIOError::Write(e),
+        FrameIOError::Read(i) => match i {},
+        FrameIOError::Eof => FrameIOError::Eof,
+        FrameIOError::Overrun => FrameIOError::Overrun,
+        #[cfg(feature = "serde")]
+        FrameIOError::Message(e) => FrameIOError::Message(e),
+    }
+}
+
+/// Widen an error from the receiving half of a link to cover both halves
+pub(crate) fn widen_read_error<Ew, Er>(e: FrameIOError<Infallible, Er>) -> FrameIOError<Ew, Er> {
+    match e {
+        FrameIOError::Frame(e) => FrameIOError::Frame(e),
+        FrameIOError::Write(i) => match i {},
+        FrameIOError::Read(e) => FrameIOError::Read(e),
+        FrameIOError::Eof => FrameIOError::Eof,
+        FrameIOError::Overrun => FrameIOError::Overrun,
+        #[cfg(feature = "serde")]
+        FrameIOError::Message(e) => FrameIOError::Message(e),
+    }
 }
 
 impl<Ew, Er> From<FrameError> for FrameIOError<Ew, Er> {
@@ -238,36 +593,110 @@ impl<Ew: ErrorType, Er> From<Ew> for FrameIOError<Ew, Er> {
     }
 }
 
-pub struct FrameTxRx<Tx: Write, Rx: Read> {
-    ftx: FrameTx<Tx>,
-    pub frx: FrameRx<Rx>,
+pub struct FrameTxRx<Tx: Write, Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock> {
+    ftx: FrameTx<Tx, C, B>,
+    pub frx: FrameRx<Rx, C, B, K>,
 }
 
 impl<Tx: Write, Rx: Read> FrameTxRx<Tx, Rx> {
     pub fn new(tx: Tx, rx: Rx) -> FrameTxRx<Tx, Rx> {
+        FrameTxRx::with_codec(tx, rx, Delimited::new())
+    }
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec + Clone> FrameTxRx<Tx, Rx, C> {
+    /// Use `codec` for the frames going both ways
+    pub fn with_codec(tx: Tx, rx: Rx, codec: C) -> FrameTxRx<Tx, Rx, C> {
+        FrameTxRx::with_buffers(tx, rx, codec, DefaultBuffer::default(), DefaultBuffer::default())
+    }
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec + Clone, B: ByteQueue> FrameTxRx<Tx, Rx, C, B> {
+    /// Use `codec` for the frames going both ways, buffering into `tx_buf` and `rx_buf`
+    pub fn with_buffers(tx: Tx, rx: Rx, codec: C, tx_buf: B, rx_buf: B) -> FrameTxRx<Tx, Rx, C, B> {
+        FrameTxRx {
+            ftx: FrameTx::with_buffer(tx, codec.clone(), tx_buf),
+            frx: FrameRx::with_buffer(rx, codec, rx_buf),
+        }
+    }
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> FrameTxRx<Tx, Rx, C, B, K> {
+    /// Every frame both ways carries a `FrameHeader`. `header` goes out in
+    /// front of frames sent with plain `send`.
+    pub fn with_header(mut self, header: FrameHeader) -> Self {
+        self.ftx.header = Some(header);
+        self.frx.headers = true;
+        self
+    }
+
+    /// See `FrameRx::with_address`
+    pub fn with_address(mut self, address: u8) -> Self {
+        self.frx = self.frx.with_address(address);
+        self
+    }
+
+    /// See `FrameRx::with_clock`
+    pub fn with_clock<K2: Clock>(self, clock: K2) -> FrameTxRx<Tx, Rx, C, B, K2> {
         FrameTxRx {
-            ftx: FrameTx::new(tx),
-            frx: FrameRx::new(rx),
+            ftx: self.ftx,
+            frx: self.frx.with_clock(clock),
         }
     }
 
-    pub fn split(self) -> (BufferedTx<Tx>, BufferedRx<Rx>) {
+    /// See `FrameRx::with_timeout`
+    pub fn with_timeout<K2: Clock>(self, clock: K2, timeout: u32) -> FrameTxRx<Tx, Rx, C, B, K2> {
+        FrameTxRx {
+            ftx: self.ftx,
+            frx: self.frx.with_timeout(clock, timeout),
+        }
+    }
+
+    /// The receiver's clock, which whatever's built on the link keeps time by
+    pub fn now(&self) -> u32 {
+        self.frx.clock.now()
+    }
+
+    pub fn split(self) -> (BufferedTx<Tx, B>, BufferedRx<Rx, B>) {
         (self.ftx.tx, self.frx.rx)
     }
+
+    /// Snapshot of both directions' counts together
+    pub fn stats(&self) -> LinkStats {
+        self.frx.stats().merge(&self.ftx.stats())
+    }
+
+    /// Snapshot of both directions' counts, starting them over from zero
+    pub fn take_stats(&mut self) -> LinkStats {
+        self.frx.take_stats().merge(&self.ftx.take_stats())
+    }
+
+    /// See `FrameRx::recv_ref`
+    pub fn recv_ref(&mut self) -> nb::Result<BorrowedFrame<'_, Rx, B>, FrameIOError<Infallible, Rx::Error>> {
+        self.frx.recv_ref()
+    }
 }
 
-impl<Tx: Write, Rx: Read> FrameSend<Tx> for FrameTxRx<Tx, Rx> {
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> FrameSend<Tx> for FrameTxRx<Tx, Rx, C, B, K> {
     fn flush(&mut self) -> nb::Result<(), <Tx>::Error> {
         self.ftx.flush()
     }
 
-    fn send(&mut self, data: &[u8]) -> Result<(), FrameIOError<<Tx>::Error, Infallible>> {
+    fn send(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<<Tx>::Error, Infallible>> {
         self.ftx.send(data)
     }
+
+    fn max_payload(&self) -> usize {
+        self.ftx.max_payload()
+    }
+
+    fn send_with_header(&mut self, header: FrameHeader, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        self.ftx.send_with_header(header, data)
+    }
 }
 
-impl<Tx: Write, Rx: Read> FrameRecv<Rx> for FrameTxRx<Tx, Rx> {
-    fn buffer(&mut self) -> nb::Result<(), <Rx>::Error> {
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> FrameRecv<Rx> for FrameTxRx<Tx, Rx, C, B, K> {
+    fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, <Rx>::Error>> {
         self.frx.buffer()
     }
 
@@ -277,7 +706,7 @@ impl<Tx: Write, Rx: Read> FrameRecv<Rx> for FrameTxRx<Tx, Rx> {
 }
 
 pub trait FrameRecv<Rx: Read> {
-    fn buffer(&mut self) -> nb::Result<(), Rx::Error>;
+    fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>>;
 
     fn recv(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, Rx::Error>>;
 }
@@ -285,108 +714,740 @@ pub trait FrameRecv<Rx: Read> {
 pub trait FrameSend<Tx: Write> {
     fn flush(&mut self) -> nb::Result<(), Tx::Error>;
 
-    fn send(&mut self, data: &[u8]) -> Result<(), FrameIOError<Tx::Error, Infallible>>;
+    /// Queue `data` up as a frame and start sending it. The whole frame is
+    /// queued or none of it is, WouldBlock means there isn't room for it yet.
+    fn send(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>>;
+
+    /// Most data one `send` takes, less whatever the sender puts in front
+    /// of it, e.g. a `FrameHeader`
+    fn max_payload(&self) -> usize;
+
+    /// Same as `send` with `header` in front of the data, see `FrameHeader`
+    fn send_with_header(&mut self, header: FrameHeader, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        self.send(&with_header(header, data).map_err(FrameIOError::from)?)
+    }
 }
 
-pub struct FrameRx<Rx: Read> {
-    pub rx: BufferedRx<Rx>,
+pub struct FrameRx<Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock> {
+    pub rx: BufferedRx<Rx, B>,
+    pub codec: C,
+    pub clock: K,
+    /// Ticks without a byte before a partial frame is given up on
+    pub timeout: Option<u32>,
+    /// When bytes last came in
+    last_rx_at: u32,
+    /// Skip addressed frames that aren't for this address or broadcast,
+    /// see `bus::Addressed`. Needs `headers`.
+    pub address: Option<u8>,
+    /// Every frame starts with a `FrameHeader`, which gets moved into
+    /// `Frame::header`
+    pub headers: bool,
+    stats: LinkStats,
 }
 
 impl<Rx: Read> FrameRx<Rx> {
     pub fn new(rx: Rx) -> FrameRx<Rx> {
-        FrameRx { rx: BufferedRx::new(rx) }
+        FrameRx::with_codec(rx, Delimited::new())
     }
 }
-    
 
-impl<Rx: Read> FrameRecv<Rx> for FrameRx<Rx> {
+impl<Rx: Read, C: FrameCodec> FrameRx<Rx, C> {
+    /// Without `alloc` the default buffer has room for `DEFAULT_BUFFER_SIZE`
+    /// bytes, a couple of `Delimited` frames. Bigger `Extended` frames need
+    /// `with_buffer` and a Deque of twice the size they can get to.
+    pub fn with_codec(rx: Rx, codec: C) -> FrameRx<Rx, C> {
+        FrameRx::with_buffer(rx, codec, DefaultBuffer::default())
+    }
+}
 
-    /// Read as much as we can out of the underlying Read until
-    /// we WouldBlock or Error
-    /// TODO no accounting for just a flood of input on Rx and
-    /// we end up blocking by accident anyway.
-    fn buffer(&mut self) -> nb::Result<(), <Rx>::Error> {
-        self.rx.buffer()
+impl<Rx: Read, C: FrameCodec, B: ByteQueue> FrameRx<Rx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits.
+    /// It holds at most two of the codec's biggest frames, or less if that's
+    /// all `buf` has room for.
+    pub fn with_buffer(rx: Rx, codec: C, buf: B) -> FrameRx<Rx, C, B> {
+        let room = buf.len().saturating_add(buf.free());
+        let mut rx = BufferedRx::with_buffer(rx, buf);
+        rx.capacity = (2 * C::MAX_FRAME_SIZE).min(room);
+        FrameRx {
+            rx,
+            codec,
+            clock: NoClock,
+            timeout: None,
+            last_rx_at: 0,
+            address: None,
+            headers: false,
+            stats: LinkStats::default(),
+        }
     }
+}
 
-    fn recv(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, <Rx>::Error>> {
-        
+impl<Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> FrameRx<Rx, C, B, K> {
+    /// Give up on a partial frame once `timeout` ticks of `clock` go by
+    /// without another byte, so a frame whose tail never arrives doesn't
+    /// hold up the ones behind it. `recv` reports `FrameError::Timeout` and
+    /// resyncs on the next frame.
+    pub fn with_timeout<K2: Clock>(self, clock: K2, timeout: u32) -> FrameRx<Rx, C, B, K2> {
+        let mut frx = self.with_clock(clock);
+        frx.timeout = Some(timeout);
+        frx
+    }
+
+    /// Keep time with `clock`, without giving up on partial frames unless
+    /// `timeout` is set as well. Anything built on the link that times out
+    /// (`ReliableLink`, `RpcClient`, ...) reads the time from it.
+    pub fn with_clock<K2: Clock>(self, clock: K2) -> FrameRx<Rx, C, B, K2> {
+        let last_rx_at = clock.now();
+        FrameRx {
+            rx: self.rx,
+            codec: self.codec,
+            clock,
+            timeout: self.timeout,
+            last_rx_at,
+            address: self.address,
+            headers: self.headers,
+            stats: self.stats,
+        }
+    }
+
+    /// Skip over addressed frames that aren't for `address` or broadcast,
+    /// for a drop on a multi-drop bus. Turns on `headers`, it's the header
+    /// that says whether a frame's addressed, see `bus::Addressed`.
+    pub fn with_address(mut self, address: u8) -> Self {
+        self.address = Some(address);
+        self.headers = true;
+        self
+    }
+
+    /// Every frame starts with a `FrameHeader`, see `headers`
+    pub fn with_headers(mut self) -> Self {
+        self.headers = true;
+        self
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Snapshot of the counts, starting them over from zero
+    pub fn take_stats(&mut self) -> LinkStats {
+        core::mem::take(&mut self.stats)
+    }
+
+    /// Throw away `amount` bytes from the front of the buffer, counting them
+    fn discard(&mut self, amount: usize) {
+        let amount = amount.min(self.rx.buf.len());
+        count(&mut self.stats.discarded, amount);
+        self.rx.skip(amount);
+    }
+
+    /// Count a bad frame, after throwing away `skip` bytes of it to resync
+    fn bad_frame(&mut self, e: FrameError, skip: usize) -> nb::Error<FrameIOError<Infallible, Rx::Error>> {
+        self.discard(skip);
+        self.stats.frame_error(&e);
+        nb::Error::Other(FrameIOError::Frame(e))
+    }
+
+    /// Buffer whatever's come in, noting when it did
+    fn fill(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        let (len, dropped) = (self.rx.buf.len(), self.rx.dropped());
+        let result = self.rx.buffer();
+        let dropped = self.rx.dropped().wrapping_sub(dropped);
+        // Bytes either stayed in the buffer or were dropped for want of room
+        let read = (self.rx.buf.len() + dropped).saturating_sub(len);
+        if read > 0 {
+            self.last_rx_at = self.clock.now();
+        }
+        count(&mut self.stats.bytes_received, read);
+        count(&mut self.stats.overruns, dropped);
+        result
+    }
+
+    /// The frame at the front of the buffer isn't all here yet. If nothing
+    /// more turns up in time, throw it away.
+    fn incomplete(&mut self) -> nb::Error<FrameIOError<Infallible, Rx::Error>> {
+        let Some(timeout) = self.timeout else {
+            return nb::Error::WouldBlock;
+        };
+        // There might be more sitting in rx that nobody's buffered yet
+        let waiting = self.rx.buf.len();
+        if let Err(e) = self.fill() {
+            return e;
+        }
+        if self.rx.buf.len() != waiting || self.clock.now().wrapping_sub(self.last_rx_at) < timeout {
+            return nb::Error::WouldBlock;
+        }
+        // The partial frame is everything in the buffer, anything after it
+        // would have finished or broken it
+        self.bad_frame(FrameError::Timeout, waiting)
+    }
+
+    /// Toss anything at the front of the buffer that can't be the start of a frame
+    fn sync(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
         loop {
-            if let Some(DELIMITER) = self.rx.peek() {
-                break;
+            let junk = self.codec.skip_to_start(self.rx.slice());
+            self.discard(junk);
+            if !self.rx.slice().is_empty() {
+                return Ok(());
             }
+            // Nothing left, so see if Rx has any more for us.
             // If we block or error, then return
-            if let Err(e) = self.rx.read() {
-                match e {
-                    nb::Error::WouldBlock => return Err(nb::Error::WouldBlock),
-                    nb::Error::Other(ee) => return Err(nb::Error::Other(FrameIOError::Read(ee))),
-                }
+            self.fill()?;
+            if self.rx.slice().is_empty() {
+                return Err(nb::Error::WouldBlock);
             }
         }
-        // At this point we just have to try and make a frame from the buffer.
-        // If we can make a frame, then we have one.
-        let buf = self.rx.slice();
-        match Frame::decode(buf) {
-            Ok(f) => {
-                self.rx.drain(f.len());
-                return Ok(f)
-            },
-            Err(FrameError::EarlyEndDelim { found_at, expected }) => {
-                // If we think we're on a frame, then the current next read will be the
-                // Frame delimiter. We should pop this so the next time we `recv` we'll
-                // toss the bytes until the next Delimiter
-                let _ = self.rx.rx.read();
-                return Err(nb::Error::Other(FrameIOError::Frame(FrameError::EarlyEndDelim { found_at, expected })))
-            },
-            Err(FrameError::CrcMismatch { calculated, found, buf }) => {
-                // If we think we're on a frame, then the current next read will be the
-                // Frame delimiter. We should pop this so the next time we `recv` we'll
-                // toss the bytes until the next Delimiter
-                let _ = self.rx.rx.read();
-                return Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { calculated, found, buf })))
-            },
-            Err(FrameError::MissingEndDelim { index, found }) => {
-                // If we think we're on a frame, then the current next read will be the
-                // Frame delimiter. We should pop this so the next time we `recv` we'll
-                // toss the bytes until the next Delimiter
-                let _ = self.rx.rx.read();
-                return Err(nb::Error::Other(FrameIOError::Frame(FrameError::MissingEndDelim { index, found })))
-            },
-            Err(FrameError::DecodeBufferTooSmall { expected_at_least: _, found: _ }) => {
-                return Err(nb::Error::WouldBlock)
-            },
-            Err(e) => {
-                // Other errors are essentially due to not having enough data
-                return Err(nb::Error::Other(FrameIOError::Frame(e)))
-            },
+    }
+
+    /// Like `recv` but the frame's data is decoded in place and borrowed
+    /// straight out of the rx buffer instead of being copied out.
+    /// The frame's bytes are only drained from the buffer once the
+    /// returned BorrowedFrame is dropped.
+    pub fn recv_ref(&mut self) -> nb::Result<BorrowedFrame<'_, Rx, B>, FrameIOError<Infallible, Rx::Error>> {
+        loop {
+            self.sync()?;
+            match self.codec.decode_in_place(self.rx.slice_mut()) {
+                Ok((f, len)) => {
+                    // Too short to have a header is as bad as any other mangled frame
+                    let f = if self.headers {
+                        match f.split_header() {
+                            Ok(f) => f,
+                            Err(e) => {
+                                self.rx.skip(len);
+                                return Err(self.bad_frame(e, 0));
+                            },
+                        }
+                    } else {
+                        f
+                    };
+                    let (size, header, data_len, crc) = (f.size, f.header, f.data.len(), f.crc);
+                    if !for_us(header, f.data, self.address) {
+                        self.rx.skip(len);
+                        count(&mut self.stats.filtered, 1);
+                        continue;
+                    }
+                    count(&mut self.stats.frames_received, 1);
+                    let data_start = if header.is_some() { FRAME_HEADER_LEN } else { 0 };
+                    return Ok(BorrowedFrame { rx: &mut self.rx, size, header, data_start, data_len, crc, len });
+                },
+                Err(FrameError::DecodeBufferTooSmall { expected_at_least: _, found: _ }) => return Err(self.incomplete()),
+                Err(e) => {
+                    // decode_in_place leaves the buffer alone on error so we can
+                    // resync the same way `recv` does
+                    let skip = self.codec.skip_after_error(self.rx.slice(), &e);
+                    return Err(self.bad_frame(e, skip));
+                },
+            }
+        }
+    }
+}
+
+/// A frame borrowed out of a `BufferedRx` by `FrameRx::recv_ref`.
+/// The frame's bytes stay in the buffer until this is dropped.
+pub struct BorrowedFrame<'a, Rx: Read, B: ByteQueue = DefaultBuffer> {
+    rx: &'a mut BufferedRx<Rx, B>,
+    size: u16,
+    header: Option<FrameHeader>,
+    /// Where the data is at the front of the buffer, after any header
+    data_start: usize,
+    data_len: usize,
+    crc: u32,
+    /// Bytes the encoded frame took up in the buffer
+    len: usize,
+}
+
+impl<Rx: Read, B: ByteQueue> BorrowedFrame<'_, Rx, B> {
+    pub fn frame(&self) -> FrameRef<'_> {
+        FrameRef {
+            size: self.size,
+            header: self.header,
+            data: self.data(),
+            crc: self.crc,
+        }
+    }
+
+    /// The decoded data, which the codec left at the front of the buffer
+    pub fn data(&self) -> &[u8] {
+        &self.rx.slice()[self.data_start..self.data_start + self.data_len]
+    }
+}
+
+impl<Rx: Read, B: ByteQueue> Drop for BorrowedFrame<'_, Rx, B> {
+    fn drop(&mut self) {
+        self.rx.skip(self.len);
+    }
+}
+
+impl<Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> FrameRecv<Rx> for FrameRx<Rx, C, B, K> {
+
+    /// Read as much as we can out of the underlying Read until we WouldBlock,
+    /// Error, or hit the BufferedRx's `budget`
+    fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, <Rx>::Error>> {
+        self.fill()
+    }
+
+    fn recv(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, <Rx>::Error>> {
+        loop {
+            self.sync()?;
+            // At this point we just have to try and make a frame from the buffer.
+            // If we can make a frame, then we have one.
+            let buf = self.rx.slice();
+            match self.codec.decode(buf) {
+                Ok((f, len)) => {
+                    self.rx.skip(len);
+                    let f = if self.headers {
+                        f.split_header().map_err(|e| self.bad_frame(e, 0))?
+                    } else {
+                        f
+                    };
+                    if !for_us(f.header, &f.data, self.address) {
+                        count(&mut self.stats.filtered, 1);
+                        continue;
+                    }
+                    count(&mut self.stats.frames_received, 1);
+                    return Ok(f);
+                },
+                Err(FrameError::DecodeBufferTooSmall { expected_at_least: _, found: _ }) => return Err(self.incomplete()),
+                Err(e) => {
+                    // Let the codec decide how much of the bad frame to toss so
+                    // the next `recv` resyncs
+                    let skip = self.codec.skip_after_error(buf, &e);
+                    return Err(self.bad_frame(e, skip));
+                },
+            }
         }
     }
 }
 
-pub struct FrameTx<Tx: Write> {
-    pub tx: BufferedTx<Tx>
+pub struct FrameTx<Tx: Write, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer> {
+    pub tx: BufferedTx<Tx, B>,
+    pub codec: C,
+    /// On links where every frame has a `FrameHeader`, the one that goes
+    /// out with plain `send`
+    pub header: Option<FrameHeader>,
+    stats: LinkStats,
 }
 
 impl<Tx: Write> FrameTx<Tx> {
     pub fn new(tx: Tx) -> FrameTx<Tx> {
-        FrameTx { tx: BufferedTx::new(tx) }
+        FrameTx::with_codec(tx, Delimited::new())
+    }
+}
+
+impl<Tx: Write, C: FrameCodec> FrameTx<Tx, C> {
+    /// Without `alloc` the default buffer has room for `DEFAULT_BUFFER_SIZE`
+    /// bytes, a couple of `Delimited` frames. Bigger `Extended` frames need
+    /// `with_buffer` and a Deque of twice the size they can get to.
+    pub fn with_codec(tx: Tx, codec: C) -> FrameTx<Tx, C> {
+        FrameTx::with_buffer(tx, codec, DefaultBuffer::default())
+    }
+}
+
+impl<Tx: Write, C: FrameCodec, B: ByteQueue> FrameTx<Tx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits.
+    /// It queues at most two of the codec's biggest frames, or less if that's
+    /// all `buf` has room for.
+    pub fn with_buffer(tx: Tx, codec: C, buf: B) -> FrameTx<Tx, C, B> {
+        let room = buf.len().saturating_add(buf.free());
+        let mut tx = BufferedTx::with_buffer(tx, buf);
+        tx.capacity = (2 * C::MAX_FRAME_SIZE).min(room);
+        FrameTx { tx, codec, header: None, stats: LinkStats::default() }
+    }
+}
+
+impl<Tx: Write, C: FrameCodec, B: ByteQueue> FrameTx<Tx, C, B> {
+    /// Send `header` in front of frames sent with plain `send`, see `header`
+    pub fn with_header(mut self, header: FrameHeader) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Snapshot of the counts, starting them over from zero
+    pub fn take_stats(&mut self) -> LinkStats {
+        core::mem::take(&mut self.stats)
     }
 }
 
-impl<Tx: Write> FrameSend<Tx> for FrameTx<Tx> {
+impl<Tx: Write, C: FrameCodec, B: ByteQueue> FrameSend<Tx> for FrameTx<Tx, C, B> {
     fn flush(&mut self) -> nb::Result<(), <Tx>::Error> {
-        embedded_hal_nb::serial::Write::flush(&mut self.tx)
+        let result = embedded_hal_nb::serial::Write::flush(&mut self.tx);
+        if let Err(nb::Error::Other(_)) = result {
+            count(&mut self.stats.write_errors, 1);
+        }
+        result
+    }
+
+    /// `EncodeBufferTooSmall` means the frame is bigger than the whole
+    /// queue, so there'll never be room for it.
+    fn send(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        match self.header {
+            Some(header) => self.send_with_header(header, data),
+            None => self.queue(data),
+        }
     }
 
-    fn send(&mut self, data: &[u8]) -> Result<(), FrameIOError<Tx::Error, Infallible>> {
-        // We can send the whole encoded frame into Tx. Tx is Buffered so
-        // Even if it doesn't all go down the wire right away it'll at least
-        // be in the buffer and will eventually flush.
+    fn max_payload(&self) -> usize {
+        match self.header {
+            Some(_) => frame_data_limit(C::MAX_DATA_SIZE) - FRAME_HEADER_LEN,
+            None => C::MAX_DATA_SIZE,
+        }
+    }
+
+    fn send_with_header(&mut self, header: FrameHeader, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        check_payload(data.len(), C::MAX_DATA_SIZE - FRAME_HEADER_LEN).map_err(FrameIOError::from)?;
+        self.queue(&with_header(header, data).map_err(FrameIOError::from)?)
+    }
+}
+
+impl<Tx: Write, C: FrameCodec, B: ByteQueue> FrameTx<Tx, C, B> {
+    /// Queue `data` as a frame as is, see `send`
+    fn queue(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let required = self.codec.encoded_len(data).map_err(|e| nb::Error::Other(e.into()))?;
+        if required > self.tx.free() {
+            // Make some room if the wire will take it
+            match FrameSend::flush(self) {
+                Ok(()) | Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(FrameIOError::Write(e))),
+            }
+        }
+        if required > self.tx.free() {
+            let capacity = self.tx.buf.len().saturating_add(self.tx.free());
+            if required > capacity {
+                return Err(nb::Error::Other(
+                    FrameError::EncodeBufferTooSmall { expected_at_least: required, found: capacity }.into(),
+                ));
+            }
+            return Err(nb::Error::WouldBlock);
+        }
+        // We can encode the frame straight into the queue, so there's no
+        // scratch buffer to outgrow. Even if it doesn't all go down the
+        // wire right away it's queued and will eventually flush.
+        let buf = &mut self.tx.buf;
+        self.codec
+            .encode_with(data, |b| {
+                let _ = buf.push_back(b);
+            })
+            .map_err(|e| nb::Error::Other(e.into()))?;
+        count(&mut self.stats.frames_sent, 1);
+        count(&mut self.stats.bytes_sent, required);
+        match FrameSend::flush(self) {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(FrameIOError::Write(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::SendTo,
+        header::{u4, u6},
+        mock::{Ticks, Wire, link},
+    };
+
+    /// Every byte that has to be escaped, plus what they turn into
+    const AWKWARD: [u8; 6] = [DELIMITER, END_DELIM, ESCAPE, DELIMITER ^ ESCAPE_XOR, END_DELIM ^ ESCAPE_XOR, 0x00];
+
+    fn encoded(data: &[u8]) -> ([u8; MAX_FRAME_SIZE], usize) {
         let mut buf = [0; MAX_FRAME_SIZE];
-        let size = data.encode(&mut buf).map_err(FrameIOError::from)?;
-        match IoWrite::write(&mut self.tx, &buf[0..size]) {
-            Ok(_) => Ok(()),
-            Err(ErrorShim(e)) => Err(FrameIOError::Write(e)),
+        let len = data.encode(&mut buf).unwrap();
+        (buf, len)
+    }
+
+    #[test]
+    fn stuffs_delimiters_and_escapes() {
+        let (buf, len) = encoded(&[DELIMITER, END_DELIM, ESCAPE]);
+        let frame = &buf[..len];
+        assert_eq!(frame[0], DELIMITER);
+        assert_eq!(frame[len - 1], END_DELIM);
+        assert_eq!(
+            frame[2..8],
+            [ESCAPE, DELIMITER ^ ESCAPE_XOR, ESCAPE, END_DELIM ^ ESCAPE_XOR, ESCAPE, ESCAPE ^ ESCAPE_XOR]
+        );
+        // Nothing between the delimiters looks like one
+        assert!(!frame[1..len - 1].iter().any(|b| *b == DELIMITER || *b == END_DELIM));
+    }
+
+    #[test]
+    fn round_trips_awkward_payloads() {
+        let size_needs_escape = [0; DELIMITER as usize];
+        for data in [&AWKWARD[..], &[], &[DELIMITER; 16], &[ESCAPE; 16], &size_needs_escape] {
+            let (buf, len) = encoded(data);
+            let (frame, used) = Frame::decode_from(&buf[..len]).unwrap();
+            assert_eq!(frame.data[..], *data);
+            assert_eq!(frame.size as usize, data.len());
+            assert_eq!(used, len);
+        }
+    }
+
+    #[test]
+    fn worst_case_fits_max_frame_size() {
+        let (buf, len) = encoded(&[DELIMITER; MAX_DATA_SIZE]);
+        assert!(len <= MAX_FRAME_SIZE);
+        assert_eq!(Frame::decode(&buf[..len]).unwrap().data[..], [DELIMITER; MAX_DATA_SIZE]);
+    }
+
+    #[test]
+    fn escape_before_delimiter_is_an_error() {
+        let (mut buf, len) = encoded(&[1, 2, 3]);
+        buf[2] = ESCAPE;
+        buf[3] = END_DELIM;
+        assert!(matches!(Frame::decode(&buf[..len]), Err(FrameError::EarlyEndDelim { found_at: 3, .. })));
+    }
+
+    #[test]
+    fn recv_payloads_full_of_delimiters() {
+        let (mut a, mut b) = link();
+        a.send(&AWKWARD).unwrap();
+        a.send(b"second").unwrap();
+        assert_eq!(b.recv().unwrap().data[..], AWKWARD);
+        assert_eq!(b.recv().unwrap().data[..], *b"second");
+        assert!(matches!(b.recv(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn recv_ref_borrows_until_dropped() {
+        let wire = Wire::new();
+        let mut rx = FrameRx::new(wire.clone());
+        let (buf, len) = encoded(&AWKWARD);
+        wire.push(&buf[..len]);
+        wire.push(&buf[..len]);
+        {
+            let frame = rx.recv_ref().unwrap();
+            assert_eq!(frame.data(), AWKWARD);
+            assert_eq!(frame.frame().size as usize, AWKWARD.len());
+        }
+        // Only the first frame's bytes went with it
+        assert_eq!(rx.rx.slice(), &buf[..len]);
+        assert_eq!(rx.recv().unwrap().data[..], AWKWARD);
+        assert!(rx.rx.slice().is_empty());
+    }
+
+    #[test]
+    fn decode_in_place_leaves_bad_frames_alone() {
+        let (mut buf, len) = encoded(&AWKWARD);
+        buf[len - 2] ^= 0x01;
+        let before = buf;
+        assert!(matches!(
+            Delimited::<Crc8>::new().decode_in_place(&mut buf[..len]),
+            Err(FrameError::CrcMismatch { .. })
+        ));
+        assert_eq!(buf, before);
+    }
+
+    #[test]
+    fn prefixed_frame_data() {
+        assert_eq!(frame_data_with(&[1, 2], b"ab").unwrap()[..], [1, 2, b'a', b'b']);
+        #[cfg(not(feature = "alloc"))]
+        assert!(matches!(
+            frame_data_with(&[1, 2], &[0; MAX_DATA_SIZE - 1]),
+            Err(FrameError::PayloadTooLarge { size, max }) if size == MAX_DATA_SIZE - 1 && max == MAX_DATA_SIZE - 2
+        ));
+    }
+
+    #[test]
+    fn cut_short_frame_resyncs_on_next_start() {
+        let wire = Wire::new();
+        let mut rx = FrameRx::new(wire.clone());
+        let (buf, len) = encoded(&AWKWARD);
+        wire.push(&[0x01, 0x02]);
+        wire.push(&buf[..len - 3]);
+        wire.push(&buf[..len]);
+        assert!(matches!(
+            rx.recv(),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::EarlyStartDelim { .. })))
+        ));
+        assert_eq!(rx.recv().unwrap().data[..], AWKWARD);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn stats_count_the_link() {
+        let (mut a, mut b) = link();
+        let (buf, len) = encoded(b"ok");
+        a.send(b"ok").unwrap();
+        // Junk before a frame, then a frame with a bad CRC
+        a.ftx.tx.tx.push(&[0x01, 0x02]);
+        let mut bad = buf;
+        bad[len - 2] ^= 0x01;
+        a.ftx.tx.tx.push(&bad[..len]);
+        assert_eq!(b.recv().unwrap().data[..], *b"ok");
+        assert!(matches!(b.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        assert!(matches!(b.recv(), Err(nb::Error::WouldBlock)));
+
+        let stats = b.stats();
+        assert_eq!((stats.frames_received, stats.crc_errors), (1, 1));
+        assert_eq!(stats.bytes_received as usize, 2 * len + 2);
+        assert_eq!(stats.discarded as usize, 2 + len);
+        assert_eq!((a.stats().frames_sent, a.stats().bytes_sent as usize), (1, len));
+
+        // Taking them starts the counts over
+        assert_eq!(b.take_stats(), stats);
+        assert_eq!(b.stats(), LinkStats::default());
+    }
+
+    #[test]
+    fn stats_count_frames_for_someone_else() {
+        let (mut a, b) = link();
+        let mut b = b.with_address(2);
+        a.send_to(1, 3, b"not you").unwrap();
+        a.send_to(1, 2, b"you").unwrap();
+        assert_eq!(b.recv().unwrap().data[..], *b"\x02\x01you");
+        // Frames that aren't addressed aren't filtered
+        a.send_with_header(FrameHeader::for_channel(u6::new(0), u4::new(0)), b"anyone").unwrap();
+        assert_eq!(b.recv().unwrap().data[..], *b"anyone");
+        assert_eq!((b.stats().filtered, b.stats().frames_received), (1, 2));
+    }
+
+    #[test]
+    fn partial_frames_time_out_by_the_clock() {
+        let wire = Wire::new();
+        let clock = Ticks::new();
+        let mut rx = FrameRx::new(wire.clone()).with_timeout(clock.clone(), 5);
+        let (buf, len) = encoded(b"late");
+        clock.set(10);
+        wire.push(&buf[..3]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+        // Five ticks from the last byte in, not the first
+        clock.set(13);
+        wire.push(&buf[3..4]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+        clock.set(17);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+        clock.set(18);
+        assert!(matches!(rx.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::Timeout)))));
+        assert_eq!((rx.stats().timeouts, rx.stats().discarded), (1, 4));
+
+        // The next frame's not held up by what's left of the last one
+        wire.push(&buf[4..len]);
+        wire.push(&buf[..len]);
+        assert_eq!(rx.recv().unwrap().data[..], *b"late");
+    }
+
+    #[test]
+    fn no_timeout_waits_forever() {
+        let wire = Wire::new();
+        let mut rx = FrameRx::new(wire.clone());
+        let (buf, len) = encoded(b"slow");
+        wire.push(&buf[..3]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+        let _ = rx.buffer();
+        wire.push(&buf[3..len]);
+        let _ = rx.buffer();
+        assert_eq!(rx.recv().unwrap().data[..], *b"slow");
+    }
+
+    /// A serial port that's been unplugged
+    struct Unplugged;
+
+    impl embedded_hal_nb::serial::ErrorType for Unplugged {
+        type Error = embedded_hal_nb::serial::ErrorKind;
+    }
+
+    impl Write for Unplugged {
+        fn write(&mut self, _word: u8) -> nb::Result<(), Self::Error> {
+            Err(nb::Error::Other(embedded_hal_nb::serial::ErrorKind::Other))
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Err(nb::Error::Other(embedded_hal_nb::serial::ErrorKind::Other))
         }
     }
+
+    #[test]
+    fn stats_count_write_errors() {
+        let mut tx = FrameTx::new(Unplugged);
+        assert!(matches!(tx.send(b"lost"), Err(nb::Error::Other(FrameIOError::Write(_)))));
+        assert!(tx.flush().is_err());
+        assert_eq!(tx.stats().write_errors, 2);
+    }
+
+    /// A FrameTx that queues at most `capacity` bytes, onto a wire that
+    /// takes `room` before it blocks
+    fn throttled(capacity: usize, room: usize) -> (FrameTx<Wire>, Wire) {
+        let wire = Wire::new();
+        wire.set_room(room);
+        let mut tx = FrameTx::new(wire.clone());
+        tx.tx.capacity = capacity;
+        (tx, wire)
+    }
+
+    #[test]
+    fn full_queue_blocks_whole_frames() {
+        // Each 4 byte frame is 8 on the wire
+        let (mut tx, wire) = throttled(12, 4);
+        tx.send(b"abcd").unwrap();
+        assert_eq!((wire.len(), tx.tx.buf.len()), (4, 4));
+        // Room for 8 more in the queue, but not the 8 + 8 of two frames
+        tx.send(b"efgh").unwrap();
+        assert!(matches!(tx.send(b"ijkl"), Err(nb::Error::WouldBlock)));
+        // Nothing of the blocked frame went in
+        assert_eq!(tx.tx.buf.len(), 12);
+        assert_eq!(tx.stats().frames_sent, 2);
+
+        // Once the wire's drained the frames come out whole and in order
+        let mut rx = FrameRx::new(wire.clone());
+        let mut got = heapless::Vec::<FrameData, 3>::new();
+        let mut sent = false;
+        while !(sent && tx.flush().is_ok() && wire.is_empty()) {
+            let _ = rx.buffer();
+            if let Ok(f) = rx.recv() {
+                got.push(f.data).unwrap();
+            }
+            if !sent {
+                sent = tx.send(b"ijkl").is_ok();
+            }
+        }
+        got.extend(rx.recv().map(|f| f.data));
+        assert!(got.iter().map(|d| &d[..]).eq([&b"abcd"[..], b"efgh", b"ijkl"]));
+    }
+
+    #[test]
+    fn frames_bigger_than_the_queue_never_fit() {
+        let (mut tx, wire) = throttled(6, 0);
+        assert!(matches!(
+            tx.send(b"abcd"),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::EncodeBufferTooSmall { expected_at_least: 8, found: 6 })))
+        ));
+        assert!(tx.tx.buf.is_empty());
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn send_frame_queues_whole_frames() {
+        let wire = Wire::new();
+        wire.set_room(0);
+        let mut tx = BufferedTx::new(wire.clone());
+        tx.capacity = 12;
+        send_frame(&mut tx, b"abcd").unwrap();
+        assert!(matches!(send_frame(&mut tx, b"efgh"), Err(nb::Error::WouldBlock)));
+        assert_eq!(tx.buf.len(), 8);
+        wire.set_room(usize::MAX);
+        send_frame(&mut tx, b"efgh").unwrap();
+
+        let mut rx = FrameRx::new(wire.clone());
+        assert_eq!(rx.recv().unwrap().data[..], *b"abcd");
+        assert_eq!(rx.recv().unwrap().data[..], *b"efgh");
+    }
+
+    #[test]
+    fn buffers_hold_what_they_have_room_for() {
+        type Big = crate::codec::Extended<Crc8>;
+        let rx = FrameRx::with_buffer(Wire::new(), Big::new(), heapless::Deque::<u8, 64>::new());
+        let tx = FrameTx::with_buffer(Wire::new(), Big::new(), heapless::Deque::<u8, 64>::new());
+        assert_eq!((rx.rx.capacity, tx.tx.capacity), (64, 64));
+
+        let rx = FrameRx::with_codec(Wire::new(), Big::new());
+        let tx = FrameTx::with_codec(Wire::new(), Big::new());
+        #[cfg(feature = "alloc")]
+        let expected = 2 * Big::MAX_FRAME_SIZE;
+        // Nowhere near two Extended frames
+        #[cfg(not(feature = "alloc"))]
+        let expected = crate::serial::DEFAULT_BUFFER_SIZE;
+        assert_eq!((rx.rx.capacity, tx.tx.capacity), (expected, expected));
+    }
 }
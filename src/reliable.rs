@@ -0,0 +1,380 @@
+use core::convert::Infallible;
+
+use embedded_hal_nb::serial::{Read, Write};
+
+use crate::{
+    clock::{Clock, NoClock},
+    codec::{Delimited, FrameCodec},
+    packet::{
+        Frame, FrameData, FrameError, FrameIOError, FrameRecv, FrameSend, FrameTxRx, check_payload,
+        frame_data, frame_data_with, widen_read_error, widen_write_error,
+    },
+    serial::{ByteQueue, DefaultBuffer},
+};
+
+/// Kind byte of a frame carrying data
+pub const KIND_DATA: u8 = 0x00;
+/// Kind byte acknowledging the data frame with the same sequence number
+pub const KIND_ACK: u8 = 0x01;
+/// Kind byte asking for the frame in flight again, it came in corrupted
+pub const KIND_NAK: u8 = 0x02;
+/// Kind and sequence number
+pub const HEADER_LEN: usize = 2;
+
+/// Something `poll` has to report
+#[derive(Debug)]
+// Without alloc the data is inline, same as a Frame, and there's no Box to put it in
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    /// Data from the other end, header stripped off. Duplicates are never reported.
+    Received(FrameData),
+    /// The other end has the frame sent with this sequence number
+    Delivered(u8),
+}
+
+#[derive(Debug)]
+pub enum ReliableError<WriteError, ReadError> {
+    Link(FrameIOError<WriteError, ReadError>),
+    /// The frame with sequence number `seq` went unacknowledged after
+    /// `max_retries` retransmissions and has been dropped
+    GaveUp { seq: u8 },
+}
+
+impl<Ew, Er> From<FrameError> for ReliableError<Ew, Er> {
+    fn from(value: FrameError) -> Self {
+        ReliableError::Link(FrameIOError::Frame(value))
+    }
+}
+
+/// Widen an error from either half of the link to cover both
+fn write_error<Ew, Er>(e: FrameIOError<Ew, Infallible>) -> ReliableError<Ew, Er> {
+    ReliableError::Link(widen_write_error(e))
+}
+
+fn read_error<Ew, Er>(e: FrameIOError<Infallible, Er>) -> ReliableError<Ew, Er> {
+    ReliableError::Link(widen_read_error(e))
+}
+
+/// Header then data as one FrameData, ready to go out as a frame
+fn packet(kind: u8, seq: u8, data: &[u8]) -> Result<FrameData, FrameError> {
+    frame_data_with(&[kind, seq], data)
+}
+
+/// The data frame waiting on an ACK
+struct InFlight {
+    packet: FrameData,
+    sent_at: u32,
+    retries: u8,
+}
+
+/// Reliable delivery over a `FrameTxRx`, for when a transaction can't
+/// have frames quietly going missing.
+///
+/// Every frame starts with a two byte header, a kind and a sequence number.
+/// Data frames are acknowledged by the other end with an ACK carrying the
+/// same sequence number, and only one data frame is in flight at a time
+/// (stop and wait). A frame that fails its CRC gets a NAK back, which has
+/// the sender retransmit straight away rather than waiting on the timeout.
+///
+/// Nothing here blocks. `poll` does whatever work is ready, timing ACKs by
+/// the link's clock (see `FrameTxRx::with_clock`). The default `NoClock`
+/// never moves, so nothing times out and only a NAK gets a frame sent again.
+///
+/// Sequence numbers are a wrapping u8. Duplicates are spotted by comparing
+/// with the last sequence number received, so if the other end restarts
+/// it should start again from a different number than it stopped on.
+pub struct ReliableLink<Tx: Write, Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock> {
+    link: FrameTxRx<Tx, Rx, C, B, K>,
+    /// Ticks to wait on an ACK before sending again
+    pub timeout: u32,
+    /// Retransmissions before giving up on a frame
+    pub max_retries: u8,
+    next_seq: u8,
+    in_flight: Option<InFlight>,
+    last_received: Option<u8>,
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock> ReliableLink<Tx, Rx, C, B, K> {
+    /// `timeout` is in the ticks of `link`'s clock
+    pub fn new(link: FrameTxRx<Tx, Rx, C, B, K>, timeout: u32) -> ReliableLink<Tx, Rx, C, B, K> {
+        ReliableLink {
+            link,
+            timeout,
+            max_retries: 3,
+            next_seq: 0,
+            in_flight: None,
+            last_received: None,
+        }
+    }
+
+    pub fn into_inner(self) -> FrameTxRx<Tx, Rx, C, B, K> {
+        self.link
+    }
+
+    /// True if there's no frame waiting on an ACK, so `send` won't block
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_none()
+    }
+
+    /// Send `data` reliably, returning the sequence number it went out with.
+    /// Blocks if the last frame sent hasn't been delivered yet.
+    pub fn send(&mut self, data: &[u8]) -> nb::Result<u8, ReliableError<Tx::Error, Rx::Error>> {
+        if self.in_flight.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        check_payload(data.len(), C::MAX_DATA_SIZE - HEADER_LEN).map_err(ReliableError::from)?;
+        let seq = self.next_seq;
+        let packet = packet(KIND_DATA, seq, data).map_err(ReliableError::from)?;
+        self.link.send(&packet).map_err(|e| e.map(write_error))?;
+        self.next_seq = seq.wrapping_add(1);
+        self.in_flight = Some(InFlight { packet, sent_at: self.link.now(), retries: 0 });
+        Ok(seq)
+    }
+
+    /// Do whatever's ready: read in frames and answer them, and retransmit
+    /// or give up on the frame in flight if it's timed out.
+    ///
+    /// WouldBlock means there's nothing to report yet.
+    pub fn poll(&mut self) -> nb::Result<Event, ReliableError<Tx::Error, Rx::Error>> {
+        match self.link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(nb::Error::Other(read_error(e))),
+        }
+
+        loop {
+            match self.link.recv() {
+                Ok(frame) => {
+                    if let Some(event) = self.handle(frame)? {
+                        return Ok(event);
+                    }
+                },
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. }))) => {
+                    // Ask for it again. We don't know what it was, so NAK what we expect next
+                    let expected = self.last_received.map_or(0, |s| s.wrapping_add(1));
+                    self.reply(KIND_NAK, expected)?;
+                },
+                // Anything else was never a whole frame, there's nothing to answer
+                Err(nb::Error::Other(FrameIOError::Frame(_))) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(read_error(e))),
+            }
+        }
+
+        if let Some(f) = &self.in_flight
+            && self.link.now().wrapping_sub(f.sent_at) >= self.timeout
+        {
+            self.retransmit()?;
+        }
+
+        match self.link.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(write_error(FrameIOError::Write(e)))),
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<Option<Event>, ReliableError<Tx::Error, Rx::Error>> {
+        // Too short to have come from the other end of a ReliableLink, drop it
+        let (kind, seq) = match frame.data.get(..HEADER_LEN) {
+            Some(h) => (h[0], h[1]),
+            None => return Ok(None),
+        };
+        match kind {
+            KIND_DATA => {
+                // ACK it even if it's a duplicate, our last ACK might have been lost
+                self.reply(KIND_ACK, seq)?;
+                if self.last_received == Some(seq) {
+                    return Ok(None);
+                }
+                self.last_received = Some(seq);
+                Ok(Some(Event::Received(frame_data(&frame.data[HEADER_LEN..])?)))
+            },
+            KIND_ACK => match &self.in_flight {
+                Some(f) if f.packet[1] == seq => {
+                    self.in_flight = None;
+                    Ok(Some(Event::Delivered(seq)))
+                },
+                // Late ACK for something already delivered
+                _ => Ok(None),
+            },
+            KIND_NAK => {
+                // A NAK for anything but the frame in flight is stale, e.g. the
+                // other end asking again for one that's since been delivered
+                if self.in_flight.as_ref().is_some_and(|f| f.packet[1] == seq) {
+                    self.retransmit()?;
+                }
+                Ok(None)
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn reply(&mut self, kind: u8, seq: u8) -> Result<(), ReliableError<Tx::Error, Rx::Error>> {
+        match self.link.send(&[kind, seq]) {
+            // No room to answer. The other end will send again and get an answer then.
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(write_error(e)),
+        }
+    }
+
+    fn retransmit(&mut self) -> Result<(), ReliableError<Tx::Error, Rx::Error>> {
+        let Some(f) = &mut self.in_flight else {
+            return Ok(());
+        };
+        if f.retries >= self.max_retries {
+            let seq = f.packet[1];
+            self.in_flight = None;
+            return Err(ReliableError::GaveUp { seq });
+        }
+        match self.link.send(&f.packet) {
+            Ok(()) => {
+                f.retries += 1;
+                f.sent_at = self.link.now();
+                Ok(())
+            },
+            // No room yet, so it's still due on the next poll
+            Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(write_error(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Crc8,
+        mock::{Ticks, Wire},
+    };
+
+    const TIMEOUT: u32 = 10;
+
+    type Link = ReliableLink<Wire, Wire, Delimited, DefaultBuffer, Ticks>;
+
+    /// Both ends on the same clock, the clock, and the wires from a to b and b to a
+    fn pair() -> (Link, Link, Ticks, Wire, Wire) {
+        let (ab, ba, clock) = (Wire::new(), Wire::new(), Ticks::new());
+        let a = ReliableLink::new(FrameTxRx::new(ab.clone(), ba.clone()).with_clock(clock.clone()), TIMEOUT);
+        let b = ReliableLink::new(FrameTxRx::new(ba.clone(), ab.clone()).with_clock(clock.clone()), TIMEOUT);
+        (a, b, clock, ab, ba)
+    }
+
+    fn received(event: nb::Result<Event, ReliableError<Infallible, Infallible>>) -> FrameData {
+        match event {
+            Ok(Event::Received(data)) => data,
+            e => panic!("expected data, got {e:?}"),
+        }
+    }
+
+    fn delivered(event: nb::Result<Event, ReliableError<Infallible, Infallible>>) -> u8 {
+        match event {
+            Ok(Event::Delivered(seq)) => seq,
+            e => panic!("expected an ACK, got {e:?}"),
+        }
+    }
+
+    #[test]
+    fn delivers_and_acknowledges() {
+        let (mut a, mut b, _, _, _) = pair();
+        assert_eq!(a.send(b"one").unwrap(), 0);
+        assert!(!a.is_idle());
+        // Stop and wait, nothing more goes until it's delivered
+        assert!(matches!(a.send(b"two"), Err(nb::Error::WouldBlock)));
+        assert_eq!(received(b.poll())[..], *b"one");
+        assert_eq!(delivered(a.poll()), 0);
+        assert!(a.is_idle());
+        assert_eq!(a.send(b"two").unwrap(), 1);
+        assert_eq!(received(b.poll())[..], *b"two");
+        assert_eq!(delivered(a.poll()), 1);
+    }
+
+    #[test]
+    fn retransmits_after_the_timeout() {
+        let (mut a, mut b, clock, ab, _) = pair();
+        a.send(b"lost").unwrap();
+        ab.take();
+        clock.set(TIMEOUT - 1);
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert!(ab.is_empty());
+        clock.set(TIMEOUT);
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(received(b.poll())[..], *b"lost");
+        assert_eq!(delivered(a.poll()), 0);
+    }
+
+    #[test]
+    fn no_clock_never_times_out() {
+        let (ab, ba) = (Wire::new(), Wire::new());
+        let mut a = ReliableLink::new(FrameTxRx::new(ab.clone(), ba), 1);
+        a.send(b"lost").unwrap();
+        ab.take();
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert!(ab.is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_acked_but_not_reported() {
+        let (mut a, mut b, clock, _, ba) = pair();
+        a.send(b"once").unwrap();
+        assert_eq!(received(b.poll())[..], *b"once");
+        // The ACK goes missing, so the frame comes round again
+        ba.take();
+        clock.set(TIMEOUT);
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert!(matches!(b.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(delivered(a.poll()), 0);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (mut a, _, clock, ab, _) = pair();
+        a.max_retries = 2;
+        a.send(b"void").unwrap();
+        for n in 1..=2 {
+            clock.set(n * TIMEOUT);
+            assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        }
+        clock.set(3 * TIMEOUT);
+        assert!(matches!(a.poll(), Err(nb::Error::Other(ReliableError::GaveUp { seq: 0 }))));
+        assert!(a.is_idle());
+        // The original and two retries
+        let mut sent = ab.take();
+        let codec = Delimited::<Crc8>::new();
+        let mut frames = 0;
+        while !sent.is_empty() {
+            let (_, len) = codec.decode(&sent).unwrap();
+            sent.drain(..len);
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+    }
+
+    #[test]
+    fn corrupted_frame_is_nakked_and_resent() {
+        let (mut a, mut b, _, ab, _) = pair();
+        a.send(b"noisy").unwrap();
+        let mut sent = ab.take();
+        let end = sent.len() - 2;
+        sent[end] ^= 0x01;
+        ab.push(&sent);
+        assert!(matches!(b.poll(), Err(nb::Error::WouldBlock)));
+        // No waiting on the timeout
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(received(b.poll())[..], *b"noisy");
+        assert_eq!(delivered(a.poll()), 0);
+    }
+
+    #[test]
+    fn stale_nak_is_ignored() {
+        let (mut a, _, _, ab, ba) = pair();
+        a.send(b"first").unwrap();
+        ab.take();
+        // A NAK for some other frame
+        let mut other = FrameTxRx::new(ba.clone(), Wire::new());
+        other.send(&[KIND_NAK, 5]).unwrap();
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert!(ab.is_empty());
+        other.send(&[KIND_NAK, 0]).unwrap();
+        assert!(matches!(a.poll(), Err(nb::Error::WouldBlock)));
+        assert!(!ab.is_empty());
+    }
+}
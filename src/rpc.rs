@@ -0,0 +1,473 @@
+use core::convert::Infallible;
+
+use embedded_hal_nb::serial::{Read, Write};
+
+use crate::{
+    clock::{Clock, NoClock},
+    codec::{Delimited, FrameCodec},
+    packet::{
+        Frame, FrameData, FrameError, FrameIOError, FrameRecv, FrameSend, FrameTxRx, MAX_DATA_SIZE,
+        check_payload, frame_data, widen_read_error, widen_write_error,
+    },
+    serial::{ByteQueue, DefaultBuffer},
+};
+
+/// Kind byte of a request, the data is the handler's input
+pub const KIND_REQUEST: u8 = 0x10;
+/// Kind byte of a handler's response, the data is its output
+pub const KIND_RESPONSE: u8 = 0x11;
+/// Kind byte of a response from a handler that failed, the data is its error code
+pub const KIND_FAILED: u8 = 0x12;
+/// Kind byte of a response to a request for an opcode with no handler
+pub const KIND_NO_HANDLER: u8 = 0x13;
+/// Kind, request id and opcode
+pub const HEADER_LEN: usize = 3;
+
+#[derive(Debug)]
+pub enum RpcError<WriteError, ReadError> {
+    Link(FrameIOError<WriteError, ReadError>),
+    /// No response came within the request's timeout. It's been forgotten,
+    /// a response turning up late is dropped.
+    Timeout { id: u8 },
+    /// The handler ran and failed with `code`
+    Failed { id: u8, code: u8 },
+    /// The other end has nothing registered for `opcode`
+    NoHandler { id: u8, opcode: u8 },
+    /// There's no request pending with this id
+    UnknownId { id: u8 },
+}
+
+impl<Ew, Er> From<FrameError> for RpcError<Ew, Er> {
+    fn from(value: FrameError) -> Self {
+        RpcError::Link(FrameIOError::Frame(value))
+    }
+}
+
+fn write_error<Ew, Er>(e: FrameIOError<Ew, Infallible>) -> RpcError<Ew, Er> {
+    RpcError::Link(widen_write_error(e))
+}
+
+fn read_error<Ew, Er>(e: FrameIOError<Infallible, Er>) -> RpcError<Ew, Er> {
+    RpcError::Link(widen_read_error(e))
+}
+
+/// Kind, id and opcode off the front of a frame, None if it's too short
+/// to have come from the other end of an RPC link
+fn header(frame: &Frame) -> Option<(u8, u8, u8)> {
+    frame.data.get(..HEADER_LEN).map(|h| (h[0], h[1], h[2]))
+}
+
+/// What's come back for a pending request
+// Responses sit in the pending table until they're picked up, and there are
+// only ever `N` of them, so the Waiting slots being as big doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Reply {
+    Waiting,
+    Response(FrameData),
+    Failed(u8),
+    NoHandler,
+}
+
+struct Pending {
+    id: u8,
+    opcode: u8,
+    sent_at: u32,
+    timeout: u32,
+    reply: Reply,
+}
+
+/// The calling end of an RPC link over a `FrameTxRx`.
+///
+/// Each request goes out with an opcode saying what's being asked for and
+/// an id the response comes back with, so up to `N` requests can be
+/// outstanding at once and their responses turn up in any order.
+///
+/// Requests time out by the link's clock, so give it one with
+/// `FrameTxRx::with_clock`. On the default `NoClock` a request waits for
+/// its response for as long as it takes.
+pub struct RpcClient<Tx: Write, Rx: Read, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock, const N: usize = 4> {
+    link: FrameTxRx<Tx, Rx, C, B, K>,
+    next_id: u8,
+    pending: [Option<Pending>; N],
+}
+
+impl<Tx: Write, Rx: Read, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize> RpcClient<Tx, Rx, C, B, K, N> {
+    pub fn new(link: FrameTxRx<Tx, Rx, C, B, K>) -> RpcClient<Tx, Rx, C, B, K, N> {
+        RpcClient { link, next_id: 0, pending: core::array::from_fn(|_| None) }
+    }
+
+    pub fn into_inner(self) -> FrameTxRx<Tx, Rx, C, B, K> {
+        self.link
+    }
+
+    /// How many requests are waiting to be picked up with `poll_response`
+    pub fn pending(&self) -> usize {
+        self.pending.iter().filter(|p| p.is_some()).count()
+    }
+
+    /// Send `data` as a request for `opcode`, returning the id to
+    /// `poll_response` with. `timeout` ticks of the link's clock from now
+    /// it's given up on.
+    ///
+    /// WouldBlock means there are already `N` requests pending or there's
+    /// no room to send it yet.
+    pub fn request(&mut self, opcode: u8, data: &[u8], timeout: u32) -> nb::Result<u8, RpcError<Tx::Error, Rx::Error>> {
+        check_payload(data.len(), C::MAX_DATA_SIZE.min(MAX_DATA_SIZE) - HEADER_LEN).map_err(RpcError::from)?;
+        let slot = self.pending.iter().position(Option::is_none).ok_or(nb::Error::WouldBlock)?;
+        // Skip over ids still waiting on a response
+        let id = (0..=u8::MAX)
+            .map(|i| self.next_id.wrapping_add(i))
+            .find(|id| !self.pending.iter().flatten().any(|p| p.id == *id))
+            .ok_or(nb::Error::WouldBlock)?;
+
+        let mut packet = [0; MAX_DATA_SIZE];
+        packet[..HEADER_LEN].copy_from_slice(&[KIND_REQUEST, id, opcode]);
+        packet[HEADER_LEN..HEADER_LEN + data.len()].copy_from_slice(data);
+        self.link.send(&packet[..HEADER_LEN + data.len()]).map_err(|e| e.map(write_error))?;
+
+        self.next_id = id.wrapping_add(1);
+        self.pending[slot] = Some(Pending { id, opcode, sent_at: self.link.now(), timeout, reply: Reply::Waiting });
+        Ok(id)
+    }
+
+    /// The response to request `id`, once it's come back.
+    ///
+    /// Reads in whatever frames are ready first, so responses to the other
+    /// pending requests are picked up along the way. WouldBlock means it's
+    /// not back yet and hasn't timed out.
+    pub fn poll_response(&mut self, id: u8) -> nb::Result<FrameData, RpcError<Tx::Error, Rx::Error>> {
+        self.poll()?;
+        let now = self.link.now();
+
+        let i = self
+            .pending
+            .iter()
+            .position(|p| p.as_ref().is_some_and(|p| p.id == id))
+            .ok_or(nb::Error::Other(RpcError::UnknownId { id }))?;
+        if let Some(p) = &self.pending[i]
+            && let Reply::Waiting = p.reply
+            && now.wrapping_sub(p.sent_at) < p.timeout
+        {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let error = match self.pending[i].take() {
+            Some(Pending { reply: Reply::Response(data), .. }) => return Ok(data),
+            Some(Pending { reply: Reply::Waiting, .. }) => RpcError::Timeout { id },
+            Some(Pending { reply: Reply::Failed(code), .. }) => RpcError::Failed { id, code },
+            Some(Pending { reply: Reply::NoHandler, opcode, .. }) => RpcError::NoHandler { id, opcode },
+            None => RpcError::UnknownId { id },
+        };
+        Err(nb::Error::Other(error))
+    }
+
+    /// Forget about request `id`, a response to it is dropped if it turns up
+    pub fn cancel(&mut self, id: u8) {
+        for p in &mut self.pending {
+            if matches!(p, Some(p) if p.id == id) {
+                *p = None;
+            }
+        }
+    }
+
+    /// Read in frames and match any responses up with their requests
+    fn poll(&mut self) -> Result<(), RpcError<Tx::Error, Rx::Error>> {
+        match self.link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(read_error(e)),
+        }
+
+        loop {
+            match self.link.recv() {
+                Ok(frame) => self.handle(frame)?,
+                Err(nb::Error::WouldBlock) => break,
+                // Whatever it was never arrived whole. If it was a response
+                // its request will time out.
+                Err(nb::Error::Other(FrameIOError::Frame(_))) => (),
+                Err(nb::Error::Other(e)) => return Err(read_error(e)),
+            }
+        }
+
+        match self.link.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(write_error(FrameIOError::Write(e))),
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), RpcError<Tx::Error, Rx::Error>> {
+        let Some((kind, id, _)) = header(&frame) else {
+            return Ok(());
+        };
+        // Late responses to requests that timed out or were cancelled land here too
+        let Some(p) = self.pending.iter_mut().flatten().find(|p| p.id == id && matches!(p.reply, Reply::Waiting)) else {
+            return Ok(());
+        };
+        p.reply = match kind {
+            KIND_RESPONSE => Reply::Response(frame_data(&frame.data[HEADER_LEN..])?),
+            KIND_FAILED => Reply::Failed(frame.data.get(HEADER_LEN).copied().unwrap_or(0)),
+            KIND_NO_HANDLER => Reply::NoHandler,
+            _ => return Ok(()),
+        };
+        Ok(())
+    }
+}
+
+/// Answers a request: gets the server's context, the request's data and
+/// a buffer for the response, and returns how much of the buffer it used.
+/// An error code goes back to the client as `RpcError::Failed`.
+pub type Handler<Ctx> = fn(&mut Ctx, &[u8], &mut [u8]) -> Result<usize, u8>;
+
+/// The answering end of an RPC link over a `FrameTxRx`, handing each
+/// request to the handler registered for its opcode.
+///
+/// Handlers are plain functions, whatever state they need is the `Ctx`
+/// handed to `poll`. There's room for `N` of them.
+///
+/// A response there's no room to send yet is held on to, and no more
+/// requests are read in until it's gone out.
+pub struct RpcServer<Tx: Write, Rx: Read, Ctx, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer, K: Clock = NoClock, const N: usize = 8> {
+    link: FrameTxRx<Tx, Rx, C, B, K>,
+    handlers: heapless::Vec<(u8, Handler<Ctx>), N>,
+    /// Response still waiting on room in the link
+    unsent: Option<FrameData>,
+}
+
+impl<Tx: Write, Rx: Read, Ctx, C: FrameCodec, B: ByteQueue, K: Clock, const N: usize> RpcServer<Tx, Rx, Ctx, C, B, K, N> {
+    pub fn new(link: FrameTxRx<Tx, Rx, C, B, K>) -> RpcServer<Tx, Rx, Ctx, C, B, K, N> {
+        RpcServer { link, handlers: heapless::Vec::new(), unsent: None }
+    }
+
+    pub fn into_inner(self) -> FrameTxRx<Tx, Rx, C, B, K> {
+        self.link
+    }
+
+    /// Have requests for `opcode` answered by `handler`, replacing whatever
+    /// answered them before. Hands `handler` back if all `N` slots are taken.
+    pub fn register(&mut self, opcode: u8, handler: Handler<Ctx>) -> Result<(), Handler<Ctx>> {
+        match self.handlers.iter_mut().find(|(op, _)| *op == opcode) {
+            Some((_, h)) => {
+                *h = handler;
+                Ok(())
+            },
+            None => self.handlers.push((opcode, handler)).map_err(|(_, h)| h),
+        }
+    }
+
+    /// Read in requests and answer them, returning the opcode of each one
+    /// a handler ran for. WouldBlock means there's nothing left to do, or
+    /// a response is still waiting on room to go out.
+    pub fn poll(&mut self, ctx: &mut Ctx) -> nb::Result<u8, RpcError<Tx::Error, Rx::Error>> {
+        self.send_unsent()?;
+        match self.link.buffer() {
+            Ok(()) | Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(nb::Error::Other(read_error(e))),
+        }
+
+        loop {
+            match self.link.recv() {
+                Ok(frame) => {
+                    let opcode = self.handle(frame, ctx)?;
+                    match (opcode, self.send_unsent()) {
+                        (_, Err(nb::Error::Other(e))) => return Err(nb::Error::Other(e)),
+                        // The handler ran, its response goes out on a later poll
+                        (Some(opcode), _) => return Ok(opcode),
+                        // Leave the rest of the requests until there's room
+                        (None, Err(nb::Error::WouldBlock)) => return Err(nb::Error::WouldBlock),
+                        (None, Ok(())) => (),
+                    }
+                },
+                Err(nb::Error::WouldBlock) => break,
+                // Never arrived whole, the client will time out
+                Err(nb::Error::Other(FrameIOError::Frame(_))) => (),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(read_error(e))),
+            }
+        }
+
+        match self.link.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(write_error(FrameIOError::Write(e)))),
+        }
+    }
+
+    /// Try the held response again, WouldBlock while there's still no room
+    fn send_unsent(&mut self) -> nb::Result<(), RpcError<Tx::Error, Rx::Error>> {
+        let Some(response) = self.unsent.take() else {
+            return Ok(());
+        };
+        match self.link.send(&response) {
+            Ok(()) => Ok(()),
+            Err(nb::Error::WouldBlock) => {
+                self.unsent = Some(response);
+                Err(nb::Error::WouldBlock)
+            },
+            // The link's broken, the client times out
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(write_error(e))),
+        }
+    }
+
+    /// Run the handler for a request, leaving its response in `unsent`
+    fn handle(&mut self, frame: Frame, ctx: &mut Ctx) -> Result<Option<u8>, RpcError<Tx::Error, Rx::Error>> {
+        let Some((KIND_REQUEST, id, opcode)) = header(&frame) else {
+            return Ok(None);
+        };
+        let handler = self.handlers.iter().find(|(op, _)| *op == opcode).map(|(_, h)| *h);
+
+        let mut response = [0; MAX_DATA_SIZE];
+        let max = C::MAX_DATA_SIZE.min(MAX_DATA_SIZE);
+        let len = match handler {
+            Some(handler) => match handler(ctx, &frame.data[HEADER_LEN..], &mut response[HEADER_LEN..max]) {
+                Ok(n) => {
+                    response[0] = KIND_RESPONSE;
+                    HEADER_LEN + n.min(max - HEADER_LEN)
+                },
+                Err(code) => {
+                    response[0] = KIND_FAILED;
+                    response[HEADER_LEN] = code;
+                    HEADER_LEN + 1
+                },
+            },
+            None => {
+                response[0] = KIND_NO_HANDLER;
+                HEADER_LEN
+            },
+        };
+        response[1] = id;
+        response[2] = opcode;
+
+        self.unsent = Some(frame_data(&response[..len])?);
+        Ok(handler.map(|_| opcode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Deque;
+
+    use super::*;
+    use crate::{
+        checksum::Crc8,
+        mock::{Ticks, Wire, link},
+    };
+
+    const ECHO: u8 = 1;
+    const FAIL: u8 = 2;
+
+    /// Echoes the request back, counting how many it's answered
+    fn echo(count: &mut u32, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        *count += 1;
+        response[..request.len()].copy_from_slice(request);
+        Ok(request.len())
+    }
+
+    fn fail(_: &mut u32, _: &[u8], _: &mut [u8]) -> Result<usize, u8> {
+        Err(0x42)
+    }
+
+    type Client = RpcClient<Wire, Wire, Delimited, DefaultBuffer, Ticks>;
+    type Server = RpcServer<Wire, Wire, u32>;
+
+    fn pair() -> (Client, Server, Ticks) {
+        let (a, b) = link();
+        let clock = Ticks::new();
+        let mut server = RpcServer::new(b);
+        server.register(ECHO, echo).unwrap();
+        server.register(FAIL, fail).unwrap();
+        (RpcClient::new(a.with_clock(clock.clone())), server, clock)
+    }
+
+    /// Answer every request that's come in
+    fn serve(server: &mut Server, count: &mut u32) {
+        while server.poll(count).is_ok() {}
+    }
+
+    #[test]
+    fn responses_match_their_requests_by_id() {
+        let (mut client, mut server, _) = pair();
+        let mut count = 0;
+        let first = client.request(ECHO, b"first", 10).unwrap();
+        let second = client.request(ECHO, b"second", 10).unwrap();
+        assert_ne!(first, second);
+        serve(&mut server, &mut count);
+        assert_eq!(count, 2);
+
+        // Picked up in the other order they're still each their own
+        assert_eq!(client.poll_response(second).unwrap()[..], *b"second");
+        assert_eq!(client.poll_response(first).unwrap()[..], *b"first");
+        assert_eq!(client.pending(), 0);
+        assert!(matches!(client.poll_response(first), Err(nb::Error::Other(RpcError::UnknownId { .. }))));
+    }
+
+    #[test]
+    fn failures_and_missing_handlers_come_back() {
+        let (mut client, mut server, _) = pair();
+        let failed = client.request(FAIL, b"", 10).unwrap();
+        let missing = client.request(9, b"", 10).unwrap();
+        serve(&mut server, &mut 0);
+        assert!(matches!(
+            client.poll_response(failed),
+            Err(nb::Error::Other(RpcError::Failed { code: 0x42, .. }))
+        ));
+        assert!(matches!(
+            client.poll_response(missing),
+            Err(nb::Error::Other(RpcError::NoHandler { opcode: 9, .. }))
+        ));
+    }
+
+    #[test]
+    fn requests_time_out_by_the_link_clock() {
+        let (mut client, mut server, clock) = pair();
+        clock.set(100);
+        let id = client.request(ECHO, b"slow", 10).unwrap();
+        clock.set(109);
+        assert!(matches!(client.poll_response(id), Err(nb::Error::WouldBlock)));
+        clock.set(110);
+        assert!(matches!(client.poll_response(id), Err(nb::Error::Other(RpcError::Timeout { .. }))));
+
+        // The late answer is dropped, not handed to the next request
+        serve(&mut server, &mut 0);
+        let next = client.request(ECHO, b"next", 10).unwrap();
+        assert!(matches!(client.poll_response(next), Err(nb::Error::WouldBlock)));
+        serve(&mut server, &mut 0);
+        assert_eq!(client.poll_response(next).unwrap()[..], *b"next");
+    }
+
+    #[test]
+    fn no_more_than_n_pending() {
+        let (a, _) = link();
+        let mut client: RpcClient<Wire, Wire, Delimited, DefaultBuffer, NoClock, 2> = RpcClient::new(a);
+        let first = client.request(ECHO, b"", 10).unwrap();
+        client.request(ECHO, b"", 10).unwrap();
+        assert!(matches!(client.request(ECHO, b"", 10), Err(nb::Error::WouldBlock)));
+        client.cancel(first);
+        assert!(client.request(ECHO, b"", 10).is_ok());
+    }
+
+    #[test]
+    fn responses_wait_for_room() {
+        let (to_server, to_client) = (Wire::new(), Wire::new());
+        to_client.set_room(0);
+        // Room for one echo of 4 bytes in the server's queue, not two
+        let link = FrameTxRx::with_buffers(to_client.clone(), to_server.clone(), Delimited::<Crc8>::new(), Deque::<u8, 16>::new(), Deque::new());
+        let mut server: RpcServer<_, _, u32, _, _> = RpcServer::new(link);
+        server.register(ECHO, echo).unwrap();
+        let mut client: RpcClient<_, _> = RpcClient::new(FrameTxRx::new(to_server, to_client.clone()));
+        let mut count = 0;
+        let mut ids = heapless::Vec::<u8, 3>::new();
+        for data in [b"abcd", b"efgh"] {
+            ids.push(client.request(ECHO, data, 10).unwrap()).unwrap();
+            assert!(matches!(server.poll(&mut count), Ok(ECHO)));
+        }
+        // The second one's handled and its response held on to, so the
+        // third isn't read in
+        ids.push(client.request(ECHO, b"ijkl", 10).unwrap()).unwrap();
+        assert!(matches!(server.poll(&mut count), Err(nb::Error::WouldBlock)));
+        assert_eq!(count, 2);
+
+        to_client.set_room(usize::MAX);
+        while server.poll(&mut count).is_ok() {}
+        assert_eq!(count, 3);
+        for (id, data) in ids.into_iter().zip([b"abcd", b"efgh", b"ijkl"]) {
+            assert_eq!(client.poll_response(id).unwrap()[..], *data);
+        }
+    }
+}
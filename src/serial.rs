@@ -1,51 +1,328 @@
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 use core::convert::Infallible;
 
+#[cfg(feature = "alloc")]
 use alloc::{collections::VecDeque, vec::Vec};
 use embedded_hal_nb::serial::{Error, ErrorType, Read, Write};
 
+use crate::packet::{FrameIOError, MAX_FRAME_SIZE};
+
+/// Capacity of the default heapless buffers when built without `alloc`.
+/// Enough for a couple of worst case `Delimited` frames, but not the bigger
+/// `Extended` ones, which need a buffer passed to `with_buffer`.
+pub const DEFAULT_BUFFER_SIZE: usize = 2 * MAX_FRAME_SIZE;
+
+/// Buffer BufferedRx and BufferedTx use when not told otherwise. Grows as
+/// needed with the `alloc` feature, otherwise it's a fixed size heapless Deque.
+#[cfg(feature = "alloc")]
+pub type DefaultBuffer = VecDeque<u8>;
+#[cfg(not(feature = "alloc"))]
+pub type DefaultBuffer = heapless::Deque<u8, DEFAULT_BUFFER_SIZE>;
+
+/// Byte queue storage for BufferedRx and BufferedTx.
+///
+/// Implemented for `heapless::Deque<u8, N>` so the capacity is picked with a
+/// const generic, and for `VecDeque<u8>` with the `alloc` feature.
+pub trait ByteQueue {
+    /// The bytes `take_front` hands back
+    type Drain<'a>: Iterator<Item = u8>
+    where
+        Self: 'a;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True if there's no room for another byte
+    fn is_full(&self) -> bool;
+
+    /// How many more bytes there's room for. Queues that grow say `usize::MAX`.
+    fn free(&self) -> usize;
+
+    /// Push onto the back, handing the byte back if there's no room
+    fn push_back(&mut self, b: u8) -> Result<(), u8>;
+
+    /// Push onto the front, handing the byte back if there's no room
+    fn push_front(&mut self, b: u8) -> Result<(), u8>;
+
+    fn pop_front(&mut self) -> Option<u8>;
+
+    fn front(&self) -> Option<u8>;
+
+    /// Throw away `amount` bytes from the front
+    fn drain_front(&mut self, amount: usize);
+
+    /// Take `amount` bytes (or as many as there are) off the front. They're
+    /// gone once the iterator is dropped, whether it got to them or not.
+    fn take_front(&mut self, amount: usize) -> Self::Drain<'_>;
+
+    fn as_slices(&self) -> (&[u8], &[u8]);
+
+    fn as_mut_slices(&mut self) -> (&mut [u8], &mut [u8]);
+
+    fn make_contiguous(&mut self) -> &mut [u8];
+}
+
+impl<const N: usize> ByteQueue for heapless::Deque<u8, N> {
+    type Drain<'a> = DequeDrain<'a, N>;
+
+    fn len(&self) -> usize {
+        heapless::Deque::len(self)
+    }
+
+    fn is_full(&self) -> bool {
+        heapless::Deque::is_full(self)
+    }
+
+    fn free(&self) -> usize {
+        heapless::Deque::capacity(self) - heapless::Deque::len(self)
+    }
+
+    fn push_back(&mut self, b: u8) -> Result<(), u8> {
+        heapless::Deque::push_back(self, b)
+    }
+
+    fn push_front(&mut self, b: u8) -> Result<(), u8> {
+        heapless::Deque::push_front(self, b)
+    }
+
+    fn pop_front(&mut self) -> Option<u8> {
+        heapless::Deque::pop_front(self)
+    }
+
+    fn front(&self) -> Option<u8> {
+        heapless::Deque::front(self).copied()
+    }
+
+    fn drain_front(&mut self, amount: usize) {
+        for _ in 0..amount {
+            if heapless::Deque::pop_front(self).is_none() {
+                break;
+            }
+        }
+    }
+
+    fn take_front(&mut self, amount: usize) -> DequeDrain<'_, N> {
+        DequeDrain { deque: self, left: amount }
+    }
+
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        heapless::Deque::as_slices(self)
+    }
+
+    fn as_mut_slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        heapless::Deque::as_mut_slices(self)
+    }
+
+    fn make_contiguous(&mut self) -> &mut [u8] {
+        // heapless's own make_contiguous gets its length wrong when the front
+        // half is the longer one (as of 0.9), so rebuild the Deque from the
+        // start of its storage instead.
+        if !heapless::Deque::as_slices(self).1.is_empty() {
+            let mut fresh = heapless::Deque::new();
+            while let Some(b) = heapless::Deque::pop_front(self) {
+                let _ = fresh.push_back(b);
+            }
+            *self = fresh;
+        }
+        heapless::Deque::as_mut_slices(self).0
+    }
+}
+
+/// Bytes coming off the front of a `heapless::Deque`, see `ByteQueue::take_front`
+#[derive(Debug)]
+pub struct DequeDrain<'a, const N: usize> {
+    deque: &'a mut heapless::Deque<u8, N>,
+    left: usize,
+}
+
+impl<const N: usize> Iterator for DequeDrain<'_, N> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        self.deque.pop_front()
+    }
+}
+
+impl<const N: usize> Drop for DequeDrain<'_, N> {
+    fn drop(&mut self) {
+        self.deque.drain_front(self.left);
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl ByteQueue for VecDeque<u8> {
+    type Drain<'a> = alloc::collections::vec_deque::Drain<'a, u8>;
+
+    fn len(&self) -> usize {
+        VecDeque::len(self)
+    }
+
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    fn free(&self) -> usize {
+        usize::MAX
+    }
+
+    fn push_back(&mut self, b: u8) -> Result<(), u8> {
+        VecDeque::push_back(self, b);
+        Ok(())
+    }
+
+    fn push_front(&mut self, b: u8) -> Result<(), u8> {
+        VecDeque::push_front(self, b);
+        Ok(())
+    }
+
+    fn pop_front(&mut self) -> Option<u8> {
+        VecDeque::pop_front(self)
+    }
+
+    fn front(&self) -> Option<u8> {
+        VecDeque::front(self).copied()
+    }
+
+    fn drain_front(&mut self, amount: usize) {
+        self.drain(0..amount.min(VecDeque::len(self)));
+    }
+
+    fn take_front(&mut self, amount: usize) -> Self::Drain<'_> {
+        self.drain(0..amount.min(VecDeque::len(self)))
+    }
+
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        VecDeque::as_slices(self)
+    }
+
+    fn as_mut_slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        VecDeque::as_mut_slices(self)
+    }
+
+    fn make_contiguous(&mut self) -> &mut [u8] {
+        VecDeque::make_contiguous(self)
+    }
+}
+
+/// What `BufferedRx` does with bytes that come in once it's full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Throw away the oldest buffered byte to make room. Whatever frame was
+    /// partway through is lost, but we stay in step with the line.
+    #[default]
+    DropOldest,
+    /// Throw away the byte that just came in
+    DropNewest,
+    /// Throw away the byte that just came in and have `buffer` return
+    /// `FrameIOError::Overrun`
+    Error,
+}
+
+/// Most bytes a single `BufferedRx::buffer` call reads by default
+pub const DEFAULT_READ_BUDGET: usize = MAX_FRAME_SIZE;
+
 #[derive(Debug)]
-pub struct BufferedRx<Rx: Read> {
+pub struct BufferedRx<Rx: Read, B: ByteQueue = DefaultBuffer> {
     pub rx: Rx,
-    pub buf: VecDeque<u8>,
+    pub buf: B,
+    /// Most bytes to hold on to, on top of whatever limit `buf` has itself
+    pub capacity: usize,
+    pub overflow: Overflow,
+    /// Most bytes read per `buffer` call, so a flood of input can't keep it
+    /// going forever
+    pub budget: usize,
+    /// Bytes thrown away by `overflow` so far
+    dropped: usize,
 }
 
 impl<Rx: Read> BufferedRx<Rx> {
     pub fn new(rx: Rx) -> BufferedRx<Rx> {
+        BufferedRx::with_buffer(rx, DefaultBuffer::default())
+    }
+}
+
+impl<Rx: Read, B: ByteQueue> BufferedRx<Rx, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits.
+    /// Holds at most `DEFAULT_BUFFER_SIZE` bytes until `capacity` says otherwise.
+    pub fn with_buffer(rx: Rx, buf: B) -> BufferedRx<Rx, B> {
         BufferedRx {
             rx,
-            buf: VecDeque::new(),
+            buf,
+            capacity: DEFAULT_BUFFER_SIZE,
+            overflow: Overflow::default(),
+            budget: DEFAULT_READ_BUDGET,
+            dropped: 0,
         }
     }
 
-    /// Load as much as we can from rx into the internal buf. Like flush from Write
-    pub fn buffer(&mut self) -> nb::Result<(), Rx::Error> {
-        loop {
-            match self.rx.read() {
-                Ok(k) => {
-                    self.buf.push_back(k);
-                }
-                Err(e) => match e {
-                    nb::Error::WouldBlock => {
-                        break;
-                    }
-                    nb::Error::Other(ee) => return Err(nb::Error::Other(ee)),
+    /// True if there's no room for another byte
+    pub fn is_full(&self) -> bool {
+        self.buf.is_full() || self.buf.len() >= self.capacity
+    }
+
+    /// Bytes thrown away because the buffer was full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn reset_dropped(&mut self) {
+        self.dropped = 0;
+    }
+
+    /// Load what rx has into the internal buf, up to `budget` bytes. Like flush from Write.
+    ///
+    /// Bytes that come in once the buffer's full are dealt with by `overflow`.
+    pub fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        let mut result = Ok(());
+        for _ in 0..self.budget {
+            let b = match self.rx.read() {
+                Ok(b) => b,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => {
+                    result = Err(nb::Error::Other(FrameIOError::Read(e)));
+                    break;
                 },
+            };
+            if self.is_full() {
+                self.dropped += 1;
+                match self.overflow {
+                    Overflow::DropOldest => self.buf.drain_front(1),
+                    Overflow::DropNewest => continue,
+                    Overflow::Error => {
+                        result = Err(nb::Error::Other(FrameIOError::Overrun));
+                        break;
+                    },
+                }
             }
+            let _ = self.buf.push_back(b);
         }
         let _ = self.buf.make_contiguous();
-        Ok(())
+        result
     }
 
     pub fn peek(&self) -> Option<u8> {
-        self.buf.front().copied()
+        self.buf.front()
+    }
+
+    /// Removes elements from the front of the buffer from 0 to the
+    /// specified amount, handing them back. With the default buffer under
+    /// `alloc` that's the `vec_deque::Drain` it's always been.
+    pub fn drain(&mut self, amount: usize) -> B::Drain<'_> {
+        self.buf.take_front(amount)
     }
 
-    /// Removes elements from the front of the VecDeque from 0 to the
-    /// specified amount.
-    pub fn drain(&mut self, amount: usize) -> alloc::collections::vec_deque::Drain<'_, u8> {
-        self.buf.drain(0..amount)
+    /// Throw away `amount` bytes from the front of the buffer
+    pub fn skip(&mut self, amount: usize) {
+        self.buf.drain_front(amount)
     }
 
     pub fn slice(&self) -> &[u8] {
@@ -54,19 +331,24 @@ impl<Rx: Read> BufferedRx<Rx> {
         // Will have elements.
         self.buf.as_slices().0
     }
+
+    /// Mutable version of `slice`, for decoding in place
+    pub fn slice_mut(&mut self) -> &mut [u8] {
+        self.buf.as_mut_slices().0
+    }
 }
 
-impl<Rx: Read> ErrorType for BufferedRx<Rx> {
+impl<Rx: Read, B: ByteQueue> ErrorType for BufferedRx<Rx, B> {
     type Error = Rx::Error;
 }
 
-impl<Rx: Read> Read for BufferedRx<Rx> {
+impl<Rx: Read, B: ByteQueue> Read for BufferedRx<Rx, B> {
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
         // Read from self.rx and place into the buffer
-        loop {
+        while !self.is_full() {
             match self.rx.read() {
                 Ok(x) => {
-                    self.buf.push_back(x);
+                    let _ = self.buf.push_back(x);
                 }
                 Err(nb::Error::Other(e)) => return Err(nb::Error::Other(e)),
                 Err(nb::Error::WouldBlock) => {
@@ -79,7 +361,8 @@ impl<Rx: Read> Read for BufferedRx<Rx> {
     }
 }
 
-impl<Rx: Read> ReadAmt for BufferedRx<Rx> {
+#[cfg(feature = "alloc")]
+impl<Rx: Read, B: ByteQueue> ReadAmt for BufferedRx<Rx, B> {
     type Error = Infallible;
 
     /// Attempt to read the amount requested
@@ -89,37 +372,68 @@ impl<Rx: Read> ReadAmt for BufferedRx<Rx> {
         if amount > self.buf.len() {
             return Err(nb::Error::WouldBlock);
         }
-        Ok(self.drain(amount).collect())
+        Ok((0..amount).filter_map(|_| self.buf.pop_front()).collect())
     }
 }
 
 
 
 #[derive(Debug)]
-pub struct BufferedTx<Tx: Write> {
-    tx: Tx,
-    pub buf: VecDeque<u8>,
+pub struct BufferedTx<Tx: Write, B: ByteQueue = DefaultBuffer> {
+    pub tx: Tx,
+    pub buf: B,
+    /// Most bytes to queue up, on top of whatever limit `buf` has itself
+    pub capacity: usize,
 }
 
 impl<Tx: Write> BufferedTx<Tx> {
     pub fn new(tx: Tx) -> BufferedTx<Tx> {
-        BufferedTx {
-            tx,
-            buf: VecDeque::new(),
-        }
+        BufferedTx::with_buffer(tx, DefaultBuffer::default())
     }
+}
 
+impl<Tx: Write, B: ByteQueue> BufferedTx<Tx, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits.
+    /// Queues at most `DEFAULT_BUFFER_SIZE` bytes until `capacity` says otherwise.
+    pub fn with_buffer(tx: Tx, buf: B) -> BufferedTx<Tx, B> {
+        BufferedTx { tx, buf, capacity: DEFAULT_BUFFER_SIZE }
+    }
+
+    /// How many more bytes there's room to queue
+    pub fn free(&self) -> usize {
+        self.buf.free().min(self.capacity.saturating_sub(self.buf.len()))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free() == 0
+    }
+
+    /// Queue all of `data` or none of it and start it on its way.
+    /// WouldBlock means there isn't room for all of it yet, which there
+    /// never will be if it's bigger than the queue.
     pub fn write_all(&mut self, data: &[u8]) -> nb::Result<(), Tx::Error> {
-        for a in data {
-            // Unwrapping here won't panic - I know this is weird atm TODO ?
-            // self.write will just add to a vec, so guarenteed to work
-            self.write(*a).unwrap();
+        if data.len() > self.free() {
+            // Make some room if the wire will take it
+            match self.flush() {
+                Ok(()) | Err(nb::Error::WouldBlock) => (),
+                Err(e) => return Err(e),
+            }
+            if data.len() > self.free() {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        for b in data {
+            let _ = self.buf.push_back(*b);
+        }
+        // It's queued now, whether the wire takes it yet or not
+        match self.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(e) => Err(e),
         }
-        self.flush()
     }
 }
 
-impl<Tx: Write> ErrorType for BufferedTx<Tx> {
+impl<Tx: Write, B: ByteQueue> ErrorType for BufferedTx<Tx, B> {
     type Error = Tx::Error;
 }
 
@@ -145,14 +459,21 @@ impl<T: Error> From<T> for ErrorShim<T> {
     }
 }
 
-impl<Tx: Write> embedded_io::ErrorType for BufferedTx<Tx> {
+impl<Tx: Write, B: ByteQueue> embedded_io::ErrorType for BufferedTx<Tx, B> {
     type Error = ErrorShim<Tx::Error>;
 }
 
-impl<Tx: Write> Write for BufferedTx<Tx> {
+impl<Tx: Write, B: ByteQueue> Write for BufferedTx<Tx, B> {
     fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        self.buf.push_back(word);
-        Ok(())
+        // Full, so try and make some room by getting a byte out on the wire
+        if self.is_full()
+            && let Some(x) = self.buf.pop_front()
+            && let Err(e) = self.tx.write(x)
+        {
+            let _ = self.buf.push_front(x);
+            return Err(e);
+        }
+        self.buf.push_back(word).map_err(|_| nb::Error::WouldBlock)
     }
 
     fn flush(&mut self) -> nb::Result<(), Self::Error> {
@@ -168,7 +489,7 @@ impl<Tx: Write> Write for BufferedTx<Tx> {
                 // If writing got an error than we need to push the byte back into the buffer
                 // Since other parts of the code assume buf is contiguous let's make it
                 // contiguous here (since pushing front is non-contiguous)
-                self.buf.push_front(x);
+                let _ = self.buf.push_front(x);
                 self.buf.make_contiguous();
                 break Err(e)
             }
@@ -176,28 +497,44 @@ impl<Tx: Write> Write for BufferedTx<Tx> {
     }
 }
 
-impl<Tx: Write> embedded_io::Write for BufferedTx<Tx> {
+/// Queues as much of each write as there's room for. Like any
+/// `embedded_io::Write` it blocks until at least one byte is taken, check
+/// `WriteReady` first to avoid that.
+impl<Tx: Write, B: ByteQueue> embedded_io::Write for BufferedTx<Tx, B> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        match self.write_all(buf) {
-            Ok(()) => Ok(buf.len()),
-            Err(e) => match e {
-                nb::Error::Other(ee) => Err(ErrorShim(ee)),
-                nb::Error::WouldBlock => Ok(buf.len()),
-            },
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = buf.len().min(self.free());
+            if n > 0 {
+                for b in &buf[..n] {
+                    let _ = self.buf.push_back(*b);
+                }
+                // Start them on their way. A write error leaves the byte
+                // queued, so it comes up again on the next flush.
+                let _ = Write::flush(self);
+                return Ok(n);
+            }
+            match Write::flush(self) {
+                Ok(()) | Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(e)) => return Err(ErrorShim(e)),
+            }
         }
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        match Write::flush(self) {
-            Ok(k) => Ok(k),
-            Err(e) => match e {
-                nb::Error::Other(ee) => Err(ErrorShim(ee)),
-                nb::Error::WouldBlock => Ok(()),
-            },
-        }
+        nb::block!(Write::flush(self)).map_err(ErrorShim)
+    }
+}
+
+impl<Tx: Write, B: ByteQueue> embedded_io::WriteReady for BufferedTx<Tx, B> {
+    fn write_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_full())
     }
 }
 
+#[cfg(feature = "alloc")]
 pub trait ReadAmt {
     type Error;
 
@@ -219,6 +556,7 @@ impl<Rx: Read, Tx: Write> BufferedRxTx<Rx, Tx> {
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<Rx: Read, Tx: Write> ReadAmt for BufferedRxTx<Rx, Tx> {
     type Error = Infallible;
     /// Attempt to read the amount requested
@@ -228,3 +566,179 @@ impl<Rx: Read, Tx: Write> ReadAmt for BufferedRxTx<Rx, Tx> {
         self.rx.read_amt(amount)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Crc8,
+        codec::Delimited,
+        mock::Wire,
+        packet::{FrameRecv, FrameSend, FrameTxRx},
+    };
+
+    #[test]
+    fn heapless_queue_stops_when_full() {
+        let mut q = heapless::Deque::<u8, 4>::new();
+        for b in 0..4 {
+            assert_eq!(ByteQueue::free(&q), 4 - b as usize);
+            ByteQueue::push_back(&mut q, b).unwrap();
+        }
+        assert!(ByteQueue::is_full(&q));
+        assert_eq!(ByteQueue::push_back(&mut q, 4), Err(4));
+        assert_eq!(ByteQueue::pop_front(&mut q), Some(0));
+        ByteQueue::push_back(&mut q, 4).unwrap();
+        // Wrapped round the end of the storage, but slices still start at the front
+        assert_eq!(ByteQueue::make_contiguous(&mut q), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn take_front_only_takes_what_it_says() {
+        let mut q = heapless::Deque::<u8, 8>::new();
+        for b in 0..6 {
+            ByteQueue::push_back(&mut q, b).unwrap();
+        }
+        // Dropped without looking at them, they're still gone
+        let mut drain = q.take_front(3);
+        assert_eq!(drain.next(), Some(0));
+        drop(drain);
+        assert_eq!(ByteQueue::as_slices(&q).0, [3, 4, 5]);
+        assert!(q.take_front(10).eq([3, 4, 5]));
+        assert!(ByteQueue::is_empty(&q));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn drain_still_hands_back_a_vec_deque_drain() {
+        let wire = Wire::new();
+        wire.push(&[1, 2, 3]);
+        let mut rx = BufferedRx::new(wire);
+        rx.buffer().unwrap();
+        let drained: alloc::collections::vec_deque::Drain<'_, u8> = rx.drain(2);
+        assert!(drained.eq([1, 2]));
+        assert_eq!(rx.slice(), [3]);
+    }
+
+    #[test]
+    fn frames_through_heapless_buffers() {
+        type Buf = heapless::Deque<u8, 64>;
+        let (a, b) = (Wire::new(), Wire::new());
+        let mut left = FrameTxRx::with_buffers(a.clone(), b.clone(), Delimited::<Crc8>::new(), Buf::new(), Buf::new());
+        let mut right = FrameTxRx::with_buffers(b, a, Delimited::<Crc8>::new(), Buf::new(), Buf::new());
+        for data in [&b"ping"[..], &[0x55; 20], b""] {
+            left.send(data).unwrap();
+            assert_eq!(right.recv().unwrap().data[..], *data);
+        }
+        right.send(b"pong").unwrap();
+        assert_eq!(left.recv().unwrap().data[..], *b"pong");
+    }
+
+    /// A BufferedRx holding at most 4 bytes, with 6 waiting on the wire
+    fn flooded(overflow: Overflow) -> (BufferedRx<Wire>, Wire) {
+        let wire = Wire::new();
+        wire.push(&[1, 2, 3, 4, 5, 6]);
+        let mut rx = BufferedRx::new(wire.clone());
+        rx.capacity = 4;
+        rx.overflow = overflow;
+        (rx, wire)
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest() {
+        let (mut rx, wire) = flooded(Overflow::DropOldest);
+        rx.buffer().unwrap();
+        assert_eq!(rx.slice(), [3, 4, 5, 6]);
+        assert_eq!(rx.dropped(), 2);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_first() {
+        let (mut rx, wire) = flooded(Overflow::DropNewest);
+        rx.buffer().unwrap();
+        assert_eq!(rx.slice(), [1, 2, 3, 4]);
+        assert_eq!(rx.dropped(), 2);
+        assert!(wire.is_empty());
+        rx.reset_dropped();
+        assert_eq!(rx.dropped(), 0);
+    }
+
+    #[test]
+    fn overflow_error_stops_at_the_first_lost_byte() {
+        let (mut rx, wire) = flooded(Overflow::Error);
+        assert!(matches!(rx.buffer(), Err(nb::Error::Other(FrameIOError::Overrun))));
+        assert_eq!(rx.slice(), [1, 2, 3, 4]);
+        assert_eq!(rx.dropped(), 1);
+        // The rest is left for later
+        assert_eq!(wire.len(), 1);
+    }
+
+    #[test]
+    fn buffer_reads_at_most_the_budget() {
+        let wire = Wire::new();
+        wire.push(&[0; 10]);
+        let mut rx = BufferedRx::new(wire.clone());
+        rx.budget = 4;
+        rx.buffer().unwrap();
+        assert_eq!((rx.slice().len(), wire.len()), (4, 6));
+        rx.buffer().unwrap();
+        assert_eq!((rx.slice().len(), wire.len()), (8, 2));
+    }
+
+    #[test]
+    fn link_stats_count_overruns() {
+        let (mut a, mut b) = crate::mock::link();
+        b.frx.rx.capacity = 8;
+        b.frx.rx.overflow = Overflow::DropNewest;
+        a.send(b"way more than eight bytes").unwrap();
+        assert!(matches!(b.recv(), Err(nb::Error::WouldBlock)));
+        let stats = b.stats();
+        assert_eq!(stats.overruns, stats.bytes_received - 8);
+        assert!(stats.overruns > 0);
+    }
+
+    #[test]
+    fn buffered_tx_pushes_back_when_full() {
+        let wire = Wire::new();
+        wire.set_room(2);
+        let mut tx = BufferedTx::new(wire.clone());
+        tx.capacity = 3;
+        for b in 0..5 {
+            Write::write(&mut tx, b).unwrap();
+        }
+        assert!(tx.is_full());
+        assert!(!embedded_io::WriteReady::write_ready(&mut tx).unwrap());
+        assert_eq!(Write::write(&mut tx, 5), Err(nb::Error::WouldBlock));
+        assert_eq!(wire.take(), [0, 1]);
+
+        // embedded_io takes what there's room for and says so
+        assert_eq!(embedded_io::Write::write(&mut tx, &[5, 6, 7, 8]).unwrap(), 2);
+        assert_eq!(tx.buf.len(), 3);
+        assert_eq!(wire.take(), [2, 3]);
+        assert_eq!(Write::flush(&mut tx), Err(nb::Error::WouldBlock));
+        assert_eq!(wire.take(), [4, 5]);
+        assert_eq!(Write::flush(&mut tx), Ok(()));
+        assert_eq!(wire.take(), [6]);
+    }
+
+    #[test]
+    fn write_all_queues_all_or_nothing() {
+        let wire = Wire::new();
+        wire.set_room(1);
+        let mut tx = BufferedTx::new(wire.clone());
+        tx.capacity = 4;
+        tx.write_all(&[0, 1, 2]).unwrap();
+        assert_eq!(wire.take(), [0]);
+        assert_eq!(tx.buf.len(), 2);
+        // Room for two more, even after the wire takes one
+        wire.set_room(1);
+        assert_eq!(tx.write_all(&[3, 4, 5, 6]), Err(nb::Error::WouldBlock));
+        assert_eq!(wire.take(), [1]);
+        assert_eq!(tx.buf.len(), 1);
+        tx.write_all(&[3, 4, 5]).unwrap();
+        assert_eq!(tx.write_all(&[0; 5]), Err(nb::Error::WouldBlock));
+        wire.set_room(usize::MAX);
+        assert_eq!(Write::flush(&mut tx), Ok(()));
+        assert_eq!(wire.take(), [2, 3, 4, 5]);
+    }
+}
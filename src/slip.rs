@@ -0,0 +1,217 @@
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::marker::PhantomData;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use slippers::{SlipDecoder, SlipEncoder};
+
+use crate::{
+    checksum::{Checksum, MAX_CHECKSUM_WIDTH, NoCrc},
+    codec::FrameCodec,
+    packet::{FrameError, FrameRef, MAX_DATA_SIZE, check_payload},
+};
+
+/// Byte that ends (and starts) every SLIP frame
+pub const SLIP_END: u8 = 0xC0;
+/// SLIP escape byte, followed by either SLIP_ESC_END or SLIP_ESC_ESC
+pub const SLIP_ESC: u8 = 0xDB;
+pub const SLIP_ESC_END: u8 = 0xDC;
+pub const SLIP_ESC_ESC: u8 = 0xDD;
+/// Start: 1, Data and CRC: up to 2 * (MAX_DATA_SIZE + MAX_CHECKSUM_WIDTH) once escaped, End: 1
+pub const SLIP_MAX_FRAME_SIZE: usize = 2 + 2 * (MAX_DATA_SIZE + MAX_CHECKSUM_WIDTH);
+
+/// Serial Line IP framing from RFC 1055, for talking to legacy devices and
+/// Linux `slattach` links.
+///
+/// Plain SLIP (the default, `Slip<NoCrc>`) has no size or CRC on the wire.
+/// Any other `Checksum` is tacked onto the end of the data before it is
+/// escaped as an extension, which both ends have to agree on.
+///
+/// Like Linux we send an END before each frame as well as after to flush
+/// out any line noise, and empty frames are ignored on the way in.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Slip<C: Checksum = NoCrc>(PhantomData<C>);
+
+impl<C: Checksum> Slip<C> {
+    pub const fn new() -> Slip<C> {
+        Slip(PhantomData)
+    }
+}
+
+impl<C: Checksum> FrameCodec for Slip<C> {
+    const MAX_FRAME_SIZE: usize = 2 + 2 * (MAX_DATA_SIZE + C::WIDTH);
+    const MAX_DATA_SIZE: usize = MAX_DATA_SIZE;
+
+    fn encode_with(&self, data: &[u8], mut out: impl FnMut(u8)) -> Result<usize, FrameError> {
+        check_payload(data.len(), MAX_DATA_SIZE)?;
+        let size = data.len();
+
+        // Line the data and crc up so the encoder can go over them in one go
+        let mut raw = [0; MAX_DATA_SIZE + MAX_CHECKSUM_WIDTH];
+        raw[..size].copy_from_slice(data);
+        C::write(C::checksum(&[size as u8], data), &mut raw[size..]);
+        let raw = &raw[..size + C::WIDTH];
+
+        // Start END, the escaped bytes, then the encoder's own END
+        out(SLIP_END);
+        let mut n = 1;
+        for b in SlipEncoder::new(raw).iter() {
+            out(b);
+            n += 1;
+        }
+
+        Ok(n)
+    }
+
+    fn decode_in_place<'a>(&self, data: &'a mut [u8]) -> Result<(FrameRef<'a>, usize), FrameError> {
+        // END never shows up escaped, so the first one we see ends the frame
+        let end = match data.iter().position(|b| *b == SLIP_END) {
+            Some(end) => end,
+            None if data.len() >= Self::MAX_FRAME_SIZE => {
+                // We've got more than a frame's worth and still no END
+                return Err(FrameError::MissingEndDelim {
+                    index: Self::MAX_FRAME_SIZE - 1,
+                    found: data[Self::MAX_FRAME_SIZE - 1],
+                });
+            }
+            None => {
+                return Err(FrameError::DecodeBufferTooSmall {
+                    expected_at_least: data.len() + 1,
+                    found: data.len(),
+                });
+            }
+        };
+        let frame = &data[..=end];
+
+        if slippers::check_for_errors(frame).is_err() {
+            let index = frame
+                .windows(2)
+                .position(|w| w[0] == SLIP_ESC && w[1] != SLIP_ESC_END && w[1] != SLIP_ESC_ESC)
+                .unwrap_or(0);
+            return Err(FrameError::InvalidEncoding { index });
+        }
+        let decoder = SlipDecoder::new(frame);
+        let n = decoder.iter().count();
+
+        // Last bytes are the crc, if there is one
+        if n < C::WIDTH {
+            return Err(FrameError::InvalidEncoding { index: 0 });
+        }
+        let size = n - C::WIDTH;
+        if size > MAX_DATA_SIZE {
+            return Err(FrameError::InvalidEncoding { index: end });
+        }
+
+        // Check the crc before we go decoding in place
+        let mut d = C::digest();
+        C::update(&mut d, &[size as u8]);
+        for b in decoder.iter().take(size) {
+            C::update(&mut d, &[b]);
+        }
+        let mut crc = [0; MAX_CHECKSUM_WIDTH];
+        for (c, b) in crc.iter_mut().zip(decoder.iter().skip(size)) {
+            *c = b;
+        }
+        let crc = C::read(&crc);
+        let calc_crc = C::finalize(d);
+        if crc != calc_crc {
+            return Err(FrameError::CrcMismatch {
+                calculated: calc_crc,
+                found: crc,
+                width: C::WIDTH,
+                #[cfg(feature = "alloc")]
+                buf: Vec::from(frame),
+            });
+        }
+
+        match slippers::decode_in_place(&mut data[..=end]) {
+            Ok((decoded, _)) => Ok((FrameRef { size: size as u16, header: None, data: &decoded[..size], crc }, end + 1)),
+            // Can't happen since we've checked for errors and the END is there
+            Err(_) => Err(FrameError::InvalidEncoding { index: 0 }),
+        }
+    }
+
+    fn skip_to_start(&self, data: &[u8]) -> usize {
+        // Back to back ENDs are empty frames, toss them
+        data.iter().take_while(|b| **b == SLIP_END).count()
+    }
+
+    fn only_separators(&self, skipped: &[u8]) -> bool {
+        skipped.iter().all(|b| *b == SLIP_END)
+    }
+
+    fn skip_after_error(&self, data: &[u8], _error: &FrameError) -> usize {
+        // Whatever went wrong, the next frame starts after the next END
+        data.iter()
+            .position(|b| *b == SLIP_END)
+            .map(|i| i + 1)
+            .unwrap_or(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Crc16Ccitt,
+        mock::Wire,
+        packet::{FrameIOError, FrameRecv, FrameRx, FrameSend, FrameTx},
+    };
+
+    fn encoded<C: Checksum>(data: &[u8]) -> ([u8; SLIP_MAX_FRAME_SIZE], usize) {
+        let mut buf = [0; SLIP_MAX_FRAME_SIZE];
+        let len = Slip::<C>::new().encode(data, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    #[test]
+    fn escapes_like_rfc_1055() {
+        let (buf, len) = encoded::<NoCrc>(&[SLIP_END, SLIP_ESC, 0x01]);
+        assert_eq!(buf[..len], [SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC, SLIP_ESC_ESC, 0x01, SLIP_END]);
+    }
+
+    #[test]
+    fn round_trips_with_a_crc() {
+        let codec = Slip::<Crc16Ccitt>::new();
+        let data = [SLIP_END; MAX_DATA_SIZE];
+        for size in [0, 1, MAX_DATA_SIZE] {
+            let (mut buf, len) = encoded::<Crc16Ccitt>(&data[..size]);
+            assert!(len <= Slip::<Crc16Ccitt>::MAX_FRAME_SIZE);
+            // Skip the leading END the way FrameRx would
+            let start = codec.skip_to_start(&buf[..len]);
+            let (frame, used) = codec.decode_in_place(&mut buf[start..len]).unwrap();
+            assert_eq!(frame.data, &data[..size]);
+            assert_eq!(start + used, len);
+        }
+    }
+
+    #[test]
+    fn bad_escape_is_invalid() {
+        let mut data = [0x01, SLIP_ESC, 0x02, SLIP_END];
+        assert!(matches!(
+            Slip::<NoCrc>::new().decode_in_place(&mut data),
+            Err(FrameError::InvalidEncoding { index: 1 })
+        ));
+    }
+
+    #[test]
+    fn resyncs_on_the_next_end() {
+        let wire = Wire::new();
+        let mut tx = FrameTx::with_codec(wire.clone(), Slip::<Crc16Ccitt>::new());
+        let mut rx = FrameRx::with_codec(wire.clone(), Slip::<Crc16Ccitt>::new());
+        // Line noise and back to back ENDs first
+        wire.push(&[SLIP_END, SLIP_END]);
+        tx.send(b"first").unwrap();
+        let (mut buf, len) = encoded::<Crc16Ccitt>(b"mangled");
+        buf[2] ^= 0x01;
+        wire.push(&buf[..len]);
+        tx.send(&[SLIP_ESC, SLIP_END]).unwrap();
+
+        assert_eq!(rx.recv().unwrap().data[..], *b"first");
+        assert!(matches!(rx.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        assert_eq!(rx.recv().unwrap().data[..], [SLIP_ESC, SLIP_END]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::packet::FrameError;
+
+/// Counts of what's gone over a link, for judging how healthy it is.
+///
+/// `FrameRx` and `FrameTx` each keep their own, `FrameTxRx::stats` adds the
+/// two together. Counters wrap rather than saturate, so report the
+/// difference between two snapshots or `take_stats` every so often.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStats {
+    pub frames_sent: u32,
+    pub frames_received: u32,
+    /// Bytes queued to go out on the wire, framing and all
+    pub bytes_sent: u32,
+    /// Bytes read off the wire, including any thrown away
+    pub bytes_received: u32,
+    pub crc_errors: u32,
+    pub early_end_delim: u32,
+    pub missing_end_delim: u32,
+    /// Partial frames given up on, see `FrameRx::with_timeout`
+    pub timeouts: u32,
+    /// Any other bad frame (early start delimiter, bad encoding, ...)
+    pub other_errors: u32,
+    /// Bytes thrown away while hunting for the start of a frame,
+    /// including what's left of bad frames
+    pub discarded: u32,
+    /// Bytes dropped because the receive buffer was full
+    pub overruns: u32,
+    /// Frames for some other address on the bus, skipped
+    pub filtered: u32,
+    pub write_errors: u32,
+}
+
+impl LinkStats {
+    /// Both sets of counts added together
+    pub fn merge(&self, other: &LinkStats) -> LinkStats {
+        LinkStats {
+            frames_sent: self.frames_sent.wrapping_add(other.frames_sent),
+            frames_received: self.frames_received.wrapping_add(other.frames_received),
+            bytes_sent: self.bytes_sent.wrapping_add(other.bytes_sent),
+            bytes_received: self.bytes_received.wrapping_add(other.bytes_received),
+            crc_errors: self.crc_errors.wrapping_add(other.crc_errors),
+            early_end_delim: self.early_end_delim.wrapping_add(other.early_end_delim),
+            missing_end_delim: self.missing_end_delim.wrapping_add(other.missing_end_delim),
+            timeouts: self.timeouts.wrapping_add(other.timeouts),
+            other_errors: self.other_errors.wrapping_add(other.other_errors),
+            discarded: self.discarded.wrapping_add(other.discarded),
+            overruns: self.overruns.wrapping_add(other.overruns),
+            filtered: self.filtered.wrapping_add(other.filtered),
+            write_errors: self.write_errors.wrapping_add(other.write_errors),
+        }
+    }
+
+    /// Count a bad frame under whichever error it was
+    pub(crate) fn frame_error(&mut self, error: &FrameError) {
+        let counter = match error {
+            FrameError::CrcMismatch { .. } => &mut self.crc_errors,
+            FrameError::EarlyEndDelim { .. } => &mut self.early_end_delim,
+            FrameError::MissingEndDelim { .. } => &mut self.missing_end_delim,
+            FrameError::Timeout => &mut self.timeouts,
+            _ => &mut self.other_errors,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// `*counter += n`, wrapping
+pub(crate) fn count(counter: &mut u32, n: usize) {
+    *counter = counter.wrapping_add(n as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_land_in_their_own_counter() {
+        let mut stats = LinkStats::default();
+        stats.frame_error(&FrameError::EarlyEndDelim { found_at: 1, expected: 2 });
+        stats.frame_error(&FrameError::MissingEndDelim { index: 2, found: 0 });
+        stats.frame_error(&FrameError::Timeout);
+        stats.frame_error(&FrameError::EarlyStartDelim { found_at: 3 });
+        stats.frame_error(&FrameError::Timeout);
+        assert_eq!(
+            stats,
+            LinkStats { early_end_delim: 1, missing_end_delim: 1, timeouts: 2, other_errors: 1, ..Default::default() }
+        );
+    }
+
+    #[test]
+    fn merge_adds_and_wraps() {
+        let a = LinkStats { frames_sent: 2, bytes_received: u32::MAX, crc_errors: 1, ..Default::default() };
+        let b = LinkStats { frames_sent: 3, bytes_received: 2, write_errors: 4, ..Default::default() };
+        assert_eq!(
+            a.merge(&b),
+            LinkStats { frames_sent: 5, bytes_received: 1, crc_errors: 1, write_errors: 4, ..Default::default() }
+        );
+    }
+}
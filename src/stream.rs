@@ -0,0 +1,237 @@
+use core::convert::Infallible;
+
+use embedded_io::{Read, ReadReady, Write, WriteReady};
+
+use crate::{
+    codec::{Delimited, FrameCodec},
+    packet::{Frame, FrameError, FrameIOError, decode_queued},
+    serial::{ByteQueue, DefaultBuffer},
+};
+
+/// Most bytes asked of the reader at once
+const READ_CHUNK: usize = 64;
+
+/// `FrameRx` for `embedded_io` byte streams, e.g. DMA backed UARTs or
+/// `std::io` streams, which hand over whole chunks rather than a word at a time.
+///
+/// `ReadReady` is what keeps this from blocking: we only read when it says
+/// a read will return straight away.
+pub struct StreamFrameRx<Rx: Read + ReadReady, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer> {
+    pub rx: Rx,
+    pub codec: C,
+    pub buf: B,
+}
+
+impl<Rx: Read + ReadReady> StreamFrameRx<Rx> {
+    pub fn new(rx: Rx) -> StreamFrameRx<Rx> {
+        StreamFrameRx::with_codec(rx, Delimited::new())
+    }
+}
+
+impl<Rx: Read + ReadReady, C: FrameCodec> StreamFrameRx<Rx, C> {
+    pub fn with_codec(rx: Rx, codec: C) -> StreamFrameRx<Rx, C> {
+        StreamFrameRx::with_buffer(rx, codec, DefaultBuffer::default())
+    }
+}
+
+impl<Rx: Read + ReadReady, C: FrameCodec, B: ByteQueue> StreamFrameRx<Rx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits
+    pub fn with_buffer(rx: Rx, codec: C, buf: B) -> StreamFrameRx<Rx, C, B> {
+        StreamFrameRx { rx, codec, buf }
+    }
+
+    /// Read whatever's ready into the buffer, until rx isn't ready or we're full
+    pub fn buffer(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        while !self.buf.is_full() {
+            match self.read_chunk() {
+                Ok(()) => (),
+                Err(nb::Error::WouldBlock) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Next frame out of the buffer, reading more in as long as rx is ready
+    pub fn recv(&mut self) -> nb::Result<Frame, FrameIOError<Infallible, Rx::Error>> {
+        loop {
+            if let Some(f) = decode_queued(&self.codec, &mut self.buf).map_err(|e| nb::Error::Other(e.into()))? {
+                return Ok(f);
+            }
+            self.read_chunk()?;
+        }
+    }
+
+    fn read_chunk(&mut self) -> nb::Result<(), FrameIOError<Infallible, Rx::Error>> {
+        if !self.rx.read_ready().map_err(|e| nb::Error::Other(FrameIOError::Read(e)))? {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let want = READ_CHUNK.min(self.buf.free());
+        let n = self.rx.read(&mut chunk[..want]).map_err(|e| nb::Error::Other(FrameIOError::Read(e)))?;
+        // Ready but nothing there is the end of the stream
+        if n == 0 {
+            return Err(nb::Error::Other(FrameIOError::Eof));
+        }
+        for b in &chunk[..n] {
+            let _ = self.buf.push_back(*b);
+        }
+        Ok(())
+    }
+}
+
+/// `FrameTx` for `embedded_io` byte streams. Frames are encoded into `buf`
+/// and written out in chunks whenever `WriteReady` says tx will take them.
+pub struct StreamFrameTx<Tx: Write + WriteReady, C: FrameCodec = Delimited, B: ByteQueue = DefaultBuffer> {
+    pub tx: Tx,
+    pub codec: C,
+    pub buf: B,
+}
+
+impl<Tx: Write + WriteReady> StreamFrameTx<Tx> {
+    pub fn new(tx: Tx) -> StreamFrameTx<Tx> {
+        StreamFrameTx::with_codec(tx, Delimited::new())
+    }
+}
+
+impl<Tx: Write + WriteReady, C: FrameCodec> StreamFrameTx<Tx, C> {
+    pub fn with_codec(tx: Tx, codec: C) -> StreamFrameTx<Tx, C> {
+        StreamFrameTx::with_buffer(tx, codec, DefaultBuffer::default())
+    }
+}
+
+impl<Tx: Write + WriteReady, C: FrameCodec, B: ByteQueue> StreamFrameTx<Tx, C, B> {
+    /// Buffer into `buf`, e.g. a `heapless::Deque<u8, N>` of whatever size suits
+    pub fn with_buffer(tx: Tx, codec: C, buf: B) -> StreamFrameTx<Tx, C, B> {
+        StreamFrameTx { tx, codec, buf }
+    }
+
+    /// Queue `data` up as a frame and start writing it out.
+    ///
+    /// The whole frame goes in the buffer or none of it does. WouldBlock
+    /// means there isn't room until more has been written out, and
+    /// `EncodeBufferTooSmall` means there never will be.
+    pub fn send(&mut self, data: &[u8]) -> nb::Result<(), FrameIOError<Tx::Error, Infallible>> {
+        let required = self.codec.encoded_len(data).map_err(|e| nb::Error::Other(e.into()))?;
+        if required > self.buf.free() {
+            let _ = self.flush();
+        }
+        if required > self.buf.free() {
+            let capacity = self.buf.len().saturating_add(self.buf.free());
+            if required > capacity {
+                return Err(nb::Error::Other(
+                    FrameError::EncodeBufferTooSmall { expected_at_least: required, found: capacity }.into(),
+                ));
+            }
+            return Err(nb::Error::WouldBlock);
+        }
+        let buf = &mut self.buf;
+        self.codec
+            .encode_with(data, |b| {
+                let _ = buf.push_back(b);
+            })
+            .map_err(|e| nb::Error::Other(e.into()))?;
+        match self.flush() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(FrameIOError::Write(e))),
+        }
+    }
+
+    /// Write out as much of the buffer as tx is ready for. WouldBlock means
+    /// some of it's still waiting.
+    pub fn flush(&mut self) -> nb::Result<(), Tx::Error> {
+        while !self.buf.is_empty() {
+            if !self.tx.write_ready()? {
+                return Err(nb::Error::WouldBlock);
+            }
+            let (front, _) = self.buf.as_slices();
+            let n = self.tx.write(front)?;
+            if n == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.buf.drain_front(n);
+        }
+        self.tx.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Wire;
+
+    fn pair() -> (StreamFrameTx<Wire>, StreamFrameRx<Wire>, Wire) {
+        let wire = Wire::new();
+        (StreamFrameTx::new(wire.clone()), StreamFrameRx::new(wire.clone()), wire)
+    }
+
+    #[test]
+    fn frames_go_over_in_chunks() {
+        let (mut tx, mut rx, wire) = pair();
+        // More than one READ_CHUNK's worth queued up at once
+        let big = [0x55; 200];
+        tx.send(&big).unwrap();
+        tx.send(b"small").unwrap();
+        assert!(wire.len() > READ_CHUNK);
+        assert_eq!(rx.recv().unwrap().data[..], big);
+        assert_eq!(rx.recv().unwrap().data[..], *b"small");
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let (mut tx, mut rx, wire) = pair();
+        tx.send(b"hello").unwrap();
+        let bytes = wire.take();
+        wire.push(&bytes[..3]);
+        assert!(matches!(rx.recv(), Err(nb::Error::WouldBlock)));
+        wire.push(&bytes[3..]);
+        assert_eq!(rx.recv().unwrap().data[..], *b"hello");
+    }
+
+    #[test]
+    fn bad_frames_resync() {
+        let (mut tx, mut rx, wire) = pair();
+        tx.send(b"one").unwrap();
+        let mut bytes = wire.take();
+        bytes[3] ^= 0x01;
+        wire.push(b"junk");
+        wire.push(&bytes);
+        tx.send(b"two").unwrap();
+        assert!(matches!(rx.recv(), Err(nb::Error::Other(FrameIOError::Frame(FrameError::CrcMismatch { .. })))));
+        assert_eq!(rx.recv().unwrap().data[..], *b"two");
+    }
+
+    #[test]
+    fn hanging_up_is_eof() {
+        let (mut tx, mut rx, wire) = pair();
+        tx.send(b"last").unwrap();
+        wire.close();
+        assert_eq!(rx.recv().unwrap().data[..], *b"last");
+        assert!(matches!(rx.recv(), Err(nb::Error::Other(FrameIOError::Eof))));
+    }
+
+    #[test]
+    fn buffer_stops_when_full() {
+        let wire = Wire::new();
+        let mut rx = StreamFrameRx::with_buffer(wire.clone(), Delimited::<crate::Crc8>::new(), heapless::Deque::<u8, 16>::new());
+        wire.push(&[0; 40]);
+        rx.buffer().unwrap();
+        assert_eq!(rx.buf.len(), 16);
+        assert_eq!(wire.len(), 24);
+    }
+
+    #[test]
+    fn frames_bigger_than_the_buffer_are_refused() {
+        let wire = Wire::new();
+        let mut tx = StreamFrameTx::with_buffer(wire.clone(), Delimited::<crate::Crc8>::new(), heapless::Deque::<u8, 8>::new());
+        assert!(matches!(
+            tx.send(b"far too long"),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::EncodeBufferTooSmall { found: 8, .. })))
+        ));
+        assert!(wire.is_empty());
+        tx.send(b"ok").unwrap();
+        assert_eq!(wire.len(), 6);
+    }
+}
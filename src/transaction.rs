@@ -0,0 +1,516 @@
+use core::convert::Infallible;
+
+use embedded_hal_nb::serial::{Read, Write};
+
+use crate::{
+    clock::{Clock, NoClock},
+    packet::{FrameError, FrameIOError, FrameRecv, FrameSend, MAX_DATA_SIZE, frame_data_limit, frame_data_with},
+};
+
+/// Fragment is the first of its transaction
+pub const FRAGMENT_FIRST: u8 = 0b01;
+/// Fragment is the last of its transaction. A message that fits in one
+/// frame is both first and last, and neither means a middle fragment.
+pub const FRAGMENT_LAST: u8 = 0b10;
+/// Flags, transaction id and fragment index
+pub const FRAGMENT_HEADER_LEN: usize = 3;
+/// Message bytes in a full fragment on a plain `Delimited` link
+pub const DEFAULT_FRAGMENT_SIZE: usize = MAX_DATA_SIZE - FRAGMENT_HEADER_LEN;
+/// Fragment index is a u8
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize + 1;
+/// Default for the most a `Reassembler` will put back together
+pub const DEFAULT_MESSAGE_SIZE: usize = 4 * DEFAULT_FRAGMENT_SIZE;
+
+/// Splits messages bigger than a frame can carry into a transaction of
+/// numbered fragments.
+///
+/// Each frame starts with a three byte header: flags (`FRAGMENT_FIRST`,
+/// `FRAGMENT_LAST`), the transaction id, and the fragment's index within
+/// the transaction. The transaction id goes up by one for each message.
+#[derive(Debug, Clone)]
+pub struct Fragmenter {
+    fragment_size: usize,
+    next_transaction: u8,
+    /// Transaction and index of the next fragment, if tx filled up partway through
+    sending: Option<(u8, usize)>,
+}
+
+impl Default for Fragmenter {
+    fn default() -> Self {
+        Fragmenter::new()
+    }
+}
+
+impl Fragmenter {
+    /// Fragments as big as whatever they're sent over takes
+    pub fn new() -> Fragmenter {
+        Fragmenter::with_fragment_size(usize::MAX)
+    }
+
+    /// Put at most `fragment_size` message bytes in each frame, e.g. to keep
+    /// frames short on a noisy line. Capped at what fits in a frame of
+    /// whatever they're sent over, see `FrameSend::max_payload`.
+    pub fn with_fragment_size(fragment_size: usize) -> Fragmenter {
+        Fragmenter {
+            fragment_size: fragment_size.max(1),
+            next_transaction: 0,
+            sending: None,
+        }
+    }
+
+    /// Message bytes that go in each fragment sent over `tx`
+    pub fn fragment_size<Tx: Write, S: FrameSend<Tx>>(&self, tx: &S) -> usize {
+        let room = frame_data_limit(tx.max_payload()).saturating_sub(FRAGMENT_HEADER_LEN);
+        self.fragment_size.min(room).max(1)
+    }
+
+    /// Most bytes a single message sent over `tx` can be
+    pub fn max_message_size<Tx: Write, S: FrameSend<Tx>>(&self, tx: &S) -> usize {
+        MAX_FRAGMENTS * self.fragment_size(tx)
+    }
+
+    /// Send `message` as one transaction, returning its id once every
+    /// fragment has gone into `tx`.
+    ///
+    /// WouldBlock means `tx` filled up partway through. Call again with the
+    /// same message to carry on from the fragment that didn't fit.
+    pub fn send<Tx: Write, S: FrameSend<Tx>>(
+        &mut self,
+        tx: &mut S,
+        message: &[u8],
+    ) -> nb::Result<u8, FrameIOError<Tx::Error, Infallible>> {
+        let fragment_size = self.fragment_size(tx);
+        let max = MAX_FRAGMENTS * fragment_size;
+        if message.len() > max {
+            self.sending = None;
+            return Err(nb::Error::Other(FrameError::PayloadTooLarge { size: message.len(), max }.into()));
+        }
+        let (transaction, first) = self.sending.unwrap_or_else(|| {
+            let transaction = self.next_transaction;
+            self.next_transaction = transaction.wrapping_add(1);
+            (transaction, 0)
+        });
+
+        // An empty message still goes as a single (empty) fragment
+        let count = message.len().div_ceil(fragment_size).max(1);
+        let mut chunks = message.chunks(fragment_size).skip(first);
+        for index in first..count {
+            let chunk = chunks.next().unwrap_or_default();
+            let mut flags = 0;
+            if index == 0 {
+                flags |= FRAGMENT_FIRST;
+            }
+            if index == count - 1 {
+                flags |= FRAGMENT_LAST;
+            }
+            let fragment =
+                frame_data_with(&[flags, transaction, index as u8], chunk).map_err(|e| nb::Error::Other(e.into()))?;
+            match tx.send(&fragment) {
+                Ok(()) => (),
+                Err(nb::Error::WouldBlock) => {
+                    self.sending = Some((transaction, index));
+                    return Err(nb::Error::WouldBlock);
+                },
+                Err(e) => {
+                    self.sending = None;
+                    return Err(e);
+                },
+            }
+        }
+        self.sending = None;
+        Ok(transaction)
+    }
+}
+
+/// Why a transaction couldn't be put back together. Whatever had been
+/// reassembled so far is thrown away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// Frame too short to have a fragment header
+    InvalidHeader,
+    /// Fragments between `expected` and `found` never showed up
+    Missing { transaction: u8, expected: u8, found: u8 },
+    /// Fragment `found` came in after we'd moved on past it
+    OutOfOrder { transaction: u8, expected: u8, found: u8 },
+    /// A middle or last fragment with no first fragment before it
+    Unexpected { transaction: u8, index: u8 },
+    /// A new transaction started before `transaction` got its last fragment.
+    /// The new one is kept.
+    Incomplete { transaction: u8 },
+    /// The message is bigger than the Reassembler has room for
+    TooLarge { transaction: u8, max: usize },
+    /// No fragment of `transaction` for longer than the timeout
+    Timeout { transaction: u8 },
+}
+
+#[derive(Debug)]
+pub enum TransactionError<ReadError> {
+    Link(FrameIOError<Infallible, ReadError>),
+    Fragment(FragmentError),
+}
+
+impl<Er> From<FragmentError> for TransactionError<Er> {
+    fn from(value: FragmentError) -> Self {
+        TransactionError::Fragment(value)
+    }
+}
+
+/// The transaction we're partway through
+#[derive(Debug, Clone, Copy)]
+struct InProgress {
+    transaction: u8,
+    /// Index of the fragment we want next
+    expected: u8,
+    /// When the last fragment came in
+    last_at: u32,
+}
+
+/// Puts transactions from a `Fragmenter` back together into messages of
+/// up to `N` bytes.
+///
+/// Fragments have to arrive in order. Anything missing, out of order or
+/// too slow (see `with_timeout`) drops the whole transaction.
+pub struct Reassembler<const N: usize = DEFAULT_MESSAGE_SIZE, K: Clock = NoClock> {
+    buf: [u8; N],
+    len: usize,
+    current: Option<InProgress>,
+    /// A message finished on the same fragment that reported an error,
+    /// so it's handed out on the next call instead
+    ready: bool,
+    pub clock: K,
+    /// Ticks to wait between fragments before giving up on a transaction
+    pub timeout: Option<u32>,
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Reassembler::new()
+    }
+}
+
+impl<const N: usize> Reassembler<N> {
+    /// Waits on the rest of a transaction for as long as it takes
+    pub fn new() -> Reassembler<N> {
+        Reassembler {
+            buf: [0; N],
+            len: 0,
+            current: None,
+            ready: false,
+            clock: NoClock,
+            timeout: None,
+        }
+    }
+}
+
+impl<const N: usize, K: Clock> Reassembler<N, K> {
+    /// Drop a transaction once `timeout` ticks of `clock` go by without
+    /// another fragment, e.g. the link's clock again
+    pub fn with_timeout<K2: Clock>(self, clock: K2, timeout: u32) -> Reassembler<N, K2> {
+        Reassembler {
+            buf: self.buf,
+            len: self.len,
+            current: self.current,
+            ready: self.ready,
+            clock,
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Receive fragments out of `rx` until a whole message is in.
+    pub fn recv<Rx: Read, R: FrameRecv<Rx>>(&mut self, rx: &mut R) -> nb::Result<&[u8], TransactionError<Rx::Error>> {
+        if self.ready {
+            self.ready = false;
+            return Ok(&self.buf[..self.len]);
+        }
+        self.check_timeout().map_err(|e| nb::Error::Other(e.into()))?;
+        loop {
+            let frame = rx.recv().map_err(|e| e.map(TransactionError::Link))?;
+            if self.fragment(&frame.data).map_err(|e| nb::Error::Other(e.into()))? {
+                break;
+            }
+        }
+        Ok(&self.buf[..self.len])
+    }
+
+    /// Hand in the data of a frame that came some other way, e.g. out of a
+    /// `ReliableLink`. Returns the message once its last fragment is in.
+    ///
+    /// After `FragmentError::Incomplete` check `ready`, the fragment that
+    /// started the new transaction may have been all of it.
+    pub fn push(&mut self, data: &[u8]) -> Result<Option<&[u8]>, FragmentError> {
+        self.check_timeout()?;
+        if self.fragment(data)? {
+            Ok(Some(&self.buf[..self.len]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// A message that was completed by the same fragment that reported
+    /// `FragmentError::Incomplete`. `recv` hands these out itself.
+    pub fn ready(&mut self) -> Option<&[u8]> {
+        if !self.ready {
+            return None;
+        }
+        self.ready = false;
+        Some(&self.buf[..self.len])
+    }
+
+    /// Drop the transaction in progress if it's been waiting too long
+    pub fn check_timeout(&mut self) -> Result<(), FragmentError> {
+        if let Some(c) = self.current
+            && let Some(timeout) = self.timeout
+            && self.clock.now().wrapping_sub(c.last_at) >= timeout
+        {
+            self.current = None;
+            return Err(FragmentError::Timeout { transaction: c.transaction });
+        }
+        Ok(())
+    }
+
+    /// Add a fragment, returning true once the message is complete
+    fn fragment(&mut self, data: &[u8]) -> Result<bool, FragmentError> {
+        let now = self.clock.now();
+        let (flags, transaction, index, chunk) = match data {
+            [flags, transaction, index, chunk @ ..] => (*flags, *transaction, *index, chunk),
+            _ => return Err(FragmentError::InvalidHeader),
+        };
+
+        // Anything ready and not picked up is about to be overwritten
+        self.ready = false;
+        let mut result = Ok(false);
+        if flags & FRAGMENT_FIRST != 0 {
+            // Starting over, but let on if that means losing one
+            if let Some(c) = self.current {
+                result = Err(FragmentError::Incomplete { transaction: c.transaction });
+            }
+            if index != 0 {
+                self.current = None;
+                return Err(FragmentError::Unexpected { transaction, index });
+            }
+            self.current = Some(InProgress { transaction, expected: 0, last_at: now });
+            self.len = 0;
+        }
+
+        let Some(c) = self.current else {
+            return Err(FragmentError::Unexpected { transaction, index });
+        };
+        if c.transaction != transaction {
+            self.current = None;
+            return Err(FragmentError::Unexpected { transaction, index });
+        }
+        if index != c.expected {
+            self.current = None;
+            let (expected, found) = (c.expected, index);
+            return Err(if index > c.expected {
+                FragmentError::Missing { transaction, expected, found }
+            } else {
+                FragmentError::OutOfOrder { transaction, expected, found }
+            });
+        }
+        if self.len + chunk.len() > N {
+            self.current = None;
+            return Err(FragmentError::TooLarge { transaction, max: N });
+        }
+        self.buf[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+        self.len += chunk.len();
+
+        if flags & FRAGMENT_LAST != 0 {
+            self.current = None;
+            self.ready = result.is_err();
+            return result.map(|_| true);
+        }
+        // The last index is 255, so there can't be another after it
+        if index == u8::MAX {
+            self.current = None;
+            return Err(FragmentError::TooLarge { transaction, max: N });
+        }
+        self.current = Some(InProgress { transaction, expected: index + 1, last_at: now });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Crc8,
+        codec::{EXTENDED_MAX_DATA_SIZE, Extended},
+        header::FRAME_HEADER_LEN,
+        mock::{Ticks, Wire, link},
+        mux::Mux,
+        packet::{FrameRx, FrameTx},
+    };
+
+    const FIRST: u8 = FRAGMENT_FIRST;
+    const LAST: u8 = FRAGMENT_LAST;
+
+    #[test]
+    fn splits_and_puts_back_together() {
+        let (mut a, mut b) = link();
+        let mut fragmenter = Fragmenter::with_fragment_size(4);
+        let mut reassembler = Reassembler::<16>::new();
+        let message = *b"split in three";
+        assert_eq!(fragmenter.send(&mut a, &message).unwrap(), 0);
+        assert_eq!(reassembler.recv(&mut b).unwrap(), message);
+        // Next message is the next transaction
+        assert_eq!(fragmenter.send(&mut a, b"").unwrap(), 1);
+        assert_eq!(reassembler.recv(&mut b).unwrap(), b"");
+    }
+
+    #[test]
+    fn fragments_have_headers() {
+        let (mut a, mut b) = link();
+        Fragmenter::with_fragment_size(2).send(&mut a, b"abcde").unwrap();
+        let headers = [[FIRST, 0, 0], [0, 0, 1], [LAST, 0, 2]];
+        for (header, chunk) in headers.iter().zip([&b"ab"[..], b"cd", b"e"]) {
+            let frame = b.recv().unwrap();
+            assert_eq!(frame.data[..FRAGMENT_HEADER_LEN], *header);
+            assert_eq!(frame.data[FRAGMENT_HEADER_LEN..], *chunk);
+        }
+    }
+
+    #[test]
+    fn fragments_are_as_big_as_the_sender_takes() {
+        let fragmenter = Fragmenter::new();
+        let (a, _) = link();
+        assert_eq!(fragmenter.fragment_size(&a), DEFAULT_FRAGMENT_SIZE);
+        let tx = FrameTx::with_codec(Wire::new(), Extended::<Crc8>::new());
+        #[cfg(feature = "alloc")]
+        assert_eq!(fragmenter.fragment_size(&tx), EXTENDED_MAX_DATA_SIZE - FRAGMENT_HEADER_LEN);
+        // Only ever MAX_DATA_SIZE in a FrameData
+        #[cfg(not(feature = "alloc"))]
+        assert_eq!(fragmenter.fragment_size(&tx), DEFAULT_FRAGMENT_SIZE);
+        assert_eq!(Fragmenter::with_fragment_size(4).fragment_size(&tx), 4);
+    }
+
+    #[test]
+    fn full_fragments_fit_on_a_mux_channel() {
+        let (a, b) = link();
+        let (a, b): (Mux<_, _>, Mux<_, _>) = (Mux::new(a), Mux::new(b));
+        let (mut tx, mut rx) = (a.channel(1).unwrap(), b.channel(1).unwrap());
+        let mut fragmenter = Fragmenter::new();
+        assert_eq!(fragmenter.fragment_size(&tx), DEFAULT_FRAGMENT_SIZE - FRAME_HEADER_LEN);
+
+        let message = [0x5A; 600];
+        fragmenter.send(&mut tx, &message).unwrap();
+        let mut reassembler = Reassembler::<600>::new();
+        let got = loop {
+            match reassembler.recv(&mut rx) {
+                Ok(got) => break got,
+                Err(nb::Error::WouldBlock) => (),
+                Err(e) => panic!("{e:?}"),
+            }
+        };
+        assert_eq!(got, message);
+    }
+
+    #[test]
+    fn too_big_to_send() {
+        let (mut a, _) = link();
+        let mut fragmenter = Fragmenter::with_fragment_size(1);
+        let message = [0; MAX_FRAGMENTS + 1];
+        assert!(matches!(
+            fragmenter.send(&mut a, &message),
+            Err(nb::Error::Other(FrameIOError::Frame(FrameError::PayloadTooLarge { max: MAX_FRAGMENTS, .. })))
+        ));
+    }
+
+    #[test]
+    fn missing_and_out_of_order() {
+        let mut r = Reassembler::<16>::new();
+        assert_eq!(r.push(&[FIRST, 7, 0, 1]), Ok(None));
+        assert_eq!(r.push(&[0, 7, 2, 3]), Err(FragmentError::Missing { transaction: 7, expected: 1, found: 2 }));
+        // The transaction's gone, so its last fragment has nothing to go on
+        assert_eq!(r.push(&[LAST, 7, 3, 4]), Err(FragmentError::Unexpected { transaction: 7, index: 3 }));
+
+        assert_eq!(r.push(&[FIRST, 8, 0, 1]), Ok(None));
+        assert_eq!(r.push(&[0, 8, 1, 2]), Ok(None));
+        assert_eq!(r.push(&[0, 8, 0, 1]), Err(FragmentError::OutOfOrder { transaction: 8, expected: 2, found: 0 }));
+        assert_eq!(r.push(&[LAST]), Err(FragmentError::InvalidHeader));
+    }
+
+    #[test]
+    fn new_transaction_cuts_the_last_one_short() {
+        let mut r = Reassembler::<16>::new();
+        assert_eq!(r.push(&[FIRST, 1, 0, 1]), Ok(None));
+        // A whole message in one fragment, but the one before it is lost
+        assert_eq!(r.push(&[FIRST | LAST, 2, 0, 9]), Err(FragmentError::Incomplete { transaction: 1 }));
+        assert_eq!(r.ready(), Some(&[9][..]));
+        assert_eq!(r.ready(), None);
+    }
+
+    #[test]
+    fn bigger_than_the_buffer() {
+        let mut r = Reassembler::<4>::new();
+        assert_eq!(r.push(&[FIRST, 3, 0, 1, 2, 3]), Ok(None));
+        assert_eq!(r.push(&[LAST, 3, 1, 4, 5]), Err(FragmentError::TooLarge { transaction: 3, max: 4 }));
+        assert_eq!(r.push(&[FIRST | LAST, 4, 0, 1, 2, 3, 4]), Ok(Some(&[1, 2, 3, 4][..])));
+    }
+
+    #[test]
+    fn slow_transactions_time_out() {
+        let clock = Ticks::new();
+        let mut r = Reassembler::<16>::new().with_timeout(clock.clone(), 10);
+        assert_eq!(r.push(&[FIRST, 5, 0, 1]), Ok(None));
+        clock.set(9);
+        assert_eq!(r.push(&[0, 5, 1, 2]), Ok(None));
+        // Ten ticks since the last fragment, not the first
+        clock.set(18);
+        assert_eq!(r.check_timeout(), Ok(()));
+        clock.set(19);
+        assert_eq!(r.push(&[LAST, 5, 2, 3]), Err(FragmentError::Timeout { transaction: 5 }));
+    }
+
+    #[test]
+    fn no_timeout_waits_forever() {
+        let mut r = Reassembler::<16>::new();
+        assert_eq!(r.push(&[FIRST, 5, 0, 1]), Ok(None));
+        assert_eq!(r.check_timeout(), Ok(()));
+        assert_eq!(r.push(&[LAST, 5, 1, 2]), Ok(Some(&[1, 2][..])));
+    }
+
+    #[test]
+    fn recv_reports_then_carries_on() {
+        let (mut a, mut b) = link();
+        a.send(&[0, 1, 1, 0xAA]).unwrap();
+        Fragmenter::new().send(&mut a, b"fine").unwrap();
+        let mut r = Reassembler::<16>::new();
+        assert!(matches!(
+            r.recv(&mut b),
+            Err(nb::Error::Other(TransactionError::Fragment(FragmentError::Unexpected { transaction: 1, index: 1 })))
+        ));
+        assert_eq!(r.recv(&mut b).unwrap(), b"fine");
+        assert!(matches!(r.recv(&mut b), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn carries_on_after_tx_fills_up() {
+        let wire = Wire::new();
+        wire.set_room(8);
+        let mut tx = FrameTx::new(wire.clone());
+        tx.tx.capacity = 24;
+        let mut rx = FrameRx::new(wire.clone());
+        let mut fragmenter = Fragmenter::with_fragment_size(4);
+        let mut reassembler = Reassembler::<32>::new();
+        let message = *b"more than the queue holds";
+
+        assert!(matches!(fragmenter.send(&mut tx, &message), Err(nb::Error::WouldBlock)));
+        let mut sent = None;
+        let got = loop {
+            let _ = rx.buffer();
+            match reassembler.recv(&mut rx) {
+                Ok(got) => break got,
+                Err(nb::Error::WouldBlock) => (),
+                Err(e) => panic!("{e:?}"),
+            }
+            if sent.is_none() {
+                sent = fragmenter.send(&mut tx, &message).ok();
+            }
+            let _ = tx.flush();
+        };
+        assert_eq!(got, message);
+        // Still the one transaction, picked up where it left off
+        assert_eq!(sent, Some(0));
+    }
+}
@@ -0,0 +1,130 @@
+//! `#[derive(Encode, Decode)]` as a user of the crate sees it. The derive
+//! names everything through `::embed_serial_protocol`, so it can only be
+//! tried out from outside the crate.
+#![cfg(feature = "derive")]
+
+use embed_serial_protocol::{Be, Bytes, Decode, Encode, FrameError, encoding::BYTES_LEN_PREFIX};
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct Position {
+    x: i16,
+    y: Be<u16>,
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct Move {
+    id: u8,
+    to: Position,
+    speeds: [u16; 2],
+    until: Option<u32>,
+    relative: bool,
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct Pair(u8, Be<u32>);
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+#[repr(u16)]
+enum Command {
+    Stop,
+    Go(Position),
+    Jump { height: u8 } = 0x0100,
+    After,
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct Write<'a> {
+    address: u16,
+    data: &'a [u8],
+    check: u8,
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct Chunks<'a> {
+    name: Option<Bytes<'a>>,
+    parts: [Bytes<'a>; 2],
+    tagged: (u8, Bytes<'a>),
+}
+
+fn round_trip<'a, T>(value: &T, buf: &'a mut [u8]) -> (&'a [u8], T)
+where
+    T: Encode<Error = FrameError> + Decode<'a, Error = FrameError>,
+{
+    let n = value.encode(buf).unwrap();
+    let bytes = &buf[..n];
+    (bytes, T::decode(bytes).unwrap())
+}
+
+#[test]
+fn structs_pack_fields_in_order() {
+    let value = Move {
+        id: 9,
+        to: Position { x: -2, y: Be(0x0102) },
+        speeds: [0x0304, 0x0506],
+        until: Some(7),
+        relative: true,
+    };
+    let mut buf = [0; 32];
+    let (bytes, back) = round_trip(&value, &mut buf);
+    assert_eq!(bytes, [9, 0xFE, 0xFF, 0x01, 0x02, 0x04, 0x03, 0x06, 0x05, 1, 7, 0, 0, 0, 1]);
+    assert_eq!(back, value);
+
+    let mut buf = [0; 8];
+    let (bytes, back) = round_trip(&Pair(1, Be(2)), &mut buf);
+    assert_eq!(bytes, [1, 0, 0, 0, 2]);
+    assert_eq!(back, Pair(1, Be(2)));
+}
+
+#[test]
+fn enums_lead_with_their_repr_discriminant() {
+    let cases: [(Command, &[u8]); 4] = [
+        (Command::Stop, &[0, 0]),
+        (Command::Go(Position { x: 1, y: Be(2) }), &[1, 0, 1, 0, 0, 2]),
+        // Explicit, then counting on from it
+        (Command::Jump { height: 5 }, &[0, 1, 5]),
+        (Command::After, &[1, 1]),
+    ];
+    for (value, expected) in cases {
+        let mut buf = [0; 8];
+        let (bytes, back) = round_trip(&value, &mut buf);
+        assert_eq!(bytes, expected);
+        assert_eq!(back, value);
+    }
+}
+
+#[test]
+fn unknown_discriminants_are_invalid() {
+    assert!(matches!(Command::decode(&[2, 0]), Err(FrameError::InvalidEncoding { index: 0 })));
+}
+
+#[test]
+fn byte_fields_are_borrowed() {
+    let value = Write { address: 0x10, data: b"abc", check: 0xEE };
+    let mut buf = [0; 16];
+    let n = value.encode(&mut buf).unwrap();
+    assert_eq!(buf[..n], [0x10, 0, 3, 0, b'a', b'b', b'c', 0xEE]);
+
+    let back = Write::decode(&buf[..n]).unwrap();
+    assert_eq!(back, value);
+    // Pointing into the data, not copied out of it
+    assert!(core::ptr::eq(back.data.as_ptr(), &buf[2 + BYTES_LEN_PREFIX]));
+}
+
+#[test]
+fn short_data_and_buffers_say_how_much_was_needed() {
+    let value = Write { address: 0x10, data: b"abc", check: 0xEE };
+    assert!(matches!(value.encode(&mut [0; 5]), Err(FrameError::EncodeBufferTooSmall { found: 5, .. })));
+    assert!(matches!(
+        Write::decode(&[0x10, 0, 3, 0, b'a']),
+        Err(FrameError::DecodeBufferTooSmall { expected_at_least: 7, found: 5 })
+    ));
+}
+
+#[test]
+fn wrapped_byte_fields_nest() {
+    let value = Chunks { name: Some(Bytes(b"n")), parts: [Bytes(b"ab"), Bytes(b"")], tagged: (7, Bytes(b"c")) };
+    let mut buf = [0; 32];
+    let (bytes, back) = round_trip(&value, &mut buf);
+    assert_eq!(bytes, [1, 1, 0, b'n', 2, 0, b'a', b'b', 0, 0, 7, 1, 0, b'c']);
+    assert_eq!(back, value);
+}